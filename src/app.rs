@@ -1,7 +1,20 @@
 use tui::{
-    widgets::{ListState},
+    widgets::{ListState, TableState},
 };
-use std::path::PathBuf;
+use crossterm::event::KeyCode;
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use crate::transcript;
+use crate::ftp;
+use crate::remote_fs::{Entry, EntryKind, LocalFs, RemoteFs};
+use crate::throttle::Throttle;
+use crate::args::Options;
+use crate::sites::{self, Site, Sites};
+use crate::vault::Vault;
+use crate::form::LoginForm;
+use crate::queue::{Queue, Transfer};
+use crate::preview::Preview;
 
 pub struct StatefulList<T> {
     pub state: ListState,
@@ -17,6 +30,9 @@ impl<T> StatefulList<T> {
     }
 
     pub fn next(&mut self) {
+        if self.items.is_empty() {
+            return;
+        }
         let i = match self.state.selected() {
             Some(i) => {
                 if i >= self.items.len() - 1 {
@@ -31,6 +47,9 @@ impl<T> StatefulList<T> {
     }
 
     pub fn previous(&mut self) {
+        if self.items.is_empty() {
+            return;
+        }
         let i = match self.state.selected() {
             Some(i) => {
                 if i == 0 {
@@ -46,8 +65,462 @@ impl<T> StatefulList<T> {
 
 }
 
+/// The entry that goes up a directory, listed first everywhere but at the root
+pub const PARENT: &str = "..";
+
+/// A column of the panes, which they can be sorted by
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Column {
+    Name,
+    Size,
+    Modified,
+    Permissions,
+    Owner
+}
+
+impl Column {
+    /// In the order they are shown, the sort keys are their positions from 1
+    pub const ALL: [Column; 5] = [Column::Name, Column::Size, Column::Modified, Column::Permissions, Column::Owner];
+
+    pub fn title(self) -> &'static str {
+        match self {
+            Column::Name => "Name",
+            Column::Size => "Size",
+            Column::Modified => "Modified",
+            Column::Permissions => "Permissions",
+            Column::Owner => "Owner"
+        }
+    }
+
+    /// Whether `entry` has a value in the column
+    pub fn known(self, entry: &Entry) -> bool {
+        match self {
+            Column::Name => true,
+            Column::Size => entry.size.is_some(),
+            Column::Modified => entry.modified.is_some(),
+            Column::Permissions => entry.permissions.is_some(),
+            Column::Owner => entry.owner.is_some()
+        }
+    }
+
+    // Orders by the column, then by name. Names compare ignoring case first
+    fn compare(self, a: &Entry, b: &Entry) -> Ordering {
+        let by_name = || a.name.to_lowercase().cmp(&b.name.to_lowercase()).then_with(|| a.name.cmp(&b.name));
+        match self {
+            Column::Name => Ordering::Equal,
+            Column::Size => a.size.cmp(&b.size),
+            Column::Modified => a.modified.cmp(&b.modified),
+            Column::Permissions => a.permissions.cmp(&b.permissions),
+            Column::Owner => a.owner.cmp(&b.owner)
+        }.then_with(by_name)
+    }
+}
+
+/// Whether `name` passes a pane filter: a glob pattern if it has wildcards, otherwise the letters of `filter`
+/// appearing in `name` in that order, ignoring case
+pub fn filter_match(filter: &str, name: &str) -> bool {
+    if filter.contains(['*', '?']) {
+        return sites::glob_match(filter, name);
+    }
+    let mut letters = name.chars().flat_map(char::to_lowercase);
+    filter.chars().flat_map(char::to_lowercase).all(|c| letters.any(|l| l == c))
+}
+
+/// What a file browser pane shows of a file system: its current directory and the entries in it
+pub struct Pane {
+    pub path: String,
+    /// The entries as shown: sorted, without the hidden ones if they are left out, and with PARENT
+    pub list: StatefulList<Entry>,
+    /// Where the table is scrolled to, following the selection of `list`
+    pub table: TableState,
+    // The directory as listed
+    entries: Vec<Entry>,
+    pub sort: Column,
+    pub reverse: bool,
+    pub dirs_first: bool,
+    /// Whether entries starting with '.' are shown
+    pub show_hidden: bool,
+    /// Only the entries matching it are shown, see `filter_match`
+    pub filter: Option<String>,
+    /// What was searched for last, n and N find it again
+    pub search: Option<String>,
+    /// The names of the entries selected for a batch operation, highlighted in the pane
+    pub marked: BTreeSet<String>
+}
+
+impl Pane {
+    pub fn new() -> Pane {
+        Pane {
+            path: String::new(),
+            list: StatefulList::with_items(Vec::new()),
+            table: TableState::default(),
+            entries: Vec::new(),
+            sort: Column::Name,
+            reverse: false,
+            dirs_first: true,
+            show_hidden: true,
+            filter: None,
+            search: None,
+            marked: BTreeSet::new()
+        }
+    }
+
+    /// Reloads the current directory of `fs`, keeping the selection
+    pub fn refresh(&mut self, fs: &mut dyn RemoteFs) -> ftp::Result<()> {
+        self.path = fs.pwd()?;
+        self.entries = fs.list()?;
+        self.arrange();
+        Ok(())
+    }
+
+    /// Shows the entries as the sort and filter settings say. The selected entry stays selected if it is still shown,
+    /// otherwise the selection stays in range
+    pub fn arrange(&mut self) {
+        let selected = self.selected().map(|e| e.name.clone());
+        // Entries that are gone cannot stay marked
+        self.marked.retain(|name| self.entries.iter().any(|e| &e.name == name));
+        let mut items: Vec<Entry> = self.entries.iter()
+            .filter(|e| self.show_hidden || !e.name.starts_with('.'))
+            .filter(|e| self.filter.as_ref().is_none_or(|filter| filter_match(filter, &e.name)))
+            .cloned()
+            .collect();
+        items.sort_by(|a, b| {
+            let order = if self.reverse { self.sort.compare(b, a) } else { self.sort.compare(a, b) };
+            if self.dirs_first { b.is_dir().cmp(&a.is_dir()).then(order) } else { order }
+        });
+        if self.path != "/" {
+            items.insert(0, Entry::new(PARENT, EntryKind::Directory));
+        }
+        let index = self.list.state.selected();
+        self.list.items = items;
+        let selected = match self.list.items.len() {
+            0 => None,
+            len => selected.and_then(|name| self.list.items.iter().position(|e| e.name == name))
+                .or(Some(index.unwrap_or(0).min(len - 1)))
+        };
+        self.list.state.select(selected);
+    }
+
+    /// Shows only the entries matching `filter`, or all of them
+    pub fn set_filter(&mut self, filter: Option<String>) {
+        self.filter = filter.filter(|filter| !filter.is_empty());
+        self.arrange();
+    }
+
+    /// Selects the first entry from index `start` on whose name contains `text` ignoring case, searching backwards
+    /// unless `forward` and wrapping around. False if there is none
+    pub fn find(&mut self, text: &str, start: usize, forward: bool) -> bool {
+        let len = self.list.items.len();
+        if len == 0 {
+            return false;
+        }
+        let text = text.to_lowercase();
+        let start = start % len;
+        let found = (0..len)
+            .map(|i| if forward { (start + i) % len } else { (start + len - i) % len })
+            .find(|&i| self.list.items[i].name != PARENT && self.list.items[i].name.to_lowercase().contains(&text));
+        if found.is_some() {
+            self.list.state.select(found);
+        }
+        found.is_some()
+    }
+
+    /// Selects the next or previous match of the last search
+    pub fn find_next(&mut self, forward: bool) -> bool {
+        let (text, current) = match (&self.search, self.list.state.selected()) {
+            (Some(text), Some(current)) => (text.clone(), current),
+            (Some(text), None) => (text.clone(), 0),
+            (None, _) => return false
+        };
+        let len = self.list.items.len().max(1);
+        let start = if forward { current + 1 } else { current + len - 1 };
+        self.find(&text, start, forward)
+    }
+
+    /// The number of entries in the directory, whether they are shown or not
+    pub fn total_count(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Handles the keys that change how the entries are shown: 1 to 5 sort by a column, or reverse the sort when it is
+    /// by that column already, 'g' groups directories first, '.' shows or hides hidden entries, n and N find the next
+    /// and previous match of the last search, Space marks or unmarks the selected entry and '*' inverts the marks.
+    /// False for other keys
+    pub fn handle_view_key(&mut self, code: KeyCode) -> bool {
+        match code {
+            KeyCode::Char('n') => {
+                self.find_next(true);
+                return true;
+            }
+            KeyCode::Char('N') => {
+                self.find_next(false);
+                return true;
+            }
+            KeyCode::Char(c @ '1'..='5') => {
+                let column = Column::ALL[c as usize - '1' as usize];
+                if column == self.sort {
+                    self.reverse = !self.reverse;
+                } else {
+                    (self.sort, self.reverse) = (column, false);
+                }
+            }
+            KeyCode::Char(' ') => {
+                self.toggle_mark();
+                return true;
+            }
+            KeyCode::Char('*') => {
+                self.invert_marks();
+                return true;
+            }
+            KeyCode::Char('g') => self.dirs_first = !self.dirs_first,
+            KeyCode::Char('.') => self.show_hidden = !self.show_hidden,
+            _ => return false
+        }
+        self.arrange();
+        true
+    }
+
+    /// Changes to `path` in `fs` and shows it. Going up to PARENT selects the directory that was left
+    pub fn cwd(&mut self, fs: &mut dyn RemoteFs, path: &str) -> ftp::Result<()> {
+        fs.cwd(path)?;
+        self.marked.clear();
+        let left = self.path.trim_end_matches('/').rsplit('/').next().unwrap_or("").to_string();
+        self.list.state.select(None);
+        self.refresh(fs)?;
+        if path == PARENT {
+            self.select_name(&left);
+        }
+        Ok(())
+    }
+
+    pub fn selected(&self) -> Option<&Entry> {
+        self.list.state.selected().and_then(|i| self.list.items.get(i))
+    }
+
+    /// The selected entry, unless it is PARENT, which file operations do not apply to
+    pub fn selected_file(&self) -> Option<&Entry> {
+        self.selected().filter(|e| e.name != PARENT)
+    }
+
+    /// The number of entries in the directory, without PARENT
+    pub fn file_count(&self) -> usize {
+        self.list.items.iter().filter(|e| e.name != PARENT).count()
+    }
+
+    /// Marks the selected entry or unmarks it, and moves the selection on to the next one
+    pub fn toggle_mark(&mut self) {
+        if let Some(name) = self.selected_file().map(|e| e.name.clone()) {
+            if !self.marked.remove(&name) {
+                self.marked.insert(name);
+            }
+        }
+        if let Some(i) = self.list.state.selected().filter(|&i| i + 1 < self.list.items.len()) {
+            self.list.state.select(Some(i + 1));
+        }
+    }
+
+    /// Marks the shown entries that are not marked and unmarks the others
+    pub fn invert_marks(&mut self) {
+        for entry in self.list.items.iter().filter(|e| e.name != PARENT) {
+            if !self.marked.remove(&entry.name) {
+                self.marked.insert(entry.name.clone());
+            }
+        }
+    }
+
+    /// Marks or unmarks the shown entries matching the glob `pattern`. Returns how many there are
+    pub fn mark_matching(&mut self, pattern: &str, mark: bool) -> usize {
+        let names: Vec<String> = self.list.items.iter()
+            .filter(|e| e.name != PARENT && sites::glob_match(pattern, &e.name))
+            .map(|e| e.name.clone())
+            .collect();
+        for name in &names {
+            if mark { self.marked.insert(name.clone()); } else { self.marked.remove(name); }
+        }
+        names.len()
+    }
+
+    pub fn is_marked(&self, entry: &Entry) -> bool {
+        self.marked.contains(&entry.name)
+    }
+
+    /// What file operations act on: the marked entries that are shown, or else the selected one
+    pub fn targets(&self) -> Vec<Entry> {
+        let marked: Vec<Entry> = self.list.items.iter().filter(|e| self.is_marked(e)).cloned().collect();
+        if marked.is_empty() { self.selected_file().cloned().into_iter().collect() } else { marked }
+    }
+
+    /// The absolute path of `name` in the current directory, for connections that may be in another one
+    pub fn path_of(&self, name: &str) -> String {
+        format!("{}/{}", self.path.trim_end_matches('/'), name)
+    }
+
+    /// Selects the entry called `name`, if there is one
+    pub fn select_name(&mut self, name: &str) {
+        if let Some(i) = self.list.items.iter().position(|e| e.name == name) {
+            self.list.state.select(Some(i));
+        }
+    }
+}
+
+impl Default for Pane {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The pane the keys act on
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Focus {
+    Remote,
+    Local,
+    /// The transfer panel
+    Queue
+}
+
 pub struct App {
-    pub remote_list: StatefulList<String>,
-    pub local_list: StatefulList<String>,
-    pub local_path: PathBuf
+    pub remote: Pane,
+    pub local: Pane,
+    pub local_fs: LocalFs,
+    pub focus: Focus,
+    pub transcript: Arc<transcript::Memory>,
+    pub transcript_file: Option<Arc<transcript::FileSink>>,
+    pub show_transcript: bool,
+    pub throttle: Arc<Throttle>,
+    /// The command line the program was started with
+    pub options: Options,
+    pub sites: Sites,
+    /// The names in the site manager while it is open
+    pub site_dialog: Option<StatefulList<String>>,
+    /// The settings of the current connection
+    pub site: Site,
+    /// The credential vault, once unlocked in this session
+    pub vault: Option<Vault>,
+    /// A yes/no question shown in a dialog box
+    pub question: Option<String>,
+    /// The login form while it is open
+    pub login: Option<LoginForm>,
+    /// The transfers a worker thread processes in the background
+    pub queue: Queue,
+    /// What the transfer panel shows of the queue, updated as the screen is drawn
+    pub transfers: StatefulList<Transfer>,
+    /// Whether the preview panel takes the place of the local pane
+    pub show_preview: bool,
+    /// The file the preview panel shows, following the selection of the remote pane
+    pub preview: Option<Preview>
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remote_fs::MemoryFs;
+    use std::io::Write;
+
+    #[test]
+    fn pane_cwd_test() {
+        let mut fs = MemoryFs::new();
+        for dir in ["a", "b", "b/inner"] {
+            fs.mkdir(dir).unwrap();
+        }
+        let mut pane = Pane::new();
+        pane.refresh(&mut fs).unwrap();
+        pane.list.next();
+        assert_eq!(pane.selected().map(|e| e.name.as_str()), Some("b"));
+
+        assert_eq!(pane.path_of("b"), "/b");
+        pane.cwd(&mut fs, "b").unwrap();
+        assert_eq!(pane.path, "/b");
+        assert_eq!(pane.path_of("inner"), "/b/inner");
+        assert_eq!(pane.list.items.iter().map(|e| e.name.as_str()).collect::<Vec<_>>(), vec!["..", "inner"]);
+        assert_eq!((pane.selected().map(|e| e.name.as_str()), pane.selected_file(), pane.file_count()), (Some(".."), None, 1));
+        // Going back up selects the directory that was left
+        pane.cwd(&mut fs, "..").unwrap();
+        assert_eq!(pane.selected().map(|e| e.name.as_str()), Some("b"));
+        assert!(pane.cwd(&mut fs, "missing").is_err());
+    }
+
+    #[test]
+    fn arrange_test() {
+        let mut fs = MemoryFs::new();
+        fs.mkdir("dir").unwrap();
+        for (name, data) in [("b.txt", &b"bb"[..]), ("A.txt", b"a"), (".hidden", b"hhh")] {
+            let mut output = fs.write(name).unwrap();
+            output.write_all(data).unwrap();
+            output.finish().unwrap();
+        }
+        let names = |pane: &Pane| pane.list.items.iter().map(|e| e.name.clone()).collect::<Vec<_>>();
+        let mut pane = Pane::new();
+        pane.refresh(&mut fs).unwrap();
+        assert_eq!(names(&pane), vec!["dir", ".hidden", "A.txt", "b.txt"]);
+        pane.list.state.select(Some(2));
+
+        // Sorting by size twice reverses it, the selection follows the entry
+        assert!(pane.handle_view_key(KeyCode::Char('2')) && pane.handle_view_key(KeyCode::Char('2')));
+        assert_eq!(names(&pane), vec!["dir", ".hidden", "b.txt", "A.txt"]);
+        assert_eq!(pane.selected().map(|e| e.name.as_str()), Some("A.txt"));
+        pane.handle_view_key(KeyCode::Char('g'));
+        pane.handle_view_key(KeyCode::Char('.'));
+        assert_eq!(names(&pane), vec!["b.txt", "A.txt", "dir"]);
+        assert!(!pane.handle_view_key(KeyCode::Char('x')));
+    }
+
+    #[test]
+    fn search_test() {
+        let mut fs = MemoryFs::new();
+        for name in ["report-2023.txt", "notes.md", "report-2024.txt", "photo.jpg"] {
+            fs.write(name).unwrap().finish().unwrap();
+        }
+        let mut pane = Pane::new();
+        pane.refresh(&mut fs).unwrap();
+        assert!(pane.find("REPORT", 0, true));
+        assert_eq!(pane.selected().map(|e| e.name.as_str()), Some("report-2023.txt"));
+        pane.search = Some("report".to_string());
+        pane.handle_view_key(KeyCode::Char('n'));
+        assert_eq!(pane.selected().map(|e| e.name.as_str()), Some("report-2024.txt"));
+        // Wraps around both ways
+        pane.handle_view_key(KeyCode::Char('n'));
+        assert_eq!(pane.selected().map(|e| e.name.as_str()), Some("report-2023.txt"));
+        pane.handle_view_key(KeyCode::Char('N'));
+        assert_eq!(pane.selected().map(|e| e.name.as_str()), Some("report-2024.txt"));
+        assert!(!pane.find("missing", 0, true));
+
+        pane.set_filter(Some("*.txt".to_string()));
+        assert_eq!((pane.file_count(), pane.total_count()), (2, 4));
+        pane.set_filter(Some("pto".to_string()));
+        assert_eq!(pane.list.items.iter().map(|e| e.name.as_str()).collect::<Vec<_>>(), vec!["photo.jpg"]);
+        pane.set_filter(Some(String::new()));
+        assert_eq!(pane.file_count(), 4);
+    }
+
+    #[test]
+    fn mark_test() {
+        let mut fs = MemoryFs::new();
+        fs.mkdir("dir").unwrap();
+        for name in ["a.txt", "b.txt", "c.jpg"] {
+            fs.write(name).unwrap().finish().unwrap();
+        }
+        let names = |entries: Vec<Entry>| entries.into_iter().map(|e| e.name).collect::<Vec<_>>();
+        let mut pane = Pane::new();
+        pane.refresh(&mut fs).unwrap();
+        assert_eq!(names(pane.targets()), vec!["dir"]);
+
+        // Space marks and moves on
+        assert!(pane.handle_view_key(KeyCode::Char(' ')));
+        assert_eq!(pane.selected().map(|e| e.name.as_str()), Some("a.txt"));
+        assert_eq!(pane.mark_matching("*.txt", true), 2);
+        assert_eq!(names(pane.targets()), vec!["dir", "a.txt", "b.txt"]);
+        pane.mark_matching("a*", false);
+        pane.handle_view_key(KeyCode::Char('*'));
+        assert_eq!(names(pane.targets()), vec!["a.txt", "c.jpg"]);
+
+        // Filtered out entries stay marked but are left alone, deleted ones are unmarked
+        pane.set_filter(Some("*.jpg".to_string()));
+        assert_eq!(names(pane.targets()), vec!["c.jpg"]);
+        pane.set_filter(None);
+        fs.delete("c.jpg").unwrap();
+        pane.refresh(&mut fs).unwrap();
+        assert_eq!(names(pane.targets()), vec!["a.txt"]);
+        pane.cwd(&mut fs, "dir").unwrap();
+        assert!(pane.marked.is_empty());
+    }
 }
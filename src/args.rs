@@ -0,0 +1,158 @@
+use crate::tls::TlsMode;
+use crate::url::{Protocol, Url};
+use std::path::PathBuf;
+
+pub const USAGE: &str = "Usage: termftp [URL | --site NAME] [--host HOST] [--port PORT] [--user USER] [--passive | --active] [--tls] [--transcript FILE] [--help]
+       termftp --certificates | --forget-certificate HOST[:PORT]
+       termftp --fxp SOURCE-URL DESTINATION-URL [--transcript FILE]
+URL is ftp://, ftps:// (implicit TLS), ftpes:// (explicit TLS) or sftp://[user[:password]@]host[:port][/path][;type=a|i|d].
+--fxp copies a file from one ftp:// server to another, directly between the servers if they allow it";
+
+/// The command line: a server URL and flags overriding parts of it
+#[derive(Default, Debug)]
+pub struct Options {
+    pub url: Option<Url>,
+    /// A site saved in the site manager
+    pub site: Option<String>,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    pub tls: bool,
+    pub active: bool,
+    pub transcript: Option<PathBuf>,
+    /// List the pinned TLS certificates
+    pub certificates: bool,
+    /// Unpin the TLS certificates of "host:port", or of every port of "host"
+    pub forget_certificate: Option<String>,
+    /// Copy the file of the first URL to the second one, server to server
+    pub fxp: Option<(Url, Url)>,
+    pub help: bool
+}
+
+impl Options {
+    /// `url` with the flags applied
+    pub fn apply(&self, mut url: Url) -> Url {
+        if let Some(host) = &self.host {
+            url.host = host.clone();
+        }
+        if self.port.is_some() {
+            url.port = self.port;
+        }
+        if self.user.is_some() {
+            url.user = self.user.clone();
+        }
+        if self.tls && url.tls.is_none() {
+            url.tls = Some(TlsMode::Explicit);
+        }
+        url
+    }
+
+    /// The server to connect to, if the command line names one
+    pub fn server(&self) -> Option<Url> {
+        match (&self.url, &self.host) {
+            (Some(url), _) => Some(self.apply(url.clone())),
+            (None, Some(_)) => Some(self.apply(Url::default())),
+            (None, None) => None
+        }
+    }
+}
+
+/// Parses the arguments, without the program name. Flags take "--flag value" or "--flag=value"
+pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Options, String> {
+    let mut res = Options::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value.to_string())),
+            _ => (arg.clone(), None)
+        };
+        let mut value = || inline.clone().or_else(|| args.next()).ok_or_else(|| format!("{} needs a value", flag));
+        match flag.as_str() {
+            "--host" => res.host = Some(value()?),
+            "--port" => {
+                let port = value()?;
+                res.port = Some(port.parse().map_err(|_| format!("Invalid port \"{}\"", port))?);
+            }
+            "--user" => res.user = Some(value()?),
+            "--site" => res.site = Some(value()?),
+            "--transcript" => res.transcript = Some(PathBuf::from(value()?)),
+            "--passive" => res.active = false,
+            "--active" => res.active = true,
+            "--tls" => res.tls = true,
+            "--certificates" => res.certificates = true,
+            "--forget-certificate" => res.forget_certificate = Some(value()?),
+            "--fxp" => {
+                let source = value()?;
+                let destination = args.next().ok_or_else(|| format!("{} needs a source and a destination", flag))?;
+                res.fxp = Some((fxp_url(&source)?, fxp_url(&destination)?));
+            }
+            "-h" | "--help" => res.help = true,
+            _ if flag.starts_with('-') => return Err(format!("Unknown option {}", flag)),
+            _ if res.url.is_none() => res.url = Some(Url::parse(&arg)?),
+            _ => return Err(format!("Unexpected argument \"{}\"", arg))
+        }
+    }
+    Ok(res)
+}
+
+// A file on a plain FTP server. FXP between TLS servers needs support from both of them, which is rare
+fn fxp_url(text: &str) -> Result<Url, String> {
+    let url = Url::parse(text)?;
+    if url.protocol != Protocol::Ftp || url.tls.is_some() {
+        return Err(format!("--fxp needs ftp:// URLs, not \"{}\"", text));
+    }
+    if url.path.as_deref().is_none_or(|path| path.is_empty() || path.ends_with('/')) {
+        return Err(format!("\"{}\" does not name a file", text));
+    }
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Options, String> {
+        parse(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn url_and_flags_test() {
+        let options = parse_args(&["ftp://bob@example.org:2121/some/dir", "--port=21", "--active", "--transcript", "/tmp/t.log"]).unwrap();
+        let server = options.server().unwrap();
+        assert_eq!((server.host.as_str(), server.port, server.user.as_deref()), ("example.org", Some(21), Some("bob")));
+        assert_eq!(server.path.as_deref(), Some("some/dir"));
+        assert!(options.active);
+        assert_eq!(options.transcript, Some(PathBuf::from("/tmp/t.log")));
+    }
+
+    #[test]
+    fn flags_only_test() {
+        let options = parse_args(&["--host", "example.org", "--user", "alice", "--tls", "--passive", "--site=Work"]).unwrap();
+        assert_eq!(options.site.as_deref(), Some("Work"));
+        let server = options.server().unwrap();
+        assert_eq!((server.host.as_str(), server.user.as_deref(), server.tls), ("example.org", Some("alice"), Some(TlsMode::Explicit)));
+        assert_eq!(server.password, None);
+        assert!(!options.active);
+        assert!(parse_args(&[]).unwrap().server().is_none());
+        assert_eq!(parse_args(&["--forget-certificate", "[::1]:990"]).unwrap().forget_certificate.as_deref(), Some("[::1]:990"));
+    }
+
+    #[test]
+    fn invalid_test() {
+        assert!(parse_args(&["--port", "x"]).is_err());
+        assert!(parse_args(&["--user"]).is_err());
+        assert!(parse_args(&["--bogus"]).is_err());
+        assert!(parse_args(&["ftp://a", "ftp://b"]).is_err());
+    }
+
+    #[test]
+    fn fxp_test() {
+        let options = parse_args(&["--fxp", "ftp://a.example.org/pub/x.iso", "ftp://bob:pw@b.example.org:2121/%2Fin/x.iso"]).unwrap();
+        let (source, destination) = options.fxp.unwrap();
+        assert_eq!((source.host.as_str(), source.path.as_deref()), ("a.example.org", Some("pub/x.iso")));
+        assert_eq!((destination.address(), destination.password.as_deref(), destination.path.as_deref()), ("b.example.org:2121".to_string(), Some("pw"), Some("/in/x.iso")));
+        assert!(parse_args(&["--fxp", "ftp://a.example.org/x"]).is_err());
+        assert!(parse_args(&["--fxp", "sftp://a.example.org/x", "ftp://b.example.org/x"]).is_err());
+        assert!(parse_args(&["--fxp", "ftp://a.example.org/", "ftp://b.example.org/x"]).is_err());
+    }
+}
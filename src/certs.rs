@@ -0,0 +1,185 @@
+use openssl::hash::MessageDigest;
+use openssl::x509::{X509NameRef, X509Ref};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// What a server certificate says about itself, shown before trusting it
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Certificate {
+    /// SHA-256 of the DER encoding, as colon separated hex like `openssl x509 -fingerprint -sha256` prints it
+    pub fingerprint: String,
+    pub subject: String,
+    pub issuer: String,
+    pub not_before: String,
+    pub not_after: String,
+    /// Why the certificate failed verification against the system's trusted certificates
+    pub problem: Option<String>
+}
+
+fn name_text(name: &X509NameRef) -> String {
+    name.entries()
+        .map(|entry| {
+            let key = entry.object().nid().short_name().unwrap_or("?");
+            let value = entry.data().as_utf8().map(|value| value.to_string()).unwrap_or_default();
+            format!("{}={}", key, value)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl Certificate {
+    pub fn new(certificate: &X509Ref, problem: Option<String>) -> io::Result<Certificate> {
+        let digest = certificate.digest(MessageDigest::sha256())?;
+        Ok(Certificate {
+            fingerprint: digest.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(":"),
+            subject: name_text(certificate.subject_name()),
+            issuer: name_text(certificate.issuer_name()),
+            not_before: certificate.not_before().to_string(),
+            not_after: certificate.not_after().to_string(),
+            problem
+        })
+    }
+}
+
+impl fmt::Display for Certificate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}, issued by {}, valid from {} to {}, SHA-256 {}", self.subject, self.issuer, self.not_before, self.not_after, self.fingerprint)?;
+        if let Some(problem) = &self.problem {
+            write!(f, " ({})", problem)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Trust {
+    /// The fingerprint is the one pinned for the server
+    Pinned,
+    /// No certificate is pinned for the server
+    Unknown,
+    /// Another certificate is pinned for the server
+    Changed { pinned: String }
+}
+
+/// Pinned certificate fingerprints by server, one "host:port fingerprint" line each, like SSH's known_hosts
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct KnownCertificates {
+    pub entries: Vec<(String, String)>
+}
+
+impl KnownCertificates {
+    pub fn parse(text: &str) -> KnownCertificates {
+        let entries = text.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| line.split_once(char::is_whitespace))
+            .map(|(server, fingerprint)| (server.to_string(), fingerprint.trim().to_string()))
+            .collect();
+        KnownCertificates { entries }
+    }
+
+    /// `server` is "host:port", with brackets around an IPv6 address
+    pub fn check(&self, server: &str, fingerprint: &str) -> Trust {
+        match self.entries.iter().find(|(s, _)| s.eq_ignore_ascii_case(server)) {
+            None => Trust::Unknown,
+            Some((_, pinned)) if pinned.eq_ignore_ascii_case(fingerprint) => Trust::Pinned,
+            Some((_, pinned)) => Trust::Changed { pinned: pinned.clone() }
+        }
+    }
+
+    /// Pins `fingerprint` for `server`, replacing the one pinned before
+    pub fn pin(&mut self, server: &str, fingerprint: &str) {
+        self.entries.retain(|(s, _)| !s.eq_ignore_ascii_case(server));
+        self.entries.push((server.to_string(), fingerprint.to_string()));
+    }
+
+    /// Removes the certificates pinned for "host:port", or for every port of "host". Returns how many there were
+    pub fn forget(&mut self, server: &str) -> usize {
+        let bare = |host: &str| host.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase();
+        let before = self.entries.len();
+        self.entries.retain(|(s, _)| {
+            let host = s.rsplit_once(':').map_or(s.as_str(), |(host, _)| host);
+            !s.eq_ignore_ascii_case(server) && bare(host) != bare(server)
+        });
+        before - self.entries.len()
+    }
+
+    pub fn to_text(&self) -> String {
+        self.entries.iter().map(|(server, fingerprint)| format!("{} {}\n", server, fingerprint)).collect()
+    }
+}
+
+/// known_certificates next to the site manager's file
+pub fn default_path() -> Option<PathBuf> {
+    crate::sites::default_path().map(|sites| sites.with_file_name("known_certificates"))
+}
+
+/// Reads the store at `path`, empty if it does not exist yet
+pub fn load(path: &Path) -> io::Result<KnownCertificates> {
+    match fs::read_to_string(path) {
+        Ok(text) => Ok(KnownCertificates::parse(&text)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(KnownCertificates::default()),
+        Err(e) => Err(e)
+    }
+}
+
+pub fn save(path: &Path, known: &KnownCertificates) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, known.to_text())?;
+    fs::rename(&temporary, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::{X509, X509NameBuilder};
+
+    #[test]
+    fn check_test() {
+        let mut known = KnownCertificates::parse("# pinned\nftp.example.org:21 AA:BB\n[::1]:990  cc:dd\n\n");
+        assert_eq!(known.check("ftp.example.org:21", "AA:BB"), Trust::Pinned);
+        assert_eq!(known.check("[::1]:990", "CC:DD"), Trust::Pinned);
+        assert_eq!(known.check("ftp.example.org:21", "AA:BC"), Trust::Changed { pinned: "AA:BB".to_string() });
+        assert_eq!(known.check("ftp.example.org:990", "AA:BB"), Trust::Unknown);
+
+        known.pin("ftp.example.org:21", "AA:BC");
+        known.pin("ftp.example.org:990", "EE:FF");
+        assert_eq!(known.check("ftp.example.org:21", "AA:BC"), Trust::Pinned);
+        assert_eq!(KnownCertificates::parse(&known.to_text()), known);
+        assert_eq!(known.forget("::1"), 1);
+        assert_eq!(known.forget("ftp.example.org"), 2);
+        assert!(known.entries.is_empty());
+    }
+
+    #[test]
+    fn certificate_test() -> Result<(), Box<dyn std::error::Error>> {
+        let key = PKey::from_rsa(Rsa::generate(2048)?)?;
+        let mut name = X509NameBuilder::new()?;
+        name.append_entry_by_text("CN", "ftp.example.org")?;
+        let name = name.build();
+        let mut builder = X509::builder()?;
+        builder.set_subject_name(&name)?;
+        builder.set_issuer_name(&name)?;
+        builder.set_pubkey(&key)?;
+        let (not_before, not_after) = (Asn1Time::from_unix(0)?, Asn1Time::from_unix(86400)?);
+        builder.set_not_before(&not_before)?;
+        builder.set_not_after(&not_after)?;
+        builder.sign(&key, MessageDigest::sha256())?;
+        let certificate = builder.build();
+
+        let info = Certificate::new(&certificate, Some("self-signed certificate".to_string()))?;
+        assert_eq!((info.subject.as_str(), info.issuer.as_str()), ("CN=ftp.example.org", "CN=ftp.example.org"));
+        assert_eq!(info.not_after, "Jan  2 00:00:00 1970 GMT");
+        assert_eq!(info.fingerprint.len(), 32 * 3 - 1);
+        assert!(info.to_string().ends_with("(self-signed certificate)"));
+        Ok(())
+    }
+}
@@ -0,0 +1,329 @@
+use crate::tls::TlsMode;
+use crate::url::{Protocol, Url};
+use crossterm::event::{KeyCode, KeyEvent};
+use zeroize::Zeroizing;
+
+/// A line of text being edited, with a cursor
+pub struct TextField {
+    pub text: Zeroizing<String>,
+    /// In characters
+    pub cursor: usize
+}
+
+impl TextField {
+    pub fn new(text: &str) -> TextField {
+        // Reserved up front, so typing a password does not leave unwiped copies in reallocated buffers
+        let mut res = Zeroizing::new(String::with_capacity(text.len().max(256)));
+        res.push_str(text);
+        TextField { cursor: text.chars().count(), text: res }
+    }
+
+    fn byte_index(&self, cursor: usize) -> usize {
+        self.text.char_indices().nth(cursor).map_or(self.text.len(), |(i, _)| i)
+    }
+
+    /// Applies an editing key, false if `code` is none
+    pub fn edit(&mut self, code: KeyCode) -> bool {
+        let len = self.text.chars().count();
+        match code {
+            KeyCode::Char(c) => {
+                let i = self.byte_index(self.cursor);
+                self.text.insert(i, c);
+                self.cursor += 1;
+            }
+            KeyCode::Backspace => {
+                if self.cursor > 0 {
+                    self.cursor -= 1;
+                    let i = self.byte_index(self.cursor);
+                    self.text.remove(i);
+                }
+            }
+            KeyCode::Delete => {
+                if self.cursor < len {
+                    let i = self.byte_index(self.cursor);
+                    self.text.remove(i);
+                }
+            }
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(len),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = len,
+            _ => return false
+        }
+        true
+    }
+}
+
+/// The protocol, and for FTP how it is secured
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Security {
+    Ftp,
+    Ftpes,
+    Ftps,
+    Sftp
+}
+
+impl Security {
+    const ALL: [Security; 4] = [Security::Ftp, Security::Ftpes, Security::Ftps, Security::Sftp];
+
+    pub fn of(url: &Url) -> Security {
+        match (url.protocol, url.tls) {
+            (Protocol::Sftp, _) => Security::Sftp,
+            (Protocol::Ftp, None) => Security::Ftp,
+            (Protocol::Ftp, Some(TlsMode::Explicit)) => Security::Ftpes,
+            (Protocol::Ftp, Some(TlsMode::Implicit)) => Security::Ftps
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Security::Ftp => "FTP",
+            Security::Ftpes => "FTP with explicit TLS (FTPES)",
+            Security::Ftps => "FTP with implicit TLS (FTPS)",
+            Security::Sftp => "SFTP"
+        }
+    }
+
+    fn apply(self, url: &mut Url) {
+        (url.protocol, url.tls) = match self {
+            Security::Ftp => (Protocol::Ftp, None),
+            Security::Ftpes => (Protocol::Ftp, Some(TlsMode::Explicit)),
+            Security::Ftps => (Protocol::Ftp, Some(TlsMode::Implicit)),
+            Security::Sftp => (Protocol::Sftp, None)
+        };
+    }
+
+    // The next or previous one, wrapping around
+    fn step(self, forward: bool) -> Security {
+        let i = Security::ALL.iter().position(|&s| s == self).unwrap_or(0);
+        let len = Security::ALL.len();
+        Security::ALL[if forward { (i + 1) % len } else { (i + len - 1) % len }]
+    }
+}
+
+pub const HOST: usize = 0;
+pub const PORT: usize = 1;
+pub const USER: usize = 2;
+pub const PASSWORD: usize = 3;
+pub const SECURITY: usize = 4;
+pub const MODE: usize = 5;
+pub const ANONYMOUS: usize = 6;
+pub const FIELDS: [&str; 7] = ["Host", "Port", "User", "Password", "Protocol", "Mode", "Anonymous"];
+
+pub const ANONYMOUS_USER: &str = "anonymous";
+pub const ANONYMOUS_PASSWORD: &str = "anonymous@";
+
+pub enum Action {
+    Submit,
+    Cancel
+}
+
+/// What the form was filled in with
+pub struct Login {
+    /// With the user, but without the password
+    pub url: Url,
+    pub password: Zeroizing<String>,
+    pub active: bool
+}
+
+/// The server to connect to and how to log in
+pub struct LoginForm {
+    pub host: TextField,
+    pub port: TextField,
+    pub user: TextField,
+    pub password: TextField,
+    pub security: Security,
+    pub active: bool,
+    pub anonymous: bool,
+    /// The index of the field being edited
+    pub focus: usize,
+    /// What is wrong, and the field it is about
+    pub error: Option<(usize, String)>,
+    /// Why the last login failed
+    pub message: Option<String>,
+    // The rest of the URL, like its path
+    url: Url
+}
+
+impl LoginForm {
+    /// Filled in from `url`, with the focus on the first field missing. `message` tells why the last login failed
+    pub fn new(url: &Url, user: Option<&str>, password: Option<&str>, active: bool, message: Option<String>) -> LoginForm {
+        let anonymous = user == Some(ANONYMOUS_USER);
+        let mut form = LoginForm {
+            host: TextField::new(&url.host),
+            port: TextField::new(&url.port.map(|port| port.to_string()).unwrap_or_default()),
+            user: TextField::new(if anonymous { "" } else { user.unwrap_or_default() }),
+            password: TextField::new(if anonymous { "" } else { password.unwrap_or_default() }),
+            security: Security::of(url),
+            active,
+            anonymous,
+            focus: HOST,
+            error: None,
+            message,
+            url: url.clone()
+        };
+        form.focus = if form.host.text.is_empty() {
+            HOST
+        } else if !anonymous && form.user.text.is_empty() {
+            USER
+        } else if !anonymous {
+            PASSWORD
+        } else {
+            HOST
+        };
+        form
+    }
+
+    /// Whether the field at `index` takes input, the credentials do not for an anonymous login
+    pub fn enabled(&self, index: usize) -> bool {
+        !(self.anonymous && (index == USER || index == PASSWORD))
+    }
+
+    pub fn text_field(&self, index: usize) -> Option<&TextField> {
+        match index {
+            HOST => Some(&self.host),
+            PORT => Some(&self.port),
+            USER => Some(&self.user),
+            PASSWORD => Some(&self.password),
+            _ => None
+        }
+    }
+
+    fn text_field_mut(&mut self, index: usize) -> Option<&mut TextField> {
+        match index {
+            HOST => Some(&mut self.host),
+            PORT => Some(&mut self.port),
+            USER => Some(&mut self.user),
+            PASSWORD => Some(&mut self.password),
+            _ => None
+        }
+    }
+
+    // Moves the focus to the next or previous field that takes input
+    fn move_focus(&mut self, forward: bool) {
+        let len = FIELDS.len();
+        loop {
+            self.focus = if forward { (self.focus + 1) % len } else { (self.focus + len - 1) % len };
+            if self.enabled(self.focus) {
+                break;
+            }
+        }
+    }
+
+    pub fn handle_key(&mut self, key: KeyEvent) -> Option<Action> {
+        match key.code {
+            KeyCode::Enter => return Some(Action::Submit),
+            KeyCode::Esc => return Some(Action::Cancel),
+            KeyCode::Tab | KeyCode::Down => self.move_focus(true),
+            KeyCode::BackTab | KeyCode::Up => self.move_focus(false),
+            code => match (self.focus, code) {
+                (SECURITY, KeyCode::Left) => self.security = self.security.step(false),
+                (SECURITY, KeyCode::Right | KeyCode::Char(' ')) => self.security = self.security.step(true),
+                (MODE, KeyCode::Left | KeyCode::Right | KeyCode::Char(' ')) => self.active = !self.active,
+                (ANONYMOUS, KeyCode::Char(' ')) => self.anonymous = !self.anonymous,
+                (index, code) => {
+                    if let Some(field) = self.text_field_mut(index) {
+                        field.edit(code);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// The login the form is filled in with. If something is missing or invalid, the error is shown
+    /// at the field, which gets the focus
+    pub fn submit(&mut self) -> Option<Login> {
+        match self.validate() {
+            Ok(login) => Some(login),
+            Err((index, error)) => {
+                self.focus = index;
+                self.error = Some((index, error));
+                None
+            }
+        }
+    }
+
+    fn validate(&self) -> Result<Login, (usize, String)> {
+        let host = self.host.text.trim();
+        if host.is_empty() {
+            return Err((HOST, "Enter the server's host name or address".to_string()));
+        }
+        if host.contains(['/', '@', ' ']) {
+            return Err((HOST, "Enter only the host name, the protocol and user have fields of their own".to_string()));
+        }
+        let port = match self.port.text.trim() {
+            "" => None,
+            port => match port.parse::<u16>() {
+                Ok(port) if port > 0 => Some(port),
+                _ => return Err((PORT, "The port is a number from 1 to 65535, or empty for the default".to_string()))
+            }
+        };
+        let (user, password) = if self.anonymous {
+            (ANONYMOUS_USER.to_string(), Zeroizing::new(ANONYMOUS_PASSWORD.to_string()))
+        } else {
+            (self.user.text.trim().to_string(), self.password.text.clone())
+        };
+        if user.is_empty() {
+            return Err((USER, "Enter a user, or check Anonymous".to_string()));
+        }
+
+        let mut url = Url { host: host.trim_start_matches('[').trim_end_matches(']').to_string(), port, user: Some(user), password: None, ..self.url.clone() };
+        self.security.apply(&mut url);
+        Ok(Login { url, password, active: self.active })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossterm::event::KeyModifiers;
+
+    fn press(form: &mut LoginForm, codes: &[KeyCode]) {
+        for &code in codes {
+            form.handle_key(KeyEvent::new(code, KeyModifiers::NONE));
+        }
+    }
+
+    #[test]
+    fn text_field_test() {
+        let mut field = TextField::new("hé");
+        for code in [KeyCode::Left, KeyCode::Char('x'), KeyCode::Home, KeyCode::Delete, KeyCode::End, KeyCode::Backspace, KeyCode::Char('!')] {
+            assert!(field.edit(code));
+        }
+        assert_eq!((field.text.as_str(), field.cursor), ("x!", 2));
+        assert!(!field.edit(KeyCode::Tab));
+    }
+
+    #[test]
+    fn form_test() {
+        let url = Url::parse("ftpes://ftp.example.org/pub;type=d").unwrap();
+        let mut form = LoginForm::new(&url, None, None, false, None);
+        assert_eq!((form.focus, form.security), (USER, Security::Ftpes));
+
+        press(&mut form, &[KeyCode::Char('b'), KeyCode::Char('o'), KeyCode::Char('b'), KeyCode::Tab, KeyCode::Char('s'), KeyCode::Tab]);
+        press(&mut form, &[KeyCode::Left, KeyCode::Tab, KeyCode::Char(' ')]);
+        let login = form.submit().unwrap();
+        assert_eq!((login.url.user.as_deref(), login.password.as_str(), login.active), (Some("bob"), "s", true));
+        assert_eq!((login.url.protocol, login.url.tls, login.url.path.as_deref()), (Protocol::Ftp, None, Some("pub")));
+
+        // Anonymous logins skip the credentials
+        press(&mut form, &[KeyCode::Tab, KeyCode::Char(' '), KeyCode::Tab, KeyCode::Tab, KeyCode::Tab]);
+        assert_eq!(form.focus, SECURITY);
+        assert_eq!(form.submit().unwrap().url.user.as_deref(), Some("anonymous"));
+    }
+
+    #[test]
+    fn validation_test() {
+        let mut form = LoginForm::new(&Url::default(), None, None, false, None);
+        assert!(form.submit().is_none());
+        assert_eq!(form.error.as_ref().map(|(index, _)| *index), Some(HOST));
+
+        press(&mut form, &[KeyCode::Char('h'), KeyCode::Tab, KeyCode::Char('0')]);
+        assert!(form.submit().is_none());
+        assert_eq!(form.focus, PORT);
+        press(&mut form, &[KeyCode::Backspace, KeyCode::Char('2'), KeyCode::Char('1')]);
+        assert!(form.submit().is_none());
+        assert_eq!(form.focus, USER);
+    }
+}
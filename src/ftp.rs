@@ -2,22 +2,157 @@ extern crate snafu;
 extern crate lazy_static;
 
 use snafu::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::io::prelude::*;
-use std::net::TcpStream;
+use std::net::{IpAddr, TcpListener, TcpStream};
 use std::io::BufReader;
-
+use std::cmp;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use openssl::ssl::SslSession;
+use crate::proxy::{self, Connector};
+use crate::transcript::{self, Direction, Entry, Sink};
+use crate::throttle::Throttle;
+use crate::date;
+use crate::certs::Certificate;
+use crate::tls::{Stream, Tls, TlsMode};
+
+// Largest read or write on a throttled data connection, keeping bursts short
+const THROTTLED_CHUNK: usize = 8192;
+// How long to wait for the server to connect back in active mode
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy)]
 pub enum ConnectionType {
     Passive,
     Active
 }
 
 pub struct Connection {
-    control_stream: TcpStream,
-    r#type: ConnectionType
+    control_stream: BufReader<Stream>,
+    r#type: ConnectionType,
+    connector: Arc<dyn Connector>,
+    transcript: Option<Arc<dyn Sink>>,
+    throttle: Option<Arc<Throttle>>,
+    transfer_throttle: Option<Arc<Throttle>>,
+    tls: Option<Tls>,
+    // Whether PROT P was accepted, so data connections use TLS as well
+    protect_data: bool,
+    encoding: Encoding,
+    // The last command sent, which the following replies belong to
+    context: Context
+}
+
+fn record(transcript: &Option<Arc<dyn Sink>>, direction: Direction, line: &str) {
+    if let Some(sink) = transcript {
+        sink.record(&Entry { time: SystemTime::now(), direction, line: line.trim_end().to_string() });
+    }
+}
+
+// The TLS settings and control connection session a data connection is secured with
+type DataTls = Option<(Tls, Option<SslSession>)>;
+
+fn secure(tls: &DataTls, stream: TcpStream) -> std::io::Result<Stream> {
+    match tls {
+        Some((tls, session)) => Ok(Stream::Tls(Box::new(tls.connect(stream, session.as_ref()).map_err(|e| std::io::Error::other(e.to_string()))?))),
+        None => Ok(Stream::Plain(stream))
+    }
+}
+
+/// A data connection, which reports its closing to the transcript.
+/// Reads count against the download limit of its throttles, writes against the upload limit.
+/// The connection is only set up on first use, after the transfer command has been sent: in active mode the
+/// server connects once it has the command, and TLS servers only start the handshake then.
+pub struct DataStream {
+    stream: Option<Stream>,
+    pending: Option<(Pending, DataTls)>,
+    peer: String,
+    transcript: Option<Arc<dyn Sink>>,
+    throttles: Vec<Arc<Throttle>>
+}
+
+enum Pending {
+    Connected(TcpStream),
+    Listening(TcpListener)
+}
+
+impl DataStream {
+    fn stream(&mut self) -> std::io::Result<&mut Stream> {
+        if let Some((pending, tls)) = self.pending.take() {
+            let stream = match pending {
+                Pending::Connected(stream) => stream,
+                Pending::Listening(listener) => {
+                    listener.set_nonblocking(true)?;
+                    let deadline = Instant::now() + ACCEPT_TIMEOUT;
+                    let (stream, peer) = loop {
+                        match listener.accept() {
+                            Ok(accepted) => break accepted,
+                            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock && Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+                            Err(e) => return Err(e)
+                        }
+                    };
+                    stream.set_nonblocking(false)?;
+                    self.peer = peer.to_string();
+                    record(&self.transcript, Direction::Data, &format!("Accepted data connection from {}", self.peer));
+                    stream
+                }
+            };
+            self.stream = Some(secure(&tls, stream)?);
+        }
+        self.stream.as_mut().ok_or_else(|| std::io::ErrorKind::NotConnected.into())
+    }
+}
+
+impl Read for DataStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.throttles.is_empty() {
+            return self.stream()?.read(buf);
+        }
+        let len = cmp::min(buf.len(), THROTTLED_CHUNK);
+        let n = self.stream()?.read(&mut buf[..len])?;
+        for throttle in &self.throttles {
+            throttle.download.take(n);
+        }
+        Ok(n)
+    }
+}
+
+impl Write for DataStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.throttles.is_empty() {
+            return self.stream()?.write(buf);
+        }
+        let len = cmp::min(buf.len(), THROTTLED_CHUNK);
+        for throttle in &self.throttles {
+            throttle.upload.take(len);
+        }
+        self.stream()?.write(&buf[..len])
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream()?.flush()
+    }
+}
+
+impl Drop for DataStream {
+    fn drop(&mut self) {
+        let opened = match (&mut self.stream, &self.pending) {
+            (Some(stream), _) => {
+                stream.shutdown();
+                true
+            }
+            (None, Some((Pending::Connected(_), _))) => true,
+            _ => false
+        };
+        if opened {
+            record(&self.transcript, Direction::Data, &format!("Closed data connection to {}", self.peer));
+        }
+    }
 }
 
 #[derive(Debug)]
-pub struct ServerResponse(String, String); // Only used for intermediate and positive responses (1xx, 2xx, 3xx)
+pub struct ServerResponse(pub String, pub String); // Reply code and the full (possibly multi-line) text after it
 
 impl From<(&str, &str)> for ServerResponse {
     fn from(tuple: (&str, &str)) -> Self {
@@ -25,16 +160,146 @@ impl From<(&str, &str)> for ServerResponse {
     }
 }
 
+impl std::fmt::Display for ServerResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.0, self.1.lines().map(str::trim).collect::<Vec<_>>().join(" "))
+    }
+}
+
+// Commands whose argument is a path on the server
+const PATH_COMMANDS: [&str; 14] = ["RETR", "STOR", "APPE", "DELE", "MKD", "RMD", "CWD", "SIZE", "MDTM", "RNFR", "RNTO", "NLST", "LIST", "MLSD"];
+
+/// The command a reply was given to, with the path it operated on
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    pub command: String,
+    pub path: Option<String>
+}
+
+impl Context {
+    fn new(command: &str, arguments: &[&str]) -> Context {
+        let path = if PATH_COMMANDS.contains(&command.to_ascii_uppercase().as_str()) && !arguments.is_empty() {
+            Some(arguments.join(" "))
+        } else {
+            None
+        };
+        Context { command: command.to_string(), path }
+    }
+}
+
+impl std::fmt::Display for Context {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "{} {}", self.command, path),
+            None => write!(f, "{}", self.command)
+        }
+    }
+}
+
 #[derive(Debug, Snafu)]
-pub enum Error { // Used for 4xx and 5xx
-    #[snafu(display("Server returned negative reply: {} {}", response.0, response.1))]
-    NegativeReturnCode { response: ServerResponse },
+pub enum Error {
+    #[snafu(display("{}: not logged in ({})", context, response))]
+    NotLoggedIn { context: Context, response: ServerResponse }, // 530
+    #[snafu(display("{}: file unavailable ({})", context, response))]
+    FileUnavailable { context: Context, response: ServerResponse }, // 550
+    #[snafu(display("{}: storage allocation exceeded ({})", context, response))]
+    StorageExceeded { context: Context, response: ServerResponse }, // 552
+    #[snafu(display("{}: file name not allowed ({})", context, response))]
+    FileNameNotAllowed { context: Context, response: ServerResponse }, // 553
+    #[snafu(display("{}: server returned negative reply ({})", context, response))]
+    NegativeReturnCode { context: Context, response: ServerResponse }, // Any other 4xx and 5xx
+    #[snafu(display("{}: unexpected reply ({})", context, response))]
+    UnexpectedReply { context: Context, response: ServerResponse },
     #[snafu(display("Received malformed data"))]
     InvalidData,
     #[snafu(display("IO error: {}", source))]
     IOError { source: std::io::Error },
     #[snafu(display("Data race"))]
-    RaceError
+    RaceError,
+    #[snafu(display("Timed out waiting for a free connection"))]
+    PoolTimeout,
+    #[snafu(display("Server requires an account (ACCT) to log in"))]
+    AccountRequired,
+    #[snafu(display("{}: {}", context, source))]
+    Ssh { context: Context, source: ssh2::Error },
+    #[snafu(display("Host key of {} is not in known_hosts ({})", host, fingerprint))]
+    UnknownHostKey { host: String, fingerprint: String },
+    #[snafu(display("Host key of {} does not match known_hosts, the server may be impersonated ({})", host, fingerprint))]
+    HostKeyMismatch { host: String, fingerprint: String },
+    #[snafu(display("Certificate of {} is not trusted: {}", server, certificate))]
+    UnknownCertificate { server: String, certificate: Box<Certificate> },
+    #[snafu(display("Certificate of {} does not match the pinned one, the server may be impersonated. \
+        If it was replaced on purpose, run termftp --forget-certificate {}", server, server))]
+    CertificateChanged { server: String, pinned: String, fingerprint: String },
+    #[snafu(display("Server accepted none of the authentication methods"))]
+    AuthenticationFailed
+}
+
+impl Error {
+    fn negative(context: Context, response: ServerResponse) -> Error {
+        match response.0.as_str() {
+            "530" => Error::NotLoggedIn { context, response },
+            "550" => Error::FileUnavailable { context, response },
+            "552" => Error::StorageExceeded { context, response },
+            "553" => Error::FileNameNotAllowed { context, response },
+            _ => Error::NegativeReturnCode { context, response }
+        }
+    }
+
+    /// The server's reply, if the error is one
+    pub fn response(&self) -> Option<&ServerResponse> {
+        match self {
+            Error::NotLoggedIn { response, .. }
+            | Error::FileUnavailable { response, .. }
+            | Error::StorageExceeded { response, .. }
+            | Error::FileNameNotAllowed { response, .. }
+            | Error::NegativeReturnCode { response, .. }
+            | Error::UnexpectedReply { response, .. } => Some(response),
+            _ => None
+        }
+    }
+
+    /// The command and path the error occurred on, if it came from the server
+    pub fn context(&self) -> Option<&Context> {
+        match self {
+            Error::NotLoggedIn { context, .. }
+            | Error::FileUnavailable { context, .. }
+            | Error::StorageExceeded { context, .. }
+            | Error::FileNameNotAllowed { context, .. }
+            | Error::NegativeReturnCode { context, .. }
+            | Error::UnexpectedReply { context, .. }
+            | Error::Ssh { context, .. } => Some(context),
+            _ => None
+        }
+    }
+
+    /// The reply code of a negative reply
+    pub fn code(&self) -> Option<&str> {
+        match self {
+            Error::UnexpectedReply { .. } => None,
+            _ => self.response().map(|r| r.0.as_str())
+        }
+    }
+
+    /// Whether retrying the same operation later may succeed: 4xx replies, timeouts and dropped connections.
+    /// 5xx replies and malformed data are permanent
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::IOError { source } => matches!(source.kind(),
+                std::io::ErrorKind::TimedOut
+                | std::io::ErrorKind::WouldBlock
+                | std::io::ErrorKind::Interrupted
+                | std::io::ErrorKind::ConnectionReset
+                | std::io::ErrorKind::ConnectionAborted
+                | std::io::ErrorKind::ConnectionRefused
+                | std::io::ErrorKind::BrokenPipe
+                | std::io::ErrorKind::UnexpectedEof),
+            // Timeouts and dropped sockets (LIBSSH2_ERROR_SOCKET_SEND, _SOCKET_DISCONNECT, _SOCKET_TIMEOUT, _TIMEOUT, _SOCKET_RECV)
+            Error::Ssh { source, .. } => matches!(source.code(), ssh2::ErrorCode::Session(-7 | -13 | -30 | -9 | -43)),
+            Error::PoolTimeout => true,
+            _ => self.code().is_some_and(|code| code.starts_with('4'))
+        }
+    }
 }
 
 impl From<std::io::Error> for Error {
@@ -45,13 +310,49 @@ impl From<std::io::Error> for Error {
 
 pub type Result<T, E = self::Error> = std::result::Result<T, E>;
 
-impl From<ServerResponse> for Result<ServerResponse> {
-    fn from(response: ServerResponse) -> Self {
-        let char = response.0.chars().nth(0).unwrap_or('0');
-        match char {
-            '1' | '2' | '3' => Ok(response),
-            '4' | '5' => Err(Error::NegativeReturnCode { response: response }),
-            _ => Err(Error::InvalidData)
+/// Login schemes of application-level FTP proxies, as offered by WS_FTP and FileZilla.
+/// The control connection is opened to the proxy, `host` is the server behind it.
+#[derive(Clone)]
+pub enum ProxyLogin {
+    /// No proxy, log in directly
+    None,
+    /// USER user@host, PASS password
+    UserAtHost { host: String },
+    /// USER proxy_user, PASS proxy_password, then USER user@host, PASS password
+    UserAtHostAfterLogon { host: String, proxy_user: String, proxy_password: String },
+    /// USER proxy_user, PASS proxy_password, SITE host, then USER user, PASS password
+    Site { host: String, proxy_user: String, proxy_password: String },
+    /// USER proxy_user, PASS proxy_password, OPEN host, then USER user, PASS password
+    Open { host: String, proxy_user: String, proxy_password: String },
+    /// USER proxy_user, PASS proxy_password, then USER user, PASS password
+    Transparent { proxy_user: String, proxy_password: String },
+    /// USER user@proxy_user@host, PASS password@proxy_password
+    UserAtProxyUserAtHost { host: String, proxy_user: String, proxy_password: String }
+}
+
+/// The character set of file names and replies on the control connection, and of listings
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum Encoding {
+    #[default]
+    #[serde(rename = "utf-8")]
+    Utf8,
+    /// ISO 8859-1, still common on older servers
+    #[serde(rename = "latin1", alias = "iso-8859-1")]
+    Latin1
+}
+
+impl Encoding {
+    pub fn encode(self, text: &str) -> Vec<u8> {
+        match self {
+            Encoding::Utf8 => text.as_bytes().to_vec(),
+            Encoding::Latin1 => text.chars().map(|c| u8::try_from(c).unwrap_or(b'?')).collect()
+        }
+    }
+
+    pub fn decode(self, bytes: &[u8]) -> String {
+        match self {
+            Encoding::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+            Encoding::Latin1 => bytes.iter().map(|&b| char::from(b)).collect()
         }
     }
 }
@@ -66,62 +367,243 @@ pub enum TransferMode {
 impl Drop for Connection {
     fn drop(&mut self) {
         // self.drain_connection();
-        self.close().unwrap();
+        // The connection may already be broken, there is nothing left to do about it here
+        let _ = self.close();
     }
 }
 
 impl Connection {
+    /// Connects to `hostname` ("host:port"), through the proxy from the environment if there is one
     pub fn new(hostname: &str, connection_type: ConnectionType) -> self::Result<Connection> {
-        Ok(Connection { control_stream: TcpStream::connect(hostname)?, r#type: connection_type })
+        Self::with_connector(hostname, connection_type, proxy::from_env())
+    }
+
+    /// Connects to `hostname` with `connector`, which is also used for the passive data connections
+    pub fn with_connector(hostname: &str, connection_type: ConnectionType, connector: Arc<dyn Connector>) -> self::Result<Connection> {
+        Ok(Connection { control_stream: BufReader::new(Stream::Plain(connector.connect(hostname)?)), r#type: connection_type, connector, transcript: None, throttle: None, transfer_throttle: None,
+            tls: None, protect_data: false, encoding: Encoding::Utf8, context: Context { command: "Connect".to_string(), path: None } })
+    }
+
+    /// Secures the control and data connections with TLS. Implicit TLS starts right away,
+    /// explicit TLS with AUTH TLS when logging in, so this has to be called before `login`.
+    pub fn set_tls(&mut self, tls: Tls) -> self::Result<()> {
+        let implicit = tls.mode == TlsMode::Implicit;
+        self.tls = Some(tls);
+        if implicit {
+            self.secure_control_connection()?;
+        }
+        Ok(())
+    }
+
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+    }
+
+    fn secure_control_connection(&mut self) -> self::Result<()> {
+        let tls = self.tls.as_ref().ok_or(Error::InvalidData)?;
+        // The plain socket is replaced by the TLS stream over a handle to the same socket
+        let stream = tls.connect(self.control_stream.get_ref().tcp().try_clone()?, None)?;
+        record(&self.transcript, Direction::Data, &format!("Secured control connection with {}", stream.ssl().version_str()));
+        *self.control_stream.get_mut() = Stream::Tls(Box::new(stream));
+        Ok(())
+    }
+
+    // The TLS settings for new data connections, if they are to be protected
+    fn data_tls(&self) -> DataTls {
+        match &self.tls {
+            Some(tls) if self.protect_data => Some((tls.clone(), self.control_stream.get_ref().session())),
+            _ => None
+        }
+    }
+
+    /// Limits every data connection of this connection, e.g. with a throttle shared by all connections
+    pub fn set_throttle(&mut self, throttle: Arc<Throttle>) {
+        self.throttle = Some(throttle);
+    }
+
+    /// Additionally limits the data connections opened until it is cleared, for limiting a single transfer
+    pub fn set_transfer_throttle(&mut self, throttle: Option<Arc<Throttle>>) {
+        self.transfer_throttle = throttle;
+    }
+
+    /// Records every command, reply and data connection event of this connection to `sink`
+    pub fn set_transcript(&mut self, sink: Arc<dyn Sink>) {
+        self.transcript = Some(sink);
     }
 
     pub fn read_server_response(&mut self) -> self::Result<ServerResponse> {
         let mut res = String::new();
-        let mut reader = BufReader::new(&self.control_stream);
-        reader.read_line(&mut res)?;
-        let response: ServerResponse = res.split_at(4).into();
+        self.read_line(&mut res)?;
+        record(&self.transcript, Direction::Received, &res);
+        if res.len() < 4 || !res.is_char_boundary(4) {
+            return Err(Error::InvalidData);
+        }
+        // Multi-line replies start with "xyz-" and run until a line starting with "xyz "
+        if res.as_bytes()[3] == b'-' {
+            let terminator = format!("{} ", &res[..3]);
+            loop {
+                let mut line = String::new();
+                if self.read_line(&mut line)? == 0 {
+                    return Err(Error::InvalidData);
+                }
+                record(&self.transcript, Direction::Received, &line);
+                res.push_str(&line);
+                if line.starts_with(&terminator) {
+                    break;
+                }
+            }
+        }
+        let response: ServerResponse = (&res[..3], &res[4..]).into();
+
+        match response.0.chars().next() {
+            Some('1' | '2' | '3') => Ok(response),
+            Some('4' | '5') => Err(Error::negative(self.context.clone(), response)),
+            _ => Err(Error::InvalidData)
+        }
+    }
+
+    // Appends a line of the control connection to `line`, returning the number of bytes read
+    fn read_line(&mut self, line: &mut String) -> std::io::Result<usize> {
+        let mut bytes = Vec::new();
+        let n = self.control_stream.read_until(b'\n', &mut bytes)?;
+        line.push_str(&self.encoding.decode(&bytes));
+        Ok(n)
+    }
 
-        response.into()
+    /// Sends a command without waiting for the reply
+    pub fn send_command(&mut self, command: &str, arguments: Vec<&str>) -> self::Result<()> {
+        let line = format!("{} {}", command, arguments.join(" "));
+        self.context = Context::new(command, &arguments);
+        record(&self.transcript, Direction::Sent, &transcript::redact(command, &line));
+        self.control_stream.get_mut().write_all(&self.encoding.encode(&format!("{}\n", line)))?;
+        Ok(())
     }
 
     pub fn issue_command(&mut self, command: &str, arguments: Vec<&str>) -> self::Result<ServerResponse> {
-        self.control_stream.write_fmt(format_args!("{} {}\n", command, arguments.join(" ")))?;
-        Ok(self.read_server_response()?)
+        self.send_command(command, arguments)?;
+        self.read_server_response()
     }
 
     pub fn login(&mut self, username: &str, password: &str) -> self::Result<ServerResponse> {
+        self.login_with_proxy(&ProxyLogin::None, username, password, None)
+    }
+
+    /// Logs in through an FTP proxy using `proxy`'s scheme. `account` is sent with ACCT if the server asks for one (332)
+    pub fn login_with_proxy(&mut self, proxy: &ProxyLogin, username: &str, password: &str, account: Option<&str>) -> self::Result<ServerResponse> {
         self.read_server_response()?;
-        self.issue_command("USER", vec![username])?;
-        Ok(self.issue_command("PASS", vec![password])?)
+        if self.tls.as_ref().is_some_and(|tls| tls.mode == TlsMode::Explicit) {
+            self.issue_command("AUTH", vec!["TLS"])?;
+            self.secure_control_connection()?;
+        }
+        let response = self.log_in(proxy, username, password, account)?;
+        if self.tls.is_some() {
+            // No buffer size applies to TLS, then ask for protected data connections
+            self.issue_command("PBSZ", vec!["0"])?;
+            self.issue_command("PROT", vec!["P"])?;
+            self.protect_data = true;
+        }
+        Ok(response)
+    }
+
+    fn log_in(&mut self, proxy: &ProxyLogin, username: &str, password: &str, account: Option<&str>) -> self::Result<ServerResponse> {
+        match proxy {
+            ProxyLogin::None => self.authenticate(username, password, account),
+            ProxyLogin::UserAtHost { host } => {
+                self.authenticate(&format!("{}@{}", username, host), password, account)
+            }
+            ProxyLogin::UserAtHostAfterLogon { host, proxy_user, proxy_password } => {
+                self.authenticate(proxy_user, proxy_password, None)?;
+                self.authenticate(&format!("{}@{}", username, host), password, account)
+            }
+            ProxyLogin::Site { host, proxy_user, proxy_password } => {
+                self.authenticate(proxy_user, proxy_password, None)?;
+                self.issue_command("SITE", vec![host])?;
+                self.authenticate(username, password, account)
+            }
+            ProxyLogin::Open { host, proxy_user, proxy_password } => {
+                self.authenticate(proxy_user, proxy_password, None)?;
+                self.issue_command("OPEN", vec![host])?;
+                self.authenticate(username, password, account)
+            }
+            ProxyLogin::Transparent { proxy_user, proxy_password } => {
+                self.authenticate(proxy_user, proxy_password, None)?;
+                self.authenticate(username, password, account)
+            }
+            ProxyLogin::UserAtProxyUserAtHost { host, proxy_user, proxy_password } => {
+                self.authenticate(&format!("{}@{}@{}", username, proxy_user, host), &format!("{}@{}", password, proxy_password), account)
+            }
+        }
+    }
+
+    // USER, followed by PASS and ACCT if the server asks for them
+    fn authenticate(&mut self, username: &str, password: &str, account: Option<&str>) -> self::Result<ServerResponse> {
+        let mut response = self.issue_command("USER", vec![username])?;
+        if response.0 == "331" {
+            response = self.issue_command("PASS", vec![password])?;
+        }
+        if response.0 == "332" {
+            response = self.account(account.ok_or(Error::AccountRequired)?)?;
+        }
+        Ok(response)
+    }
+
+    pub fn account(&mut self, account: &str) -> self::Result<ServerResponse> {
+        self.issue_command("ACCT", vec![account])
     }
     pub fn close(&mut self) -> self::Result<()> {
         self.issue_command("QUIT", vec![])?;
         Ok(())   
     }
-    pub fn establish_data_connection(&mut self) -> self::Result<TcpStream> {
+    /// Sends PASV and returns the address and port the server is listening on
+    pub fn passive_address(&mut self) -> self::Result<(String, u16)> {
+        let passive_response = self.issue_command("PASV", vec![])?;
+        if !passive_response.0.starts_with("227") {
+            return Err(Error::UnexpectedReply { context: self.context.clone(), response: passive_response });
+        }
+        let passive_data: Vec<u8> = passive_response.1.split_once('(')
+            .ok_or(Error::InvalidData)?
+            .1
+            .split_once(')')
+            .ok_or(Error::InvalidData)?
+            .0
+            .split(',')
+            .map(|s| s.trim().parse::<u8>())
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|_| Error::InvalidData)?;
+        if passive_data.len() != 6 {
+            return Err(Error::InvalidData);
+        }
+        let address = format!("{}.{}.{}.{}", passive_data[0], passive_data[1], passive_data[2], passive_data[3]);
+        Ok((address, u16::from(passive_data[4]) * 0x100 + u16::from(passive_data[5])))
+    }
+
+    /// Tells the server to connect to `address`:`port` for the next transfer
+    pub fn port(&mut self, address: &str, port: u16) -> self::Result<ServerResponse> {
+        let argument = format!("{},{},{}", address.replace('.', ","), port >> 8, port & 0xff);
+        self.issue_command("PORT", vec![argument.as_str()])
+    }
+
+    pub fn establish_data_connection(&mut self) -> self::Result<DataStream> {
         match &self.r#type {
             self::ConnectionType::Passive => {  
-                let passive_response = self.issue_command("PASV", vec![])?;
-                if passive_response.0.starts_with("227") {
-                    let passive_data: Vec<&str> = passive_response.1.split_once('(')
-                        .ok_or(Error::InvalidData)?
-                        .1
-                        .trim_end()
-                        .strip_suffix(").")
-                        .ok_or(Error::InvalidData)?
-                        .split(',').collect();
-                    let address = passive_data[0..4].join(".");
-                    let port = (passive_data[4].parse::<u16>().unwrap_or(0) * 0x100 + passive_data[5].parse::<u16>().unwrap_or(0)).to_string();
-                    TcpStream::connect(address + ":" + &port).map_err(|e| Error::IOError { source: e })
-                }
-                else {
-                    Err(Error::NegativeReturnCode { response: passive_response })
-                }
+                let (address, port) = self.passive_address()?;
+                let peer = format!("{}:{}", address, port);
+                let stream = self.connector.connect(&peer).map_err(|e| Error::IOError { source: e })?;
+                record(&self.transcript, Direction::Data, &format!("Opened data connection to {}", peer));
+                let throttles = self.throttle.iter().chain(self.transfer_throttle.iter()).cloned().collect();
+                Ok(DataStream { stream: None, pending: Some((Pending::Connected(stream), self.data_tls())), peer, transcript: self.transcript.clone(), throttles })
             }
-            self::ConnectionType::Active => { // Do not use!
-                /*let active_response = self.issue_command("PORT", vec![])?;
-                Ok(active_response)*/
-                Err(Error::InvalidData)
+            self::ConnectionType::Active => {
+                // Listen on the address the server already reaches us on
+                let address = self.control_stream.get_ref().tcp().local_addr()?.ip();
+                let listener = TcpListener::bind((address, 0))?;
+                let port = listener.local_addr()?.port();
+                match address {
+                    IpAddr::V4(_) => self.port(&address.to_string(), port)?,
+                    IpAddr::V6(_) => self.issue_command("EPRT", vec![&format!("|2|{}|{}|", address, port)])?
+                };
+                let throttles = self.throttle.iter().chain(self.transfer_throttle.iter()).cloned().collect();
+                Ok(DataStream { stream: None, pending: Some((Pending::Listening(listener), self.data_tls())), peer: String::new(), transcript: self.transcript.clone(), throttles })
             }
         }
     }
@@ -129,11 +611,22 @@ impl Connection {
         let mut stream = self.establish_data_connection()?;
         self.issue_command("NLST", vec![])?;
 
-        let mut res = String::new();
-        stream.read_to_string(&mut res)?;
+        let mut res = Vec::new();
+        stream.read_to_end(&mut res)?;
+        self.read_server_response()?;
+
+        Ok(self.encoding.decode(&res).split('\n').map(|s| s.trim_end().to_string()).filter(|s| !s.is_empty()).collect())
+    }
+    /// The raw lines of a LIST of the current directory
+    pub fn get_detailed_listing(&mut self) -> self::Result<Vec<String>> {
+        let mut stream = self.establish_data_connection()?;
+        self.issue_command("LIST", vec![])?;
+
+        let mut res = Vec::new();
+        stream.read_to_end(&mut res)?;
         self.read_server_response()?;
 
-        Ok(res.split('\n').map(|s| s.trim_end().to_string()).filter(|s| s.len() > 0).collect())
+        Ok(self.encoding.decode(&res).split('\n').map(|s| s.trim_end().to_string()).filter(|s| !s.is_empty()).collect())
     }
     pub fn set_transfer_mode(&mut self, mode: TransferMode) -> self::Result<ServerResponse> {
         self.issue_command("TYPE", vec![
@@ -157,19 +650,136 @@ impl Connection {
         Ok(res)
     }
 
+    /// Receives `length` bytes of `filename` starting at `offset`, handing each chunk to `sink` as it arrives.
+    /// The transfer is aborted once the range has been read; if `length` is `None` it runs to the end of the file.
+    /// Returns the number of bytes received, which is less than `length` if the file ended early.
+    pub fn receive_range<F>(&mut self, filename: &str, offset: u64, length: Option<u64>, mut sink: F) -> self::Result<u64>
+    where
+        F: FnMut(&[u8]) -> std::io::Result<()>
+    {
+        let mut stream = self.establish_data_connection()?;
+        if offset > 0 {
+            self.restart_at(offset)?;
+        }
+        self.issue_command("RETR", vec![filename])?;
+
+        let limit = length.unwrap_or(u64::MAX);
+        let mut buf = [0u8; 16384];
+        let mut received = 0;
+        while received < limit {
+            let want = cmp::min(buf.len() as u64, limit - received) as usize;
+            let n = stream.read(&mut buf[..want])?;
+            if n == 0 {
+                break;
+            }
+            sink(&buf[..n])?;
+            received += n as u64;
+        }
+
+        if received < limit {
+            // The server closed the data connection, the transfer is complete
+            self.read_server_response()?;
+        }
+        else {
+            drop(stream);
+            self.abort()?;
+        }
+
+        Ok(received)
+    }
+
+    pub fn restart_at(&mut self, offset: u64) -> self::Result<ServerResponse> {
+        self.issue_command("REST", vec![offset.to_string().as_str()])
+    }
+
+    /// Aborts the transfer in progress. The server first answers the transfer itself (226 if it had already completed,
+    /// 426 if it was interrupted) and then replies to ABOR.
+    pub fn abort(&mut self) -> self::Result<ServerResponse> {
+        match self.issue_command("ABOR", vec![]) {
+            Ok(_) => self.read_server_response(),
+            Err(e) if e.response().is_some() => self.read_server_response(),
+            Err(e) => Err(e)
+        }
+    }
+
     pub fn upload_file(&mut self, data: &[u8], filename: &str) -> self::Result<ServerResponse> {
         {
             let mut stream = self.establish_data_connection()?;
             self.issue_command("STOR", vec![filename])?;
             stream.write_all(data)?;
+            // Sets up the connection even for an empty file
+            stream.flush()?;
         }
         self.read_server_response()
     }
 
+    /// Starts sending `filename` over a new data connection. Once the stream has been read and dropped,
+    /// the final reply has to be read with `read_server_response`.
+    pub fn retrieve(&mut self, filename: &str) -> self::Result<DataStream> {
+        self.retrieve_from(filename, 0)
+    }
+
+    /// Like `retrieve`, starting at `offset`. REST goes right before RETR, as servers may forget it on PASV or PORT
+    pub fn retrieve_from(&mut self, filename: &str, offset: u64) -> self::Result<DataStream> {
+        let stream = self.establish_data_connection()?;
+        if offset > 0 {
+            self.restart_at(offset)?;
+        }
+        self.issue_command("RETR", vec![filename])?;
+        Ok(stream)
+    }
+
+    /// Starts storing `filename` from a new data connection, see `retrieve`
+    pub fn store(&mut self, filename: &str) -> self::Result<DataStream> {
+        self.store_from(filename, 0)
+    }
+
+    /// Like `store`, writing from `offset` on, see `retrieve_from`
+    pub fn store_from(&mut self, filename: &str, offset: u64) -> self::Result<DataStream> {
+        let stream = self.establish_data_connection()?;
+        if offset > 0 {
+            self.restart_at(offset)?;
+        }
+        self.issue_command("STOR", vec![filename])?;
+        Ok(stream)
+    }
+
     pub fn get_remote_size(&mut self, filename: &str) -> self::Result<u64> {
         self.issue_command("SIZE", vec![filename])?.1.trim().parse::<u64>().map_err(|_| Error::InvalidData)
     }
 
+    pub fn modification_time(&mut self, filename: &str) -> self::Result<SystemTime> {
+        // "YYYYMMDDHHMMSS", possibly followed by fractions of a second
+        let response = self.issue_command("MDTM", vec![filename])?;
+        let digits = response.1.trim();
+        let field = |range: std::ops::Range<usize>| digits.get(range).and_then(|d| d.parse::<u32>().ok());
+        match (field(0..4), field(4..6), field(6..8), field(8..10), field(10..12), field(12..14)) {
+            (Some(year), Some(month), Some(day), Some(hour), Some(minute), Some(second)) =>
+                date::from_civil(i64::from(year), month, day, hour, minute, second).ok_or(Error::InvalidData),
+            _ => Err(Error::InvalidData)
+        }
+    }
+
+    pub fn rename(&mut self, from: &str, to: &str) -> self::Result<ServerResponse> {
+        self.issue_command("RNFR", vec![from])?;
+        self.issue_command("RNTO", vec![to])
+    }
+
+    /// Sets the permissions of `name` with the widespread SITE CHMOD extension
+    pub fn chmod(&mut self, name: &str, mode: u32) -> self::Result<ServerResponse> {
+        self.issue_command("SITE", vec!["CHMOD", &format!("{:o}", mode), name])
+    }
+
+    pub fn current_directory(&mut self) -> self::Result<String> {
+        // 257 "/some/dir" is the current directory, with quotes inside the name doubled
+        let response = self.issue_command("PWD", vec![])?;
+        let text = response.1.as_str();
+        match (text.find('"'), text.rfind('"')) {
+            (Some(start), Some(end)) if end > start => Ok(text[start + 1..end].replace("\"\"", "\"")),
+            _ => Err(Error::InvalidData)
+        }
+    }
+
     pub fn delete_file(&mut self, name: &str) -> self::Result<ServerResponse> {
         self.issue_command("DELE", vec![name])
     }
@@ -194,10 +804,18 @@ impl Connection {
 #[cfg(test)]
 mod tests {
     use crate::ftp;
+    use crate::segmented;
+    use crate::pool;
+    use crate::fxp;
+    use crate::transcript;
+    use std::time::Duration;
     use std::fs::{File};
     use std::io::prelude::{Write};
     use lazy_static::lazy_static;
     use std::sync::Mutex;
+    use std::net::TcpListener;
+    use std::io::{BufRead, BufReader};
+    use std::thread;
 
     static FTP_URL: &str = "ftp.dlptest.com:21";
     static FTP_USER: &str = "dlpuser";
@@ -216,6 +834,30 @@ mod tests {
         Ok(ftp)
     }
 
+    // Plays the server side of a control connection, answering each command with the next scripted reply.
+    // The handle yields the commands that were received
+    fn scripted_server(replies: Vec<&'static str>) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            writer.write_all(b"220 Ready\r\n").unwrap();
+            let mut commands = Vec::new();
+            for reply in replies {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                commands.push(line.trim_end().to_string());
+                writer.write_all(format!("{}\r\n", reply).as_bytes()).unwrap();
+            }
+            commands
+        });
+        (address, handle)
+    }
+
     #[test]
     fn login_test() -> ftp::Result<()> {
         // Log onto DLP test server
@@ -223,6 +865,127 @@ mod tests {
         Ok(())
     }
     #[test]
+    fn proxy_site_login_test() -> ftp::Result<()> {
+        let (address, server) = scripted_server(vec![
+            "331 Proxy password required", "230 Proxy login ok", "200 Connected to ftp.example.com",
+            "331 Password required", "332 Account required", "230 Logged in", "221 Bye"
+        ]);
+        {
+            let mut ftp = ftp::Connection::new(&address, ftp::ConnectionType::Passive)?;
+            let proxy = ftp::ProxyLogin::Site {
+                host: "ftp.example.com".to_string(),
+                proxy_user: "fwuser".to_string(),
+                proxy_password: "fwpass".to_string()
+            };
+            ftp.login_with_proxy(&proxy, "user", "pass", Some("acct"))?;
+        }
+        assert_eq!(server.join().unwrap(), vec![
+            "USER fwuser", "PASS fwpass", "SITE ftp.example.com", "USER user", "PASS pass", "ACCT acct", "QUIT"
+        ]);
+        Ok(())
+    }
+    #[test]
+    fn proxy_user_at_host_login_test() -> ftp::Result<()> {
+        let (address, server) = scripted_server(vec!["331 Password required", "332 Account required", "221 Bye"]);
+        {
+            let mut ftp = ftp::Connection::new(&address, ftp::ConnectionType::Passive)?;
+            let proxy = ftp::ProxyLogin::UserAtHost { host: "ftp.example.com:2121".to_string() };
+            let res = ftp.login_with_proxy(&proxy, "user", "pass", None);
+            assert!(matches!(res, Err(ftp::Error::AccountRequired)));
+        }
+        assert_eq!(server.join().unwrap(), vec!["USER user@ftp.example.com:2121", "PASS pass", "QUIT"]);
+        Ok(())
+    }
+    #[test]
+    fn transcript_test() -> ftp::Result<()> {
+        let (address, server) = scripted_server(vec!["331 Password required", "230 Logged in", "221 Bye"]);
+        let memory = std::sync::Arc::new(transcript::Memory::new(100));
+        {
+            let mut ftp = ftp::Connection::new(&address, ftp::ConnectionType::Passive)?;
+            ftp.set_transcript(memory.clone());
+            ftp.login("user", "hunter2")?;
+        }
+        server.join().unwrap();
+
+        let lines: Vec<String> = memory.entries().iter().map(|e| e.to_string().split_once("] ").unwrap().1.to_string()).collect();
+        assert_eq!(lines, vec![
+            "< 220 Ready", "> USER user", "< 331 Password required", "> PASS ****", "< 230 Logged in", "> QUIT", "< 221 Bye"
+        ]);
+        Ok(())
+    }
+    #[test]
+    fn encoding_test() {
+        assert_eq!(ftp::Encoding::Latin1.encode("CWD Müll€"), b"CWD M\xfcll?");
+        assert_eq!(ftp::Encoding::Latin1.decode(b"257 \"/M\xfcll\""), "257 \"/Müll\"");
+        assert_eq!(ftp::Encoding::Utf8.decode("Müll".as_bytes()), "Müll");
+    }
+    #[test]
+    fn directory_and_time_replies_test() -> ftp::Result<()> {
+        let (address, server) = scripted_server(vec![
+            "331 Password required", "230 Logged in",
+            "257 \"/home/with \"\"quotes\"\"\" is the current directory", "213 20000229123456.789", "350 Ready", "250 Renamed", "221 Bye"
+        ]);
+        {
+            let mut ftp = ftp::Connection::new(&address, ftp::ConnectionType::Passive)?;
+            ftp.login("user", "pass")?;
+            assert_eq!(ftp.current_directory()?, "/home/with \"quotes\"");
+            assert_eq!(ftp.modification_time("file")?, std::time::UNIX_EPOCH + Duration::from_secs(951_827_696));
+            ftp.rename("old", "new")?;
+        }
+        let commands = server.join().unwrap();
+        assert_eq!(commands[2..6], ["PWD", "MDTM file", "RNFR old", "RNTO new"]);
+        Ok(())
+    }
+
+    #[test]
+    fn restart_order_test() -> ftp::Result<()> {
+        let (address, server) = scripted_server(vec![
+            "331 Password required", "230 Logged in",
+            "200 PORT ok", "350 Restarting at 5", "150 Opening", "200 PORT ok", "350 Restarting at 7", "150 Opening", "221 Bye"
+        ]);
+        {
+            let mut ftp = ftp::Connection::new(&address, ftp::ConnectionType::Active)?;
+            ftp.login("user", "pass")?;
+            ftp.retrieve_from("a.txt", 5)?;
+            ftp.store_from("b.txt", 7)?;
+        }
+        let commands = server.join().unwrap();
+        // REST comes right before the transfer, after the data connection is set up
+        assert!(commands[2].starts_with("PORT ") && commands[5].starts_with("PORT "));
+        assert_eq!(commands[3..5], ["REST 5", "RETR a.txt"]);
+        assert_eq!(commands[6..8], ["REST 7", "STOR b.txt"]);
+        Ok(())
+    }
+
+    #[test]
+    fn error_context_test() -> ftp::Result<()> {
+        let (address, server) = scripted_server(vec![
+            "331 Password required", "230 Logged in",
+            "550-No such file\r\n550 Check the file name", "452 Insufficient storage", "553 Name not allowed", "221 Bye"
+        ]);
+        {
+            let mut ftp = ftp::Connection::new(&address, ftp::ConnectionType::Passive)?;
+            ftp.login("user", "pass")?;
+
+            let e = ftp.delete_file("missing.txt").unwrap_err();
+            assert!(matches!(e, ftp::Error::FileUnavailable { .. }));
+            assert!(!e.is_transient());
+            let context = e.context().unwrap();
+            assert_eq!((context.command.as_str(), context.path.as_deref()), ("DELE", Some("missing.txt")));
+            assert_eq!(e.response().unwrap().to_string(), "550 No such file 550 Check the file name");
+
+            let e = ftp.make_directory("new").unwrap_err();
+            assert!(matches!(e, ftp::Error::NegativeReturnCode { .. }));
+            assert!(e.is_transient());
+
+            let e = ftp.change_directory("bad|name").unwrap_err();
+            assert!(matches!(e, ftp::Error::FileNameNotAllowed { .. }));
+            assert_eq!(e.to_string(), "CWD bad|name: file name not allowed (553 Name not allowed)");
+        }
+        server.join().unwrap();
+        Ok(())
+    }
+    #[test]
     fn data_connection_test() -> ftp::Result<()> {
         // Log onto DLP test server
         let mut ftp = test_login()?;
@@ -266,7 +1029,7 @@ mod tests {
             // Write file
             let data = ftp.receive_file(&files[0])?;
 
-            bytes_written = file.write(&data).map_err(|e| ftp::Error::from(e))?;
+            bytes_written = file.write(&data).map_err(ftp::Error::from)?;
         }   
 
         let file = File::open(&path)?;
@@ -278,6 +1041,97 @@ mod tests {
         Ok(())
     }
     #[test]
+    fn segmented_download_test() -> ftp::Result<()> {
+        // Log onto DLP test server
+        let mut ftp = test_login()?;
+
+        // Upload a file large enough to be split
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        ftp.set_transfer_mode(ftp::TransferMode::Binary)?;
+        ftp.upload_file(&data, "test_segmented_file")?;
+        let size = ftp.get_remote_size("test_segmented_file")?;
+
+        // Fetch it over four pooled connections
+        let pool = pool::Pool::new(4, Duration::from_secs(60));
+        let connect = || pool.get(FTP_URL, FTP_USER, FTP_PASS, ftp::ConnectionType::Passive);
+        let path = std::path::Path::new("/tmp/test_segmented_file");
+        segmented::download(connect, "test_segmented_file", size, path, 4, |_| {})?;
+
+        assert_eq!(std::fs::read(path)?, data);
+
+        Ok(())
+    }
+    #[test]
+    fn pool_test() -> ftp::Result<()> {
+        let _guard = FTP_MUTEX.lock().map_err(|_| ftp::Error::RaceError)?;
+        let pool = pool::Pool::new(2, Duration::from_secs(60));
+        pool.set_limit(FTP_URL, FTP_USER, 1);
+
+        // The only connection is reused once it has been handed back
+        {
+            let mut ftp = pool.get(FTP_URL, FTP_USER, FTP_PASS, ftp::ConnectionType::Passive)?;
+            ftp.make_directory("this_is_a_pool_test_directory")?;
+            ftp.change_directory("this_is_a_pool_test_directory")?;
+        }
+        let mut ftp = pool.get(FTP_URL, FTP_USER, FTP_PASS, ftp::ConnectionType::Passive)?;
+        assert_eq!(ftp.get_directory_listing()?.len(), 0);
+        ftp.root_directory()?;
+        ftp.remove_directory("this_is_a_pool_test_directory")?;
+
+        Ok(())
+    }
+    #[test]
+    fn fxp_test() -> ftp::Result<()> {
+        let string = "This is a test file";
+
+        // Log onto DLP test server twice, acting as both source and destination
+        let mut source = test_login()?;
+        let mut destination = test_login()?;
+        source.upload_file(string.as_bytes(), "test_fxp_source")?;
+
+        // The data goes either directly or through the client, depending on what the server allows
+        println!("{:?}", fxp::transfer(&mut source, "test_fxp_source", &mut destination, "test_fxp_destination")?);
+
+        let remote = String::from_utf8(destination.receive_file("test_fxp_destination")?).map_err(|_| ftp::Error::InvalidData)?;
+        assert_eq!(remote, string);
+
+        Ok(())
+    }
+    #[test]
+    fn fxp_order_test() -> ftp::Result<()> {
+        let login = ["331 Password required", "230 Logged in", "200 Type set"];
+        let (source_address, source_server) = scripted_server([&login[..], &["213 5", "200 PORT ok", "150 Opening\r\n226 Sent", "221 Bye"]].concat());
+        let (destination_address, destination_server) = scripted_server([&login[..], &[
+            "227 Entering Passive Mode (127,0,0,1,4,1)", "150 Ready\r\n226 Stored", "221 Bye"
+        ]].concat());
+        {
+            let mut source = ftp::Connection::new(&source_address, ftp::ConnectionType::Passive)?;
+            source.login("user", "pass")?;
+            let mut destination = ftp::Connection::new(&destination_address, ftp::ConnectionType::Passive)?;
+            destination.login("user", "pass")?;
+            assert_eq!(fxp::transfer(&mut source, "a.txt", &mut destination, "b.txt")?, fxp::Route::Direct);
+        }
+        // The destination is told to store before the source starts sending
+        assert_eq!(source_server.join().unwrap()[3..], ["SIZE a.txt", "PORT 127,0,0,1,4,1", "RETR a.txt", "QUIT"]);
+        assert_eq!(destination_server.join().unwrap()[3..], ["PASV", "STOR b.txt", "QUIT"]);
+
+        // A source that cannot send the file leaves the destination's STOR aborted
+        let (source_address, source_server) = scripted_server([&login[..], &["502 No SIZE", "200 PORT ok", "550 No such file", "221 Bye"]].concat());
+        let (destination_address, destination_server) = scripted_server([&login[..], &[
+            "227 Entering Passive Mode (127,0,0,1,4,1)", "150 Ready", "426 Aborted\r\n226 ABOR ok", "221 Bye"
+        ]].concat());
+        {
+            let mut source = ftp::Connection::new(&source_address, ftp::ConnectionType::Passive)?;
+            source.login("user", "pass")?;
+            let mut destination = ftp::Connection::new(&destination_address, ftp::ConnectionType::Passive)?;
+            destination.login("user", "pass")?;
+            assert!(matches!(fxp::transfer(&mut source, "a.txt", &mut destination, "b.txt"), Err(ftp::Error::FileUnavailable { .. })));
+        }
+        source_server.join().unwrap();
+        assert_eq!(destination_server.join().unwrap()[3..], ["PASV", "STOR b.txt", "ABOR", "QUIT"]);
+        Ok(())
+    }
+    #[test]
     fn file_upload_test() -> ftp::Result<()> {
         let string = "This is a test file";
 
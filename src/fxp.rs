@@ -0,0 +1,105 @@
+use crate::ftp::{self, Connection, ServerResponse, TransferMode};
+use std::io;
+
+/// The path the data of a server-to-server transfer took
+#[derive(Debug, PartialEq, Eq)]
+pub enum Route {
+    /// Directly between the two servers
+    Direct,
+    /// Through the client, because the servers could not be pointed at each other
+    Relayed
+}
+
+// Replies meaning the data connection between the two servers could not be set up
+fn is_unreachable(e: &ftp::Error) -> bool {
+    matches!(e.code(), Some("425" | "426"))
+}
+
+// Reads replies until the final one, skipping the preliminary 1xx replies
+fn read_final_response(connection: &mut Connection) -> ftp::Result<ServerResponse> {
+    loop {
+        let response = connection.read_server_response()?;
+        if !response.0.starts_with('1') {
+            return Ok(response);
+        }
+    }
+}
+
+// Returns false if the servers refused to connect to each other
+fn direct(source: &mut Connection, source_file: &str, destination: &mut Connection, destination_file: &str) -> ftp::Result<bool> {
+    // A missing file fails here, before the destination is told to store it. Servers without SIZE find out with RETR
+    if let Err(e @ ftp::Error::FileUnavailable { .. }) = source.get_remote_size(source_file) {
+        return Err(e);
+    }
+    let (address, port) = destination.passive_address()?;
+    match source.port(&address, port) {
+        Ok(_) => {}
+        // Servers guarding against bounce attacks refuse PORT to anything but the client's address
+        Err(e) if e.code().is_some() => return Ok(false),
+        Err(e) => return Err(e)
+    }
+
+    // STOR goes first, so the destination expects the data before the source connects to it and starts sending
+    match destination.issue_command("STOR", vec![destination_file]) {
+        Ok(_) => {}
+        Err(e) if is_unreachable(&e) => return Ok(false),
+        Err(e) => return Err(e)
+    }
+    // A missing source file leaves the destination waiting, it is told to give up
+    if let Err(e) = source.issue_command("RETR", vec![source_file]) {
+        destination.abort()?;
+        return if is_unreachable(&e) { Ok(false) } else { Err(e) };
+    }
+
+    let sent = read_final_response(source);
+    let stored = read_final_response(destination);
+    match (sent, stored) {
+        (Ok(_), Ok(_)) => Ok(true),
+        (Err(e), _) | (_, Err(e)) if is_unreachable(&e) => Ok(false),
+        (Err(e), _) | (_, Err(e)) => Err(e)
+    }
+}
+
+/// Copies `source_file` to `destination_file` by passing the data through the client
+pub fn relay(source: &mut Connection, source_file: &str, destination: &mut Connection, destination_file: &str) -> ftp::Result<()> {
+    let mut input = source.establish_data_connection()?;
+    source.issue_command("RETR", vec![source_file])?;
+
+    let mut output = match destination.establish_data_connection() {
+        Ok(output) => output,
+        Err(e) => {
+            drop(input);
+            source.abort()?;
+            return Err(e);
+        }
+    };
+    if let Err(e) = destination.issue_command("STOR", vec![destination_file]) {
+        drop(input);
+        source.abort()?;
+        return Err(e);
+    }
+
+    io::copy(&mut input, &mut output)?;
+    drop(output);
+    drop(input);
+
+    source.read_server_response()?;
+    destination.read_server_response()?;
+    Ok(())
+}
+
+/// Copies `source_file` on one server to `destination_file` on another (FXP).
+/// The destination is put in passive mode and the source is pointed at it with PORT, so the data flows
+/// directly between the servers. If either server refuses the foreign address, the data is relayed through the client.
+pub fn transfer(source: &mut Connection, source_file: &str, destination: &mut Connection, destination_file: &str) -> ftp::Result<Route> {
+    source.set_transfer_mode(TransferMode::Binary)?;
+    destination.set_transfer_mode(TransferMode::Binary)?;
+
+    if direct(source, source_file, destination, destination_file)? {
+        Ok(Route::Direct)
+    }
+    else {
+        relay(source, source_file, destination, destination_file)?;
+        Ok(Route::Relayed)
+    }
+}
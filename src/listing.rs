@@ -0,0 +1,181 @@
+use crate::date;
+use crate::remote_fs::{Entry, EntryKind};
+
+const MONTHS: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+
+/// Parses one line of a LIST reply, in either the Unix `ls -l` or the DOS/IIS format. A line in a format the parser
+/// does not know is taken as a file name. Returns None for lines that describe no entry, such as "total 12", "." and "..".
+pub fn parse_line(line: &str) -> Option<Entry> {
+    if line.is_empty() || line.starts_with("total ") {
+        return None;
+    }
+    let entry = parse_unix(line).or_else(|| parse_dos(line)).unwrap_or_else(|| Entry::new(line, EntryKind::File));
+    if entry.name == "." || entry.name == ".." {
+        return None;
+    }
+    Some(entry)
+}
+
+/// The entries of a whole LIST reply, see `parse_line`
+pub fn parse_listing<S: AsRef<str>>(lines: &[S]) -> Vec<Entry> {
+    lines.iter().filter_map(|line| parse_line(line.as_ref())).collect()
+}
+
+// Splits off the first `n` whitespace separated fields, returning them and the rest of the line
+fn fields(line: &str, n: usize) -> Option<(Vec<&str>, &str)> {
+    let mut res = Vec::new();
+    let mut rest = line.trim_start();
+    for _ in 0..n {
+        let end = rest.find(char::is_whitespace)?;
+        res.push(&rest[..end]);
+        rest = rest[end..].trim_start();
+    }
+    Some((res, rest))
+}
+
+// "drwxr-xr-x 2 owner group 4096 Oct 19 12:00 name" or "-rw-r--r-- 1 owner group 123 Jan 1 2020 name"
+fn parse_unix(line: &str) -> Option<Entry> {
+    let kind = match line.chars().next()? {
+        'd' => EntryKind::Directory,
+        'l' => EntryKind::Symlink,
+        '-' => EntryKind::File,
+        _ => return None
+    };
+    let (head, name) = fields(line, 8)?;
+    let permissions = head[0];
+    if permissions.len() < 10 {
+        return None;
+    }
+    let size = head[4].parse::<u64>().ok()?;
+    let month = MONTHS.iter().position(|m| m.eq_ignore_ascii_case(head[5]))? as u32 + 1;
+    let day = head[6].parse::<u32>().ok()?;
+    let modified = match head[7].split_once(':') {
+        Some((hour, minute)) => {
+            // Without a year the date lies within the last six months
+            let (hour, minute) = (hour.parse::<u32>().ok()?, minute.parse::<u32>().ok()?);
+            let year = date::current_year();
+            let time = date::from_civil(year, month, day, hour, minute, 0)?;
+            if time > std::time::SystemTime::now() + std::time::Duration::from_secs(86400) {
+                date::from_civil(year - 1, month, day, hour, minute, 0)
+            }
+            else {
+                Some(time)
+            }
+        }
+        None => date::from_civil(head[7].parse::<i64>().ok()?, month, day, 0, 0, 0)
+    };
+    let name = match kind {
+        EntryKind::Symlink => name.split(" -> ").next().unwrap_or(name),
+        _ => name
+    };
+    if name.is_empty() {
+        return None;
+    }
+    Some(Entry {
+        name: name.to_string(),
+        kind,
+        size: Some(size),
+        modified,
+        permissions: Some(permissions.to_string()),
+        owner: Some(head[2].to_string())
+    })
+}
+
+// "10-19-26  12:00PM       <DIR>          name" or "10-19-26  12:00PM             1234 name"
+fn parse_dos(line: &str) -> Option<Entry> {
+    let (head, name) = fields(line, 3)?;
+    let mut date_parts = head[0].split('-').map(|p| p.parse::<u32>().ok());
+    let (month, day, year) = (date_parts.next()??, date_parts.next()??, date_parts.next()??);
+    let year = match year {
+        0..=69 => 2000 + year,
+        70..=99 => 1900 + year,
+        _ => year
+    };
+    let time = head[1].to_ascii_uppercase();
+    let (clock, afternoon) = match (time.strip_suffix("AM"), time.strip_suffix("PM")) {
+        (Some(clock), _) => (clock, false),
+        (_, Some(clock)) => (clock, true),
+        _ => (time.as_str(), false)
+    };
+    let (hour, minute) = clock.split_once(':')?;
+    let (hour, minute) = (hour.parse::<u32>().ok()? % 12 + if afternoon { 12 } else { 0 }, minute.parse::<u32>().ok()?);
+    let (kind, size) = if head[2].eq_ignore_ascii_case("<DIR>") {
+        (EntryKind::Directory, None)
+    }
+    else {
+        (EntryKind::File, Some(head[2].parse::<u64>().ok()?))
+    };
+    if name.is_empty() {
+        return None;
+    }
+    Some(Entry {
+        name: name.to_string(),
+        kind,
+        size,
+        modified: date::from_civil(i64::from(year), month, day, hour, minute, 0),
+        permissions: None,
+        owner: None
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unix_test() {
+        let entry = parse_line("-rw-r--r--    1 owner    group      123456 Jan 02  2020 some file.txt").unwrap();
+        assert_eq!(entry.name, "some file.txt");
+        assert_eq!(entry.kind, EntryKind::File);
+        assert_eq!(entry.size, Some(123456));
+        assert_eq!(entry.modified, date::from_civil(2020, 1, 2, 0, 0, 0));
+        assert_eq!(entry.permissions.as_deref(), Some("-rw-r--r--"));
+        assert_eq!(entry.owner.as_deref(), Some("owner"));
+
+        let entry = parse_line("drwxr-xr-x 2 ftp ftp 4096 Oct 19 12:00 pub").unwrap();
+        assert_eq!(entry.kind, EntryKind::Directory);
+        assert_eq!(entry.name, "pub");
+        assert!(entry.modified.is_some());
+
+        let entry = parse_line("lrwxrwxrwx 1 root root 7 Mar 5 2021 latest -> v1.2.3").unwrap();
+        assert_eq!(entry.kind, EntryKind::Symlink);
+        assert_eq!(entry.name, "latest");
+    }
+
+    #[test]
+    fn dos_test() {
+        let entry = parse_line("10-19-26  01:30PM       <DIR>          Reports").unwrap();
+        assert_eq!(entry.kind, EntryKind::Directory);
+        assert_eq!(entry.name, "Reports");
+        assert_eq!(entry.modified, date::from_civil(2026, 10, 19, 13, 30, 0));
+
+        let entry = parse_line("02-29-00  12:05AM                 1024 data.bin").unwrap();
+        assert_eq!(entry.size, Some(1024));
+        assert_eq!(entry.modified, date::from_civil(2000, 2, 29, 0, 5, 0));
+    }
+
+    #[test]
+    fn ignored_lines_test() {
+        assert!(parse_line("total 12").is_none());
+        assert!(parse_line("drwxr-xr-x 2 ftp ftp 4096 Oct 19 12:00 .").is_none());
+        assert!(parse_line("drwxr-xr-x 2 ftp ftp 4096 Oct 19 12:00 ..").is_none());
+        assert!(parse_line("").is_none());
+        assert_eq!(parse_line("some unknown format").map(|e| e.name), Some("some unknown format".to_string()));
+    }
+
+    #[test]
+    fn listing_test() {
+        let lines = [
+            "total 12",
+            "drwxr-xr-x 2 ftp ftp 4096 Oct 19 12:00 .",
+            "drwxr-xr-x 9 ftp ftp 4096 Oct 19 12:00 ..",
+            "-rw-r--r-- 1 ftp ftp 5 Oct 19 12:00 a.txt",
+            "10-19-26  01:30PM       <DIR>          .",
+            "some unknown format"
+        ];
+        let entries = parse_listing(&lines);
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["a.txt", "some unknown format"]);
+        assert_eq!(entries[1].kind, EntryKind::File);
+    }
+}
@@ -1,17 +1,52 @@
 pub mod ui;
 pub mod ftp;
 pub mod app;
+pub mod segmented;
+pub mod pool;
+pub mod fxp;
+pub mod proxy;
+pub mod transcript;
+pub mod throttle;
+pub mod date;
+pub mod listing;
+pub mod remote_fs;
+pub mod sftp;
+pub mod tls;
+pub mod url;
+pub mod args;
+pub mod netrc;
+pub mod sites;
+pub mod vault;
+pub mod certs;
+pub mod form;
+pub mod queue;
+pub mod preview;
 
-use app::{App, StatefulList};
-use std::{io, thread, time::Duration};
-use crossterm::event::{poll, read, Event, KeyCode};
+use app::{App, Focus, Pane, StatefulList};
+use std::{io, thread, time::{Duration, Instant}};
+use crossterm::event::{poll, read, Event, KeyCode, KeyEvent, KeyModifiers};
 use tui::{
     backend::Backend,
     backend::CrosstermBackend,
     Terminal
 };
-use std::fs::{self, File};
-use std::io::prelude::{Write};
+use std::env;
+use std::cmp;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use transcript::Sink;
+use proxy::Connector;
+use throttle::{Throttle, TokenBucket};
+use remote_fs::{Entry, EntryKind, FtpFs, LocalFs, RemoteFs};
+use sftp::{HostKeyPolicy, SftpFs};
+use url::{Protocol, TypeCode, Url};
+use sites::{Site, Sites, TransferRule, TransferType};
+use certs::KnownCertificates;
+use queue::{Queue, Transfer};
+use preview::Preview;
+use form::{Action, Login, LoginForm};
+use vault::Vault;
+use zeroize::Zeroizing;
 
 use crossterm::{
     event::{self, DisableMouseCapture, EnableMouseCapture},
@@ -19,105 +54,1151 @@ use crossterm::{
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 
+// Number of parallel connections used for segmented downloads
+const DOWNLOAD_SEGMENTS: usize = 4;
+// Connections kept open per server, including the ones used for browsing and by the transfer queue
+const MAX_CONNECTIONS: usize = DOWNLOAD_SEGMENTS + 2;
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+// Transcript lines kept for the transcript panel
+const TRANSCRIPT_LINES: usize = 1000;
+// How often the status line is updated during a transfer
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+// How long the selection has to stay on a file before the preview fetches it
+const PREVIEW_DELAY: Duration = Duration::from_millis(300);
+// Bandwidth limits the limit keys step through in bytes per second, 0 being unlimited
+const RATE_STEPS: [u64; 9] = [64 << 10, 128 << 10, 256 << 10, 512 << 10, 1 << 20, 2 << 20, 5 << 20, 10 << 20, 0];
+
+// How a session ended
+enum Ended {
+    Quit,
+    /// Connecting or logging in failed, so the login form comes back
+    LoginFailed(ftp::Error)
+}
+
+// The rate of RATE_STEPS after or before `rate`
+fn next_rate(rate: u64, faster: bool) -> u64 {
+    let current = RATE_STEPS.iter().position(|&r| r == rate).unwrap_or(RATE_STEPS.len() - 1);
+    let next = if faster { cmp::min(current + 1, RATE_STEPS.len() - 1) } else { current.saturating_sub(1) };
+    RATE_STEPS[next]
+}
+
+fn step_rate(bucket: &TokenBucket, faster: bool) {
+    bucket.set_rate(next_rate(bucket.rate(), faster));
+}
+
+fn rate_text(rate: u64) -> String {
+    if rate == 0 { "unlimited".to_string() } else { format!("{}/s", ui::human_size(rate)) }
+}
+
 impl App {
     pub fn new() -> io::Result<App> {
-        Ok(App { 
-            remote_list: StatefulList::with_items(Vec::new()), 
-            local_list: StatefulList::with_items(
-                fs::read_dir(home::home_dir().unwrap())?
-                .map(|res| res.map(|e| e.path().to_str().unwrap_or(" ").to_string()))
-                .collect::<Result<Vec<_>, io::Error>>()?
-            ),
-            local_path: home::home_dir().unwrap() 
+        let home = home::home_dir().or_else(|| env::current_dir().ok()).unwrap_or_else(|| PathBuf::from("/"));
+        let mut app = App {
+            remote: Pane::new(),
+            local: Pane::new(),
+            local_fs: LocalFs::new(home),
+            focus: Focus::Remote,
+            transcript: Arc::new(transcript::Memory::new(TRANSCRIPT_LINES)),
+            transcript_file: None,
+            show_transcript: false,
+            throttle: Arc::new(Throttle::unlimited()),
+            options: args::Options::default(),
+            sites: Sites::default(),
+            site_dialog: None,
+            site: Site::default(),
+            vault: None,
+            question: None,
+            login: None,
+            queue: match (queue::default_path(), queue::history_path()) {
+                (Some(path), Some(history)) => Queue::persistent(path, history),
+                _ => Queue::new()
+            },
+            transfers: StatefulList::with_items(Vec::new()),
+            show_preview: false,
+            preview: None
+        };
+        // An unreadable home directory only leaves the pane empty, the user can still go elsewhere
+        let _ = app.local.refresh(&mut app.local_fs);
+        Ok(app)
+    }
+    // Shows `path` in the local pane
+    fn set_local_path(&mut self, path: &Path) -> ftp::Result<()> {
+        self.local.cwd(&mut self.local_fs, &path.to_string_lossy())
+    }
+    // The file pane with the focus, or the remote one while the transfer panel has it
+    fn focused_pane_mut(&mut self) -> &mut Pane {
+        match self.focus {
+            Focus::Local => &mut self.local,
+            Focus::Remote | Focus::Queue => &mut self.remote
+        }
+    }
+    // Searches the focused pane as the text is typed in the status line. Enter stays at the match, Esc goes back
+    fn search<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> io::Result<()> {
+        let origin = self.focused_pane_mut().list.state.selected();
+        let mut text = String::new();
+        let mut found = true;
+        loop {
+            let status = format!("Search: {}{}", text, if found { "" } else { " (not found)" });
+            terminal.draw(|f| ui::draw_layout(f, self, status))?;
+            let code = match read()? {
+                Event::Key(key) => key.code,
+                _ => continue
+            };
+            let pane = self.focused_pane_mut();
+            match code {
+                KeyCode::Char(c) => text.push(c),
+                KeyCode::Backspace => {
+                    text.pop();
+                }
+                KeyCode::Enter => {
+                    pane.search = Some(text).filter(|text| !text.is_empty());
+                    return Ok(());
+                }
+                KeyCode::Esc => {
+                    pane.list.state.select(origin);
+                    return Ok(());
+                }
+                _ => continue
+            }
+            // Each time from where the search started, so the match only moves on when it no longer fits
+            pane.list.state.select(origin);
+            found = text.is_empty() || pane.find(&text, origin.unwrap_or(0), true);
+        }
+    }
+    // Asks for the filter of the focused pane, an empty one shows all entries
+    fn edit_filter<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> io::Result<()> {
+        let current = self.focused_pane_mut().filter.clone().unwrap_or_default();
+        if let Some(filter) = self.edit_line(terminal, "Filter (a pattern like *.txt, or letters in order, empty for all): ", &current, false)? {
+            self.focused_pane_mut().set_filter(Some(filter.to_string()));
+        }
+        Ok(())
+    }
+    // Asks for a pattern and marks the entries of the focused pane matching it, or unmarks them
+    fn mark_pattern<B: Backend>(&mut self, terminal: &mut Terminal<B>, mark: bool) -> io::Result<Option<String>> {
+        let label = if mark { "Select (a pattern like *.txt): " } else { "Deselect (a pattern like *.txt): " };
+        Ok(match self.edit_line(terminal, label, "*", false)? {
+            Some(pattern) if !pattern.is_empty() => {
+                let count = self.focused_pane_mut().mark_matching(&pattern, mark);
+                Some(format!("{} {} matching {}", if mark { "Selected" } else { "Deselected" }, count, *pattern))
+            }
+            _ => None
         })
     }
+    // What the status line shows when nothing else is going on
+    fn status_text(&self) -> String {
+        let files = |pane: &Pane| match pane.marked.len() {
+            0 => format!("{} files", pane.file_count()),
+            marked => format!("{} files, {} selected", pane.file_count(), marked)
+        };
+        match self.focus {
+            Focus::Remote => format!("{}, {}", files(&self.remote), self.limits_text()),
+            Focus::Local => format!("{}, {}", files(&self.local), self.limits_text()),
+            Focus::Queue => {
+                let done = self.transfers.items.iter().filter(|t| t.state == queue::State::Done).count();
+                format!("{} in the queue, {} done, {}", self.transfers.items.len(), done, self.limits_text())
+            }
+        }
+    }
+    // Shows the current state of the queue in the transfer panel, keeping the selection in range
+    fn update_transfers(&mut self) {
+        self.transfers.items = self.queue.items();
+        let selected = match self.transfers.items.len() {
+            0 => None,
+            len => Some(self.transfers.state.selected().unwrap_or(0).min(len - 1))
+        };
+        self.transfers.state.select(selected);
+        if selected.is_none() && self.focus == Focus::Queue {
+            self.focus = Focus::Remote;
+        }
+    }
+    // Handles the keys of the transfer panel. + and - change the limit of the selected transfer
+    fn handle_queue_key(&mut self, code: KeyCode) {
+        let selected = self.transfers.state.selected().and_then(|i| self.transfers.items.get(i));
+        let (id, limit) = (selected.map(|t| t.id), selected.map_or(0, |t| t.limit));
+        match (code, id) {
+            (KeyCode::Down, _) => self.transfers.next(),
+            (KeyCode::Up, _) => self.transfers.previous(),
+            (KeyCode::Char('p'), Some(id)) => self.queue.pause(id),
+            (KeyCode::Char('r'), Some(id)) => {
+                self.queue.resume(id);
+                self.queue.retry(id);
+            }
+            (KeyCode::Char('c') | KeyCode::Delete, Some(id)) => self.queue.cancel(id),
+            (KeyCode::Char(c @ ('+' | '-')), Some(id)) => self.queue.set_limit(id, next_rate(limit, c == '+')),
+            (KeyCode::Char(c @ ('u' | 'd')), Some(id)) => {
+                self.queue.move_item(id, c == 'u');
+                self.update_transfers();
+                if let Some(i) = self.transfers.items.iter().position(|t| t.id == id) {
+                    self.transfers.state.select(Some(i));
+                }
+            }
+            (code, _) => self.handle_limit_key(code)
+        }
+    }
+    // Adds a transfer of `entry` between the current directories of the panes to the queue
+    fn enqueue(&mut self, direction: queue::Direction, entry: &Entry, move_source: bool) {
+        let site = Some(self.site.name.as_str()).filter(|name| !name.is_empty());
+        let mut transfer = Transfer::new(direction, entry, self.local_fs.path().to_path_buf(), &self.remote.path, &self.queue.server(), site);
+        transfer.move_source = move_source;
+        self.queue.push(transfer);
+        self.update_transfers();
+    }
+    // Handles the keys of the local pane. Errors there are shown in the status line and do not end the session
+    fn handle_local_key(&mut self, event: KeyEvent) -> ftp::Result<()> {
+        match event.code {
+            KeyCode::Down => self.local.list.next(),
+            KeyCode::Up => self.local.list.previous(),
+            KeyCode::Enter => {
+                if let Some(entry) = self.local.selected().filter(|e| e.is_dir()).cloned() {
+                    self.local.cwd(&mut self.local_fs, &entry.name)?;
+                }
+            }
+            KeyCode::Backspace => self.local.cwd(&mut self.local_fs, app::PARENT)?,
+            KeyCode::Char('r') if event.modifiers.contains(KeyModifiers::CONTROL) => self.local.refresh(&mut self.local_fs)?,
+            code => {
+                if !self.local.handle_view_key(code) {
+                    self.handle_limit_key(code);
+                }
+            }
+        }
+        Ok(())
+    }
+    // Adjusts the bandwidth limits shared by all transfers: ]/[ for downloads, >/< for uploads
+    fn handle_limit_key(&self, code: KeyCode) {
+        match code {
+            KeyCode::Char(']') => step_rate(&self.throttle.download, true),
+            KeyCode::Char('[') => step_rate(&self.throttle.download, false),
+            KeyCode::Char('>') => step_rate(&self.throttle.upload, true),
+            KeyCode::Char('<') => step_rate(&self.throttle.upload, false),
+            _ => {}
+        }
+    }
+    // Handles the limit keys pressed while a transfer is running
+    fn poll_limit_keys(&self) -> io::Result<()> {
+        while poll(Duration::ZERO)? {
+            if let Event::Key(event) = read()? {
+                self.handle_limit_key(event.code);
+            }
+        }
+        Ok(())
+    }
+    fn limits_text(&self) -> String {
+        format!("download limit {}, upload limit {}", rate_text(self.throttle.download.rate()), rate_text(self.throttle.upload.rate()))
+    }
+    // The panel's transcript, and the transcript file if one was requested
+    fn transcript_sink(&self) -> Arc<dyn Sink> {
+        match &self.transcript_file {
+            Some(file) => Arc::new(transcript::Fanout(vec![self.transcript.clone(), file.clone()])),
+            None => self.transcript.clone()
+        }
+    }
     pub fn run<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> Result<(), ftp::Error> {
-        terminal.draw(|mut f| {
-            ui::draw_layout(&mut f, self, String::new());
+        terminal.draw(|f| {
+            ui::draw_layout(f, self, String::new());
         })?;
 
-        let mut res: Vec<String> = vec![];
-        for t in ["Server: ", "User: ", "Password: "] {
-            let mut text = String::new();
-            terminal.draw(|mut f| {
-                ui::draw_layout(&mut f, self, t.to_string() + &text);
-            })?;
-            loop {
-                if let Event::Key(key) = event::read()? {
-                    match key.code {
-                        KeyCode::Char(c) => {
-                            text.push(c);
-                            terminal.draw(|mut f| {
-                                ui::draw_layout(&mut f, self, t.to_string() + &text);
-                            })?;                
-                        }
-                        KeyCode::Backspace => {
-                            text.pop();
-                            terminal.draw(|mut f| {
-                                ui::draw_layout(&mut f, self, t.to_string() + &text);
-                            })?;
-                        }
-                        KeyCode::Enter => break,
-                        _ => {}
+        // Transfers left unfinished last time go on with their server, unless the command line names one
+        let (mut server, mut site) = match self.resume_transfers(terminal)? {
+            Some(transfer) if self.options.server().is_none() && self.options.site.is_none() => self.transfer_server(&transfer),
+            _ => self.choose_server(terminal)?
+        };
+        // A ";type=a" URL transfers everything as text
+        if server.type_code == Some(TypeCode::Ascii) {
+            site.transfer_types.push(TransferRule { pattern: "*".to_string(), transfer_type: TransferType::Ascii });
+        }
+        site.active |= self.options.active;
+        let mut password = server.password.take().map(Zeroizing::new);
+        let mut error = None;
+        // Until the user quits: what is not known of the login is asked in the login form,
+        // which also comes back when logging in fails
+        loop {
+            let (entry, netrc_warning) = netrc_entry(&server.host, server.user.as_deref());
+            let entry = entry.unwrap_or_default();
+            let user = server.user.clone().or(entry.login);
+            if password.is_none() {
+                password = match entry.password {
+                    Some(password) => Some(Zeroizing::new(password)),
+                    None if error.is_none() => self.vault_password(terminal, &site)?,
+                    None => None
+                };
+            }
+            if error.is_some() || server.host.is_empty() || user.is_none() || password.is_none() {
+                let form = LoginForm::new(&server, user.as_deref(), password.as_ref().map(|p| p.as_str()), site.active, error.take());
+                match self.login_form(terminal, form)? {
+                    Some(login) => {
+                        server = login.url;
+                        password = Some(login.password);
+                        site.active = login.active;
+                        continue;
                     }
+                    None => return Ok(())
                 }
             }
-            res.push(text);
+            let (user, password) = (user.unwrap_or_default(), password.clone().unwrap_or_default());
+            terminal.draw(|f| ui::draw_layout(f, self, format!("Connecting to {}", server.address())))?;
+            match self.session(terminal, &server, site.clone(), &user, &password, entry.account, netrc_warning)? {
+                Ended::Quit => return Ok(()),
+                Ended::LoginFailed(e) => error = Some(e.to_string())
+            }
+        }
+    }
+    // Logs in and lets the user work with the server until they quit
+    #[allow(clippy::too_many_arguments)]
+    fn session<B: Backend>(&mut self, terminal: &mut Terminal<B>, server: &Url, site: Site, user: &str, password: &str, account: Option<String>, netrc_warning: Option<String>) -> ftp::Result<Ended> {
+        let mut address = server.address();
+        let connection_type = if site.active { ftp::ConnectionType::Active } else { ftp::ConnectionType::Passive };
+        let connector = site.connector();
+        let mut pool = pool::Pool::with_connector(MAX_CONNECTIONS, IDLE_TIMEOUT, connector.clone());
+        // Through an FTP proxy the control connections go to the proxy, which is told the server's address
+        if let Some(mut proxy) = site.ftp_proxy.clone().filter(|_| server.protocol == Protocol::Ftp) {
+            if proxy.password.is_none() && proxy.scheme.logs_in() {
+                let label = format!("Password for the FTP proxy {}: ", proxy.address);
+                proxy.password = self.edit_line(terminal, &label, "", true)?.map(|password| password.to_string());
+            }
+            pool.set_proxy_login(proxy.login(&server.proxy_target()));
+            address = proxy.address;
         }
-        let mut ftp = ftp::Connection::new((res[0].clone().trim_end().to_string() + ":21").as_str(), ftp::ConnectionType::Passive)?;
-        ftp.login(res[1].as_str().trim_end(), res[2].as_str().trim_end())?;
+        pool.set_transcript(self.transcript_sink());
+        pool.set_throttle(self.throttle.clone());
+        pool.set_encoding(site.encoding);
+        let known = Arc::new(RwLock::new(load_certificates()?));
+        if let Some(mode) = server.tls {
+            pool.set_tls(tls::Tls::new(mode, &server.host, &server.address(), known.clone())?);
+        }
+        if let Some(account) = account {
+            pool.set_account(account);
+        }
+        let connect = || pool.get(address.as_str(), user, password, connection_type);
+        let remote: ftp::Result<Box<dyn RemoteFs + '_>> = match server.protocol {
+            Protocol::Sftp => self.connect_sftp(terminal, connector.as_ref(), &server.host, server.port_or_default(), user, password).map(|fs| Box::new(fs) as Box<dyn RemoteFs>),
+            Protocol::Ftp => self.connect_ftp(terminal, &known, connect).and_then(FtpFs::new).map(|fs| Box::new(fs) as Box<dyn RemoteFs>)
+        };
+        // Listing the directory is part of logging in, as it fails when the data connections do not get through
+        let mut remote = match remote.and_then(|mut remote| self.remote.refresh(remote.as_mut()).map(|_| remote)) {
+            Ok(remote) => remote,
+            Err(e) => return Ok(Ended::LoginFailed(e))
+        };
+        let local_dir = site.local_dir.clone();
+        self.site = site;
 
-        self.remote_list = StatefulList::with_items(ftp.get_directory_listing()?);
-        let len = self.remote_items().len();
+        let (mut message, selection) = match &server.path {
+            Some(path) => match open_path(remote.as_mut(), path, server.type_code) {
+                Ok(name) => (netrc_warning, name),
+                Err(e) => (Some(e.to_string()), None)
+            },
+            None => (netrc_warning, None)
+        };
+        if let Some(dir) = local_dir {
+            if let Err(e) = self.set_local_path(&dir) {
+                message = Some(e.to_string());
+            }
+        }
+        if let Some(name) = selection {
+            self.remote.select_name(&name);
+        }
+
+        // The queue works over a connection of its own, so browsing goes on during transfers
+        let site = self.site.clone();
+        let auths = sftp::default_auths(password);
+        let open = || match server.protocol {
+            Protocol::Sftp => {
+                let known_hosts = sftp::default_known_hosts().ok_or(ftp::Error::InvalidData)?;
+                let fs = SftpFs::connect(connector.as_ref(), &server.host, server.port_or_default(), user, &auths, &known_hosts, &HostKeyPolicy::Strict)?;
+                Ok(Box::new(fs) as Box<dyn RemoteFs>)
+            }
+            Protocol::Ftp => FtpFs::new(connect()?).map(|fs| Box::new(fs) as Box<dyn RemoteFs>)
+        };
+        let queue = self.queue.clone();
+        queue.reopen(&Url { user: Some(user.to_string()), ..server.clone() }.server_url());
+        thread::scope(|scope| {
+            scope.spawn(|| queue::work(&queue, open, |name| site.transfer_type(name) == TransferType::Ascii));
+            let res = self.browse(terminal, remote.as_mut(), server, &pool, connect, message);
+            // Transfers left unfinished go on after logging in again
+            queue.close();
+            res
+        })
+    }
+    // Lets the user work with the server until they quit
+    fn browse<'p, B: Backend, C>(&mut self, terminal: &mut Terminal<B>, remote: &mut dyn RemoteFs, server: &Url, pool: &pool::Pool, connect: C, mut message: Option<String>) -> ftp::Result<Ended>
+    where
+        C: Fn() -> ftp::Result<pool::PooledConnection<'p>> + Sync + Copy
+    {
+        let mut finished = self.queue.finished();
+        let mut moved = (None, Instant::now());
         loop {
-            terminal.draw(|mut f| {
-                ui::draw_layout(&mut f, self, format!("{} files", len));
+            self.update_transfers();
+            self.update_preview(remote, &mut moved);
+            if let Some(e) = self.queue.take_error() {
+                message = Some(e);
+            }
+            // The panes show what finished transfers changed
+            if self.queue.finished() != finished {
+                finished = self.queue.finished();
+                self.refresh_local();
+                if let Err(e) = self.remote.refresh(remote) {
+                    message = Some(e.to_string());
+                }
+            }
+            let status = message.clone().unwrap_or_else(|| self.status_text());
+            terminal.draw(|f| {
+                ui::draw_layout(f, self, status);
             })?;
             if poll(Duration::from_millis(200))? {
-                match read()? {
-                    Event::Key(event) => {
-                        match event.code {
-                            KeyCode::Down => self.remote_list.next(),
-                            KeyCode::Up => self.remote_list.previous(),
-                            KeyCode::Enter => { 
-                                let filename = &self.remote_items()[self.remote_list.state.selected().unwrap_or(0)];
-                                self.local_path.push(filename);
-                                let mut file = File::create(&self.local_path)?;
-                                terminal.draw(|mut f| {
-                                    ui::draw_layout(&mut f, self, format!("Receiving file {}", &self.local_path.to_str().unwrap_or("Unknown file")));
-                                })?;
-                                let data = ftp.receive_file(filename)?;
-                                file.write_all(&data).map_err(|e| ftp::Error::from(e))?;
-                                self.local_path = home::home_dir().unwrap();
-                             }
-                            KeyCode::Esc => break,
-                            _ => {}
+                if let Event::Key(event) = read()? {
+                    message = None;
+                    let mut result = Ok(());
+                    match event.code {
+                        KeyCode::Tab => {
+                            // The local pane is left out while the preview takes its place
+                            self.focus = match self.focus {
+                                Focus::Remote if !self.show_preview => Focus::Local,
+                                Focus::Remote | Focus::Local if !self.transfers.items.is_empty() => Focus::Queue,
+                                _ => Focus::Remote
+                            };
+                        }
+                        KeyCode::Char('t') => self.show_transcript = !self.show_transcript,
+                        KeyCode::Char('v') => {
+                            self.show_preview = !self.show_preview;
+                            if self.show_preview && self.focus == Focus::Local {
+                                self.focus = Focus::Remote;
+                            }
+                        }
+                        KeyCode::PageDown | KeyCode::PageUp if self.preview.is_some() => {
+                            if let Some(preview) = &mut self.preview {
+                                let page = preview.height.max(1) as isize;
+                                preview.scroll_by(if event.code == KeyCode::PageDown { page } else { -page });
+                            }
+                        }
+                        KeyCode::Esc => return Ok(Ended::Quit),
+                        _ if self.focus == Focus::Queue => self.handle_queue_key(event.code),
+                        KeyCode::Char('/') => self.search(terminal)?,
+                        KeyCode::Char('f') => self.edit_filter(terminal)?,
+                        KeyCode::Char(c @ ('+' | '-')) => message = self.mark_pattern(terminal, c == '+')?,
+                        KeyCode::F(5..=8) | KeyCode::Char('m') => {
+                            // A failed operation is reported, the session goes on
+                            message = self.file_operation(terminal, remote, event).unwrap_or_else(|e| Some(e.to_string()));
+                        }
+                        _ if self.focus == Focus::Local => {
+                            if let Err(e) = self.handle_local_key(event) {
+                                message = Some(e.to_string());
+                            }
+                        }
+                        KeyCode::Char('r') if event.modifiers.contains(KeyModifiers::CONTROL) => result = self.remote.refresh(remote),
+                        KeyCode::Down => self.remote.list.next(),
+                        KeyCode::Up => self.remote.list.previous(),
+                        KeyCode::Enter => {
+                            if let Some(entry) = self.remote.selected().cloned() {
+                                result = match entry.kind {
+                                    EntryKind::Directory => self.remote.cwd(remote, &entry.name),
+                                    // A link may point to a directory or to a file
+                                    EntryKind::Symlink => self.remote.cwd(remote, &entry.name).or_else(|_| {
+                                        self.enqueue(queue::Direction::Download, &entry, false);
+                                        Ok(())
+                                    }),
+                                    EntryKind::File => {
+                                        self.enqueue(queue::Direction::Download, &entry, false);
+                                        Ok(())
+                                    }
+                                };
+                            }
+                        }
+                        KeyCode::Backspace => result = self.remote.cwd(remote, app::PARENT),
+                        KeyCode::Char('s') => {
+                            // Parallel connections are only available over FTP, and only for binary transfers
+                            // which the segments can resume at any offset
+                            if let Some(entry) = self.remote.selected_file().cloned() {
+                                let ascii = self.site.transfer_type(&entry.name) == TransferType::Ascii;
+                                result = match server.protocol {
+                                    Protocol::Ftp if !ascii => self.download_segmented(terminal, remote, connect, &entry.name),
+                                    _ => self.download(terminal, remote, &entry.name)
+                                };
+                            }
+                        }
+                        code => {
+                            if !self.remote.handle_view_key(code) {
+                                self.handle_limit_key(code);
+                            }
                         }
                     }
-                    _ => {}
+                    match result {
+                        // The server refused the operation, but the session can go on
+                        Err(e) if e.context().is_some() => message = Some(e.to_string()),
+                        res => res?
+                    }
                 }
             } else {
                 // Timeout expired and no `Event` is available
+                pool.evict_idle();
             }
         }
-
+    }
+    // Shows the login form until it is submitted, None if it is cancelled
+    fn login_form<B: Backend>(&mut self, terminal: &mut Terminal<B>, form: LoginForm) -> io::Result<Option<Login>> {
+        self.login = Some(form);
+        let res = loop {
+            terminal.draw(|f| ui::draw_layout(f, self, String::new()))?;
+            let (form, key) = match (&mut self.login, event::read()?) {
+                (Some(form), Event::Key(key)) => (form, key),
+                (None, _) => break None,
+                _ => continue
+            };
+            match form.handle_key(key) {
+                Some(Action::Submit) => {
+                    if let Some(login) = form.submit() {
+                        break Some(login);
+                    }
+                }
+                Some(Action::Cancel) => break None,
+                None => {}
+            }
+        };
+        self.login = None;
+        Ok(res)
+    }
+    // Asks a yes/no question in a dialog box
+    fn confirm<B: Backend>(&mut self, terminal: &mut Terminal<B>, question: &str) -> io::Result<bool> {
+        self.question = Some(question.to_string());
+        let answer = loop {
+            terminal.draw(|f| ui::draw_layout(f, self, String::new()))?;
+            if let Event::Key(key) = read()? {
+                match key.code {
+                    KeyCode::Char('y' | 'Y') => break true,
+                    KeyCode::Char('n' | 'N') | KeyCode::Esc => break false,
+                    _ => {}
+                }
+            }
+        };
+        self.question = None;
+        Ok(answer)
+    }
+    // Reads a line of input in the status line
+    fn prompt<B: Backend>(&mut self, terminal: &mut Terminal<B>, label: &str) -> io::Result<String> {
+        loop {
+            if let Some(text) = self.edit_line(terminal, label, "", false)? {
+                return Ok(text.to_string());
+            }
+        }
+    }
+    // Edits `text` in the status line, None if Esc cancelled it. A `secret` is shown as asterisks
+    fn edit_line<B: Backend>(&mut self, terminal: &mut Terminal<B>, label: &str, initial: &str, secret: bool) -> io::Result<Option<Zeroizing<String>>> {
+        // Reserved up front, so typing a password does not leave unwiped copies in reallocated buffers
+        let mut text = Zeroizing::new(String::with_capacity(initial.len().max(256)));
+        text.push_str(initial);
+        loop {
+            let shown = if secret { "*".repeat(text.chars().count()) } else { text.to_string() };
+            terminal.draw(|f| {
+                ui::draw_layout(f, self, label.to_string() + &shown);
+            })?;
+            if let Event::Key(key) = event::read()? {
+                match key.code {
+                    KeyCode::Char(c) => text.push(c),
+                    KeyCode::Backspace => {
+                        text.pop();
+                    }
+                    KeyCode::Enter => return Ok(Some(text)),
+                    KeyCode::Esc => return Ok(None),
+                    _ => {}
+                }
+            }
+        }
+    }
+    // Offers to go on with the transfers left unfinished last time, which are dropped otherwise.
+    // Returns the first one if they are restored
+    fn resume_transfers<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> io::Result<Option<Transfer>> {
+        let path = match queue::default_path() {
+            Some(path) => path,
+            None => return Ok(None)
+        };
+        let saved = queue::load(&path)?;
+        let first = match saved.first() {
+            Some(transfer) => transfer.clone(),
+            None => return Ok(None)
+        };
+        let others = if saved.iter().any(|t| t.server != first.server) { " and other servers" } else { "" };
+        let noun = if saved.len() == 1 { "transfer" } else { "transfers" };
+        if self.confirm(terminal, &format!("Resume {} unfinished {} with {}{}?", saved.len(), noun, first.server, others))? {
+            self.queue.restore(saved);
+            return Ok(Some(first));
+        }
+        if let Some(history) = queue::history_path() {
+            for transfer in &saved {
+                queue::record(&history, transfer, "dropped")?;
+            }
+        }
+        queue::save(&path, &[])?;
+        Ok(None)
+    }
+    // The server and settings a restored transfer was queued with
+    fn transfer_server(&self, transfer: &Transfer) -> (Url, Site) {
+        match transfer.site.as_deref().and_then(|name| self.sites.find(name)) {
+            Some(site) => (self.options.apply(site.url()), site.clone()),
+            None => (self.options.apply(Url::parse(&transfer.server).unwrap_or_default()), Site::default())
+        }
+    }
+    // The server to connect to and the settings to use: a site or URL from the command line,
+    // or the one picked in the site manager
+    fn choose_server<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> io::Result<(Url, Site)> {
+        let site = match &self.options.site {
+            Some(name) => self.sites.find(name).cloned(),
+            None => match self.options.server() {
+                Some(server) => return Ok((server, Site::default())),
+                None => self.site_manager(terminal)?
+            }
+        };
+        match site {
+            Some(site) => Ok((self.options.apply(site.url()), site)),
+            None => Ok((self.options.apply(Url::default()), Site::default()))
+        }
+    }
+    // Shows the saved sites until one is picked, None for connecting to a server typed in
+    fn site_manager<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> io::Result<Option<Site>> {
+        let names = |sites: &Sites| sites.sites.iter().map(|site| site.name.clone()).collect();
+        let mut dialog = StatefulList::with_items(names(&self.sites));
+        dialog.next();
+        self.site_dialog = Some(dialog);
+        let mut message: Option<String> = None;
+        loop {
+            let status = message.take().unwrap_or_default();
+            terminal.draw(|f| {
+                ui::draw_layout(f, self, status);
+            })?;
+            let code = match event::read()? {
+                Event::Key(key) => key.code,
+                _ => continue
+            };
+            let selected = self.site_dialog.as_ref().and_then(|dialog| dialog.state.selected()).filter(|&i| i < self.sites.sites.len());
+            let mut changed = false;
+            match (code, selected) {
+                (KeyCode::Down, _) => self.site_dialog.iter_mut().for_each(StatefulList::next),
+                (KeyCode::Up, _) => self.site_dialog.iter_mut().for_each(StatefulList::previous),
+                (KeyCode::Enter, Some(i)) => {
+                    self.site_dialog = None;
+                    return Ok(Some(self.sites.sites[i].clone()));
+                }
+                (KeyCode::Enter | KeyCode::Esc, _) => {
+                    self.site_dialog = None;
+                    return Ok(None);
+                }
+                (KeyCode::Char('n'), _) => {
+                    if let Some(site) = self.edit_site(terminal, Site::default(), None)? {
+                        self.sites.sites.push(site);
+                        let last = self.sites.sites.len() - 1;
+                        self.site_dialog.iter_mut().for_each(|dialog| dialog.state.select(Some(last)));
+                        changed = true;
+                    }
+                }
+                (KeyCode::Char('e'), Some(i)) => {
+                    if let Some(site) = self.edit_site(terminal, self.sites.sites[i].clone(), Some(i))? {
+                        self.sites.sites[i] = site;
+                        changed = true;
+                    }
+                }
+                (KeyCode::Char('d'), Some(i)) => {
+                    let answer = self.prompt(terminal, &format!("Delete site \"{}\"? (y/n): ", self.sites.sites[i].name))?;
+                    if answer.trim().eq_ignore_ascii_case("y") {
+                        let site = self.sites.sites.remove(i);
+                        // The password goes too if the vault is open, otherwise it stays behind unused
+                        if let (Some(entry), Some(vault)) = (&site.vault_entry, &mut self.vault) {
+                            if vault.remove(entry) {
+                                vault.save()?;
+                            }
+                        }
+                        changed = true;
+                    }
+                }
+                _ => {}
+            }
+            if changed {
+                if let Some(dialog) = &mut self.site_dialog {
+                    dialog.items = names(&self.sites);
+                    let selected = match dialog.items.len() {
+                        0 => None,
+                        len => Some(dialog.state.selected().unwrap_or(0).min(len - 1))
+                    };
+                    dialog.state.select(selected);
+                }
+                if let Err(e) = sites::default_path().map_or(Ok(()), |path| sites::save(&path, &self.sites)) {
+                    message = Some(format!("Could not save the sites: {}", e));
+                }
+            }
+        }
+    }
+    // Asks for each field of `site` in turn, None if Esc cancelled the form. `index` is the site's position if it is saved already
+    fn edit_site<B: Backend>(&mut self, terminal: &mut Terminal<B>, mut site: Site, index: Option<usize>) -> io::Result<Option<Site>> {
+        // The password is only asked for where it can be saved
+        let fields = if self.sites.allow_plaintext_passwords { sites::FIELDS.len() } else { sites::FIELDS.len() - 1 };
+        let mut error: Option<String> = None;
+        let mut i = 0;
+        while i < fields {
+            let label = match error.take() {
+                Some(e) => format!("{}. {}: ", e, sites::FIELDS[i]),
+                None => format!("{}: ", sites::FIELDS[i])
+            };
+            let text = match self.edit_line(terminal, &label, &site.field(i), i == sites::FIELDS.len() - 1)? {
+                Some(text) => text,
+                None => return Ok(None)
+            };
+            match site.set_field(i, &text) {
+                Ok(()) if i == 0 && self.sites.sites.iter().enumerate().any(|(j, s)| s.name == site.name && Some(j) != index) =>
+                    error = Some(format!("There is a site named \"{}\" already", site.name)),
+                Ok(()) => i += 1,
+                Err(e) => error = Some(e)
+            }
+        }
+        if !self.sites.allow_plaintext_passwords {
+            let password = match self.edit_line(terminal, "Password (saved in the encrypted vault, empty keeps the current one): ", "", true)? {
+                Some(password) => password,
+                None => return Ok(None)
+            };
+            if !password.is_empty() && self.unlock_vault(terminal)? {
+                let entry = site.vault_entry.clone().unwrap_or_else(|| site.name.clone());
+                if let Some(vault) = &mut self.vault {
+                    vault.insert(&entry, password);
+                    vault.save()?;
+                }
+                site.vault_entry = Some(entry);
+            }
+        }
+        Ok(Some(site))
+    }
+    // Asks for the master password the first time in a session, or for a new one if there is no vault yet.
+    // False if that was cancelled
+    fn unlock_vault<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> io::Result<bool> {
+        if self.vault.is_some() {
+            return Ok(true);
+        }
+        let path = vault::default_path().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No home directory for the vault"))?;
+        let exists = path.exists();
+        let mut label = if exists { "Master password: " } else { "New master password for the vault: " }.to_string();
+        loop {
+            let passphrase = match self.edit_line(terminal, &label, "", true)? {
+                Some(passphrase) => passphrase,
+                None => return Ok(false)
+            };
+            if !exists {
+                match self.edit_line(terminal, "Repeat the master password: ", "", true)? {
+                    Some(repeated) if repeated == passphrase => {}
+                    Some(_) => {
+                        label = "The passwords differ. New master password for the vault: ".to_string();
+                        continue;
+                    }
+                    None => return Ok(false)
+                }
+            }
+            terminal.draw(|f| {
+                ui::draw_layout(f, self, "Unlocking the vault".to_string());
+            })?;
+            let vault = if exists { Vault::open(&path, &passphrase) } else { Vault::create(&path, &passphrase) };
+            match vault {
+                Ok(vault) => {
+                    self.vault = Some(vault);
+                    return Ok(true);
+                }
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => label = format!("{}. Master password: ", e),
+                Err(e) => return Err(e)
+            }
+        }
+    }
+    // The password `site` keeps in the vault, unlocking it if needed
+    fn vault_password<B: Backend>(&mut self, terminal: &mut Terminal<B>, site: &Site) -> io::Result<Option<Zeroizing<String>>> {
+        let entry = match &site.vault_entry {
+            Some(entry) => entry,
+            None => return Ok(None)
+        };
+        if !self.unlock_vault(terminal)? {
+            return Ok(None);
+        }
+        Ok(self.vault.as_ref().and_then(|vault| vault.get(entry)).map(|password| Zeroizing::new(password.to_string())))
+    }
+    // Connects over SFTP, asking before trusting a host key seen for the first time
+    fn connect_sftp<B: Backend>(&mut self, terminal: &mut Terminal<B>, connector: &dyn Connector, host: &str, port: u16, user: &str, password: &str) -> ftp::Result<SftpFs> {
+        let known_hosts = sftp::default_known_hosts().ok_or(ftp::Error::InvalidData)?;
+        let auths = sftp::default_auths(password);
+        match SftpFs::connect(connector, host, port, user, &auths, &known_hosts, &HostKeyPolicy::Strict) {
+            Err(ftp::Error::UnknownHostKey { host: name, fingerprint }) => {
+                let answer = self.prompt(terminal, &format!("Host key of {} is unknown, fingerprint {}. Trust it? (y/n): ", name, fingerprint))?;
+                if !answer.trim().eq_ignore_ascii_case("y") {
+                    return Err(ftp::Error::UnknownHostKey { host: name, fingerprint });
+                }
+                SftpFs::connect(connector, host, port, user, &auths, &known_hosts, &HostKeyPolicy::AcceptFingerprint(fingerprint))
+            }
+            res => res
+        }
+    }
+    // The first connection to an FTP server, asking whether to trust a certificate that is neither pinned nor verified
+    fn connect_ftp<'p, B: Backend, C>(&mut self, terminal: &mut Terminal<B>, known: &RwLock<KnownCertificates>, connect: C) -> ftp::Result<pool::PooledConnection<'p>>
+    where
+        C: Fn() -> ftp::Result<pool::PooledConnection<'p>>
+    {
+        match connect() {
+            Err(ftp::Error::UnknownCertificate { server, certificate }) => {
+                let answer = self.prompt(terminal, &format!("Unknown certificate for {}: {}. Trust it? (y/n): ", server, certificate))?;
+                if !answer.trim().eq_ignore_ascii_case("y") {
+                    return Err(ftp::Error::UnknownCertificate { server, certificate });
+                }
+                {
+                    let mut known = known.write().map_err(|_| ftp::Error::InvalidData)?;
+                    known.pin(&server, &certificate.fingerprint);
+                    if let Some(path) = certs::default_path() {
+                        certs::save(&path, &known)?;
+                    }
+                }
+                connect()
+            }
+            res => res
+        }
+    }
+    fn download<B: Backend>(&mut self, terminal: &mut Terminal<B>, remote: &mut dyn RemoteFs, filename: &str) -> ftp::Result<()> {
+        remote.set_ascii(self.site.transfer_type(filename) == TransferType::Ascii)?;
+        let mut local = self.local_fs.clone();
+        let path = local.path().join(filename).to_str().unwrap_or("Unknown file").to_string();
+        terminal.draw(|f| {
+            ui::draw_layout(f, self, format!("Receiving file {}", path));
+        })?;
+        let start = Instant::now();
+        let mut last_update = start;
+        remote_fs::copy(remote, filename, &mut local, filename, |received| {
+            if last_update.elapsed() >= PROGRESS_INTERVAL {
+                last_update = Instant::now();
+                self.poll_limit_keys()?;
+                let rate = (received * 1000).checked_div(start.elapsed().as_millis() as u64).unwrap_or(0);
+                let status = format!("Receiving file {}: {} at {}/s, {}", path, ui::human_size(received), ui::human_size(rate), self.limits_text());
+                terminal.draw(|f| ui::draw_layout(f, self, status))?;
+            }
+            Ok(())
+        })?;
+        self.refresh_local();
+        Ok(())
+    }
+    fn download_segmented<'p, B: Backend, C>(&mut self, terminal: &mut Terminal<B>, remote: &mut dyn RemoteFs, connect: C, filename: &str) -> ftp::Result<()>
+    where
+        C: Fn() -> ftp::Result<pool::PooledConnection<'p>> + Sync
+    {
+        let path = self.local_fs.path().join(filename);
+        let size = remote.stat(filename)?.size.ok_or(ftp::Error::InvalidData)?;
+        // The segments run on pooled connections, which are in whatever directory they were left in
+        let remote_path = self.remote.path_of(filename);
+        segmented::download(connect, &remote_path, size, &path, DOWNLOAD_SEGMENTS, |progress| {
+            let _ = self.poll_limit_keys();
+            let status = format!("Receiving file {}: {}% of {} at {}/s over {} connections, {}",
+                path.to_str().unwrap_or("Unknown file"),
+                progress.percent(),
+                ui::human_size(progress.total),
+                ui::human_size(progress.rate()),
+                progress.connections,
+                self.limits_text());
+            let _ = terminal.draw(|f| ui::draw_layout(f, self, status));
+        })?;
+        self.refresh_local();
         Ok(())
     }
-    pub fn remote_items(&self) -> Vec<String> {
-        self.remote_list.items.clone()
+    // The commander keys, acting on the marked entries of the focused pane or else on the selected one: F5 copies them
+    // to the other pane, F6 moves them there, F8 deletes them and 'm' changes their permissions. F7 makes a directory
+    // and Shift+F6 renames the selected entry. Returns the outcome to show in the status line
+    fn file_operation<B: Backend>(&mut self, terminal: &mut Terminal<B>, remote: &mut dyn RemoteFs, event: KeyEvent) -> ftp::Result<Option<String>> {
+        let mut local = self.local_fs.clone();
+        let upload = self.focus == Focus::Local;
+        let (pane, other_path) = if upload { (&self.local, &self.remote.path) } else { (&self.remote, &self.local.path) };
+        let (selected, targets, other_path) = (pane.selected_file().cloned(), pane.targets(), other_path.clone());
+        let fs: &mut dyn RemoteFs = if upload { &mut local } else { &mut *remote };
+        let shift = event.modifiers.contains(KeyModifiers::SHIFT);
+        let mut select = None;
+        // The entries that stay marked afterwards, those the operation failed for
+        let mut failed = Vec::new();
+        let outcome = match (event.code, selected) {
+            (KeyCode::F(6), Some(entry)) if shift => {
+                match self.edit_line(terminal, "Rename to: ", &entry.name, false)? {
+                    Some(name) if !name.is_empty() && *name != entry.name => {
+                        fs.rename(&entry.name, &name)?;
+                        select = Some(name.to_string());
+                        Some(format!("Renamed {} to {}", entry.name, *name))
+                    }
+                    _ => None
+                }
+            }
+            (KeyCode::F(code @ (5 | 6)), _) if !targets.is_empty() => {
+                let verb = if code == 6 { "Move" } else { "Copy" };
+                if !self.confirm(terminal, &format!("{} {} to {}?", verb, ui::entries_text(&targets), other_path))? {
+                    return Ok(None);
+                }
+                let direction = if upload { queue::Direction::Upload } else { queue::Direction::Download };
+                for entry in &targets {
+                    self.enqueue(direction, entry, code == 6);
+                }
+                Some(format!("Queued: {} {} to {}", verb.to_lowercase(), ui::entries_text(&targets), other_path))
+            }
+            (KeyCode::F(7), _) => {
+                match self.edit_line(terminal, "New directory: ", "", false)? {
+                    Some(name) if !name.is_empty() => {
+                        fs.mkdir(&name)?;
+                        select = Some(name.to_string());
+                        Some(format!("Made directory {}", *name))
+                    }
+                    _ => None
+                }
+            }
+            (KeyCode::F(8), _) if !targets.is_empty() => {
+                let question = match targets.iter().any(|e| e.is_dir()) {
+                    true => format!("Delete {} and everything in the directories?", ui::entries_text(&targets)),
+                    false => format!("Delete {}?", ui::entries_text(&targets))
+                };
+                if !self.confirm(terminal, &question)? {
+                    return Ok(None);
+                }
+                let (mut done, mut count) = (Vec::new(), 0);
+                for entry in targets.iter().cloned() {
+                    match remote_fs::delete_tree(fs, &entry) {
+                        Ok(files) => {
+                            count += files;
+                            done.push(entry);
+                        }
+                        Err(e) if targets.len() == 1 => return Err(e),
+                        Err(e) => failed.push((entry.name, e))
+                    }
+                }
+                Some(batch_outcome(format!("Deleted {} ({} files)", ui::entries_text(&done), count), &failed))
+            }
+            (KeyCode::Char('m'), _) if !targets.is_empty() => {
+                // The current permissions when they are the same for all entries
+                let modes: Vec<Option<u32>> = targets.iter().map(|e| e.permissions.as_deref().and_then(remote_fs::parse_mode)).collect();
+                let current = match modes[0] {
+                    Some(mode) if modes.iter().all(|&m| m == Some(mode)) => format!("{:o}", mode),
+                    _ => String::new()
+                };
+                let label = format!("Permissions of {} (octal, like 644): ", ui::entries_text(&targets));
+                match self.edit_line(terminal, &label, &current, false)? {
+                    Some(text) if !text.is_empty() => match u32::from_str_radix(&text, 8) {
+                        Ok(mode) if mode <= 0o7777 => {
+                            let mut done = Vec::new();
+                            for entry in targets.iter().cloned() {
+                                match fs.chmod(&entry.name, mode) {
+                                    Ok(()) => done.push(entry),
+                                    Err(e) if targets.len() == 1 => return Err(e),
+                                    Err(e) => failed.push((entry.name, e))
+                                }
+                            }
+                            Some(batch_outcome(format!("Changed the permissions of {} to {:o}", ui::entries_text(&done), mode), &failed))
+                        }
+                        _ => return Ok(Some(format!("Not an octal mode: {}", *text)))
+                    },
+                    _ => None
+                }
+            }
+            _ => None
+        };
+        self.refresh_local();
+        self.remote.refresh(remote)?;
+        let pane = if upload { &mut self.local } else { &mut self.remote };
+        if let Some(name) = select {
+            pane.select_name(&name);
+        }
+        if outcome.is_some() && !targets.is_empty() {
+            pane.marked = failed.into_iter().map(|(name, _)| name).collect();
+        }
+        Ok(outcome)
+    }
+    // Keeps the preview on the file selected in the remote pane once the selection has stayed there for PREVIEW_DELAY,
+    // so moving through the listing does not fetch every file on the way. `moved` is the selection and when it changed
+    // A preview that cannot be fetched shows why instead of ending the session
+    fn update_preview(&mut self, remote: &mut dyn RemoteFs, moved: &mut (Option<(String, String)>, Instant)) {
+        if !self.show_preview {
+            self.preview = None;
+            return;
+        }
+        let selected = self.remote.selected_file().filter(|e| !e.is_dir()).cloned();
+        let key = selected.as_ref().map(|e| (self.remote.path.clone(), e.name.clone()));
+        if key != moved.0 {
+            *moved = (key, Instant::now());
+        }
+        let shown = selected.as_ref().is_some_and(|e| self.preview.as_ref().is_some_and(|p| p.shows(&self.remote.path, &e.name)));
+        match selected {
+            Some(_) if shown => {
+                if !self.preview.as_ref().is_some_and(|p| p.wants_more()) {
+                    return;
+                }
+            }
+            Some(entry) if moved.1.elapsed() >= PREVIEW_DELAY => self.preview = Some(Preview::new(&self.remote.path, &entry)),
+            Some(_) => return,
+            None => {
+                self.preview = None;
+                return;
+            }
+        }
+        // The next page, the first one of a new preview
+        if let Some(preview) = &mut self.preview {
+            if let Err(e) = preview.fetch(remote) {
+                preview.error = Some(e.to_string());
+            }
+        }
     }
-    pub fn local_items(&self) -> Vec<String> {
-        self.remote_list.items.clone()
+    // Shows the files that appeared in the local directory. If it cannot be read, the pane only stays outdated
+    fn refresh_local(&mut self) {
+        let _ = self.local.refresh(&mut self.local_fs);
+    }
+}
+
+// The outcome of a batch operation: what was done, then which entries it failed for and why
+fn batch_outcome(done: String, failed: &[(String, ftp::Error)]) -> String {
+    match failed {
+        [] => done,
+        [(name, e)] => format!("{}, failed for {}: {}", done, name, e),
+        [(name, e), rest @ ..] => format!("{}, failed for {}: {} and for {} more", done, name, e, rest.len())
+    }
+}
+
+// Changes to the directory of a URL path. A path with a file type code names a file:
+// changes to its directory instead and returns the file name to select
+fn open_path(remote: &mut dyn RemoteFs, path: &str, type_code: Option<TypeCode>) -> ftp::Result<Option<String>> {
+    match type_code {
+        Some(TypeCode::Ascii | TypeCode::Image) => {
+            let (directory, name) = match path.rsplit_once('/') {
+                Some(("", name)) => ("/", name),
+                Some((directory, name)) => (directory, name),
+                None => ("", path)
+            };
+            if !directory.is_empty() {
+                remote.cwd(directory)?;
+            }
+            Ok(Some(name.to_string()))
+        }
+        _ => remote.cwd(path).map(|_| None)
+    }
+}
+
+// The ~/.netrc ($NETRC) entry for `host` and `user`, and a warning if the file could not be used as it is.
+// Like classic ftp clients, passwords are not taken from a file other users have access to.
+fn netrc_entry(host: &str, user: Option<&str>) -> (Option<netrc::Entry>, Option<String>) {
+    let path = match netrc::default_path() {
+        Some(path) if path.exists() => path,
+        _ => return (None, None)
+    };
+    let netrc = match netrc::load(&path) {
+        Ok(netrc) => netrc,
+        Err(e) => return (None, Some(format!("Ignoring {}: {}", path.display(), e)))
+    };
+    let mut entry = netrc.find(host, user).cloned();
+    let has_passwords = netrc.machines.iter().map(|(_, e)| e).chain(netrc.default.iter()).any(|e| e.password.is_some());
+    if has_passwords && netrc::is_exposed(&path).unwrap_or(false) {
+        if let Some(entry) = &mut entry {
+            entry.password = None;
+        }
+        return (entry, Some(format!("{} is accessible by other users, so its passwords are ignored. Remove them or chmod 600 the file", path.display())));
+    }
+    (entry, None)
+}
+
+fn load_certificates() -> io::Result<KnownCertificates> {
+    match certs::default_path() {
+        Some(path) => certs::load(&path),
+        None => Ok(KnownCertificates::default())
+    }
+}
+
+// --certificates and --forget-certificate, which work on the store and exit
+fn manage_certificates(options: &args::Options) {
+    let path = certs::default_path().unwrap_or_else(|| {
+        eprintln!("Cannot find the configuration directory");
+        std::process::exit(2);
+    });
+    let mut known = certs::load(&path).unwrap_or_else(|e| {
+        eprintln!("Cannot read {}: {}", path.display(), e);
+        std::process::exit(2);
+    });
+    if let Some(server) = &options.forget_certificate {
+        match known.forget(server) {
+            0 => {
+                eprintln!("No certificate is pinned for {}", server);
+                std::process::exit(1);
+            }
+            count => {
+                if let Err(e) = certs::save(&path, &known) {
+                    eprintln!("Cannot write {}: {}", path.display(), e);
+                    std::process::exit(2);
+                }
+                println!("Forgot {} certificate(s) of {}", count, server);
+            }
+        }
+    } else {
+        print!("{}", known.to_text());
+    }
+}
+
+// --fxp: copies a file from one server to another and exits. The logins come from the URLs, ~/.netrc or are anonymous
+fn fxp_copy(source: &Url, destination: &Url, transcript: Option<Arc<dyn Sink>>) {
+    let connect = |url: &Url| -> ftp::Result<ftp::Connection> {
+        let (entry, netrc_warning) = netrc_entry(&url.host, url.user.as_deref());
+        if let Some(warning) = netrc_warning {
+            eprintln!("{}", warning);
+        }
+        let entry = entry.unwrap_or_default();
+        let user = url.user.clone().or(entry.login).unwrap_or_else(|| form::ANONYMOUS_USER.to_string());
+        let password = url.password.clone().or(entry.password).unwrap_or_else(|| form::ANONYMOUS_PASSWORD.to_string());
+        let mut connection = ftp::Connection::new(&url.address(), ftp::ConnectionType::Passive)?;
+        if let Some(sink) = &transcript {
+            connection.set_transcript(sink.clone());
+        }
+        connection.login_with_proxy(&ftp::ProxyLogin::None, &user, &password, entry.account.as_deref())?;
+        Ok(connection)
+    };
+    let copy = || -> ftp::Result<fxp::Route> {
+        let mut from = connect(source)?;
+        let mut to = connect(destination)?;
+        fxp::transfer(&mut from, source.path.as_deref().unwrap_or_default(), &mut to, destination.path.as_deref().unwrap_or_default())
+    };
+    match copy() {
+        Ok(fxp::Route::Direct) => println!("Copied directly between the servers"),
+        Ok(fxp::Route::Relayed) => println!("Copied through this computer, as the servers could not connect to each other"),
+        Err(e) => {
+            eprintln!("Cannot copy {}/{}: {}", source.server_url(), source.path.as_deref().unwrap_or_default(), e);
+            std::process::exit(1);
+        }
     }
 }
 
 fn main() -> Result<(), ftp::Error> {    
+    let options = match args::parse(env::args().skip(1)) {
+        Ok(options) if options.help => {
+            println!("{}", args::USAGE);
+            return Ok(());
+        }
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n{}", e, args::USAGE);
+            std::process::exit(2);
+        }
+    };
+    if options.certificates || options.forget_certificate.is_some() {
+        manage_certificates(&options);
+        return Ok(());
+    }
+    let sites = match sites::default_path() {
+        Some(path) => sites::load(&path).unwrap_or_else(|e| {
+            eprintln!("Cannot read {}: {}", path.display(), e);
+            std::process::exit(2);
+        }),
+        None => Sites::default()
+    };
+    if let Some(name) = options.site.as_ref().filter(|name| sites.find(name).is_none()) {
+        eprintln!("No saved site named \"{}\"", name);
+        std::process::exit(2);
+    }
+    let transcript_file = match &options.transcript {
+        Some(path) => Some(Arc::new(transcript::FileSink::create(path)?)),
+        None => None
+    };
+    if let Some((source, destination)) = &options.fxp {
+        fxp_copy(source, destination, transcript_file.map(|sink| sink as Arc<dyn Sink>));
+        return Ok(());
+    }
     enable_raw_mode()?;
     let mut stdout = io::stdout();
     execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
     let mut app = App::new()?;
+    app.transcript_file = transcript_file;
+    app.options = options;
+    app.sites = sites;
 
     app.run(&mut terminal).unwrap_or_else(|e| { 
         terminal.draw(|f| {
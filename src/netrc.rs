@@ -0,0 +1,237 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The credentials of a `machine` or `default` entry
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Entry {
+    pub login: Option<String>,
+    pub password: Option<String>,
+    pub account: Option<String>,
+    /// `macdef` macros by name, each a list of lines
+    pub macros: BTreeMap<String, Vec<String>>
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Netrc {
+    pub machines: Vec<(String, Entry)>,
+    pub default: Option<Entry>
+}
+
+impl Netrc {
+    /// The first entry for `host`, or else the default one. With `user`, entries for another login are skipped
+    pub fn find(&self, host: &str, user: Option<&str>) -> Option<&Entry> {
+        self.machines.iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case(host))
+            .map(|(_, entry)| entry)
+            .chain(self.default.iter())
+            .find(|entry| user.is_none() || entry.login.is_none() || entry.login.as_deref() == user)
+    }
+}
+
+// Splits a netrc file into whitespace separated tokens, which may be quoted
+struct Tokens<'a> {
+    text: &'a str,
+    pos: usize
+}
+
+impl Tokens<'_> {
+    fn next(&mut self) -> Option<String> {
+        loop {
+            let rest = &self.text[self.pos..];
+            let trimmed = rest.trim_start();
+            self.pos += rest.len() - trimmed.len();
+            if !trimmed.starts_with('#') {
+                break;
+            }
+            // Comments run to the end of the line
+            self.pos += trimmed.find('\n').unwrap_or(trimmed.len());
+        }
+
+        let rest = &self.text[self.pos..];
+        let mut chars = rest.char_indices();
+        let mut token = String::new();
+        match chars.next() {
+            None => return None,
+            Some((_, '"')) => {
+                // "quoted token", with backslash escapes
+                let mut escaped = false;
+                for (i, c) in chars {
+                    match c {
+                        _ if escaped => {
+                            token.push(c);
+                            escaped = false;
+                        }
+                        '\\' => escaped = true,
+                        '"' => {
+                            self.pos += i + 1;
+                            return Some(token);
+                        }
+                        _ => token.push(c)
+                    }
+                }
+                self.pos = self.text.len();
+            }
+            Some(_) => {
+                let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                token.push_str(&rest[..end]);
+                self.pos += end;
+            }
+        }
+        Some(token)
+    }
+
+    fn value(&mut self, keyword: &str) -> Result<String, String> {
+        self.next().ok_or_else(|| format!("\"{}\" needs a value", keyword))
+    }
+
+    // A macro body: the lines following the current one, up to an empty line
+    fn macro_body(&mut self) -> Vec<String> {
+        let rest = &self.text[self.pos..];
+        let mut lines = Vec::new();
+        let mut consumed = rest.find('\n').map_or(rest.len(), |i| i + 1);
+        for line in rest[consumed..].split_inclusive('\n') {
+            consumed += line.len();
+            if line.trim().is_empty() {
+                break;
+            }
+            lines.push(line.trim_end_matches(['\r', '\n']).to_string());
+        }
+        self.pos += consumed;
+        lines
+    }
+}
+
+// The entry the keywords that follow belong to
+enum Current {
+    Machine(usize),
+    Default
+}
+
+pub fn parse(text: &str) -> Result<Netrc, String> {
+    let mut res = Netrc::default();
+    let mut tokens = Tokens { text, pos: 0 };
+    let mut current = None;
+    while let Some(token) = tokens.next() {
+        if token == "machine" {
+            res.machines.push((tokens.value(&token)?, Entry::default()));
+            current = Some(Current::Machine(res.machines.len() - 1));
+            continue;
+        }
+        if token == "default" {
+            res.default = Some(Entry::default());
+            current = Some(Current::Default);
+            continue;
+        }
+        let entry = match current {
+            Some(Current::Machine(i)) => res.machines.get_mut(i).map(|(_, entry)| entry),
+            Some(Current::Default) => res.default.as_mut(),
+            None => None
+        };
+        let entry = entry.ok_or_else(|| format!("\"{}\" before the first machine", token))?;
+        match token.as_str() {
+            "login" => entry.login = Some(tokens.value(&token)?),
+            "password" => entry.password = Some(tokens.value(&token)?),
+            "account" => entry.account = Some(tokens.value(&token)?),
+            "macdef" => {
+                let name = tokens.value(&token)?;
+                entry.macros.insert(name, tokens.macro_body());
+            }
+            _ => return Err(format!("Unknown keyword \"{}\"", token))
+        }
+    }
+    Ok(res)
+}
+
+/// $NETRC, or ~/.netrc
+pub fn default_path() -> Option<PathBuf> {
+    match env::var_os("NETRC") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => home::home_dir().map(|home| home.join(".netrc"))
+    }
+}
+
+pub fn load(path: &Path) -> io::Result<Netrc> {
+    parse(&fs::read_to_string(path)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Whether users other than the owner have access to the file, which should not be trusted with passwords then
+#[cfg(unix)]
+pub fn is_exposed(path: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::PermissionsExt;
+    Ok(fs::metadata(path)?.permissions().mode() & 0o077 != 0)
+}
+
+#[cfg(not(unix))]
+pub fn is_exposed(_path: &Path) -> io::Result<bool> {
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = "# Work servers
+machine ftp.example.org login alice password \"se cret\\\"s\"
+machine FILES.example.org
+    login bob password hunter2 account sales
+    macdef init
+cd /pub
+binary
+
+machine ftp.example.org login carol password other
+default login anonymous password guest@
+";
+
+    #[test]
+    fn parse_test() {
+        let netrc = parse(SAMPLE).unwrap();
+        assert_eq!(netrc.machines.len(), 3);
+        let (name, alice) = &netrc.machines[0];
+        assert_eq!(name, "ftp.example.org");
+        assert_eq!((alice.login.as_deref(), alice.password.as_deref()), (Some("alice"), Some("se cret\"s")));
+
+        let bob = &netrc.machines[1].1;
+        assert_eq!(bob.account.as_deref(), Some("sales"));
+        assert_eq!(bob.macros.get("init"), Some(&vec!["cd /pub".to_string(), "binary".to_string()]));
+        // The macro ends at the empty line, so the next machine is parsed as usual
+        assert_eq!(netrc.machines[2].1.login.as_deref(), Some("carol"));
+        assert_eq!(netrc.default.as_ref().unwrap().password.as_deref(), Some("guest@"));
+    }
+
+    #[test]
+    fn find_test() {
+        let netrc = parse(SAMPLE).unwrap();
+        assert_eq!(netrc.find("ftp.example.org", None).unwrap().login.as_deref(), Some("alice"));
+        assert_eq!(netrc.find("ftp.example.org", Some("carol")).unwrap().password.as_deref(), Some("other"));
+        assert_eq!(netrc.find("files.example.org", None).unwrap().login.as_deref(), Some("bob"));
+        assert_eq!(netrc.find("elsewhere.org", None).unwrap().login.as_deref(), Some("anonymous"));
+        assert_eq!(netrc.find("elsewhere.org", Some("dave")), None);
+        assert_eq!(parse("machine a login b").unwrap().find("c", None), None);
+    }
+
+    #[test]
+    fn invalid_test() {
+        assert!(parse("login alice").is_err());
+        assert!(parse("machine").is_err());
+        assert!(parse("machine a login").is_err());
+        assert!(parse("machine a port 21").is_err());
+        assert_eq!(parse("  \n# nothing here\n").unwrap(), Netrc::default());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exposed_test() -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        let path = env::temp_dir().join(format!("termftp_netrc_{}", std::process::id()));
+        fs::write(&path, "machine a login b password c")?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+        assert!(!is_exposed(&path)?);
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644))?;
+        assert!(is_exposed(&path)?);
+        assert_eq!(load(&path)?.find("a", None).unwrap().password.as_deref(), Some("c"));
+        fs::remove_file(&path)
+    }
+}
@@ -0,0 +1,242 @@
+use crate::ftp::{self, Connection, ConnectionType, Encoding, ProxyLogin};
+use crate::proxy::{self, Connector};
+use crate::transcript::Sink;
+use crate::throttle::Throttle;
+use crate::tls::Tls;
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Key {
+    pub host: String,
+    pub user: String
+}
+
+struct Server {
+    limit: usize,
+    open: usize,
+    idle: Vec<(Connection, Instant)>
+}
+
+/// A pool of logged-in connections, keyed by server and user.
+/// Connections are checked with NOOP before being handed out, closed after sitting idle for `idle_timeout`,
+/// and the per-server limit shrinks when the server refuses new connections with 421.
+pub struct Pool {
+    max_connections: usize,
+    idle_timeout: Duration,
+    wait_timeout: Duration,
+    servers: Mutex<HashMap<Key, Server>>,
+    returned: Condvar,
+    connector: Arc<dyn Connector>,
+    transcript: Option<Arc<dyn Sink>>,
+    throttle: Option<Arc<Throttle>>,
+    tls: Option<Tls>,
+    account: Option<String>,
+    proxy_login: ProxyLogin,
+    encoding: Encoding
+}
+
+/// A connection borrowed from a `Pool`, handed back when dropped
+pub struct PooledConnection<'a> {
+    pool: &'a Pool,
+    key: Key,
+    connection: Option<Connection>
+}
+
+impl Deref for PooledConnection<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.connection.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledConnection<'_> {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.connection.as_mut().unwrap()
+    }
+}
+
+impl PooledConnection<'_> {
+    /// Closes the connection instead of handing it back, e.g. after an error left it in an unknown state
+    pub fn discard(mut self) {
+        if let Some(connection) = self.connection.take() {
+            self.pool.release(&self.key);
+            drop(connection);
+        }
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            let mut servers = self.pool.lock();
+            if let Some(server) = servers.get_mut(&self.key) {
+                server.idle.push((connection, Instant::now()));
+            }
+            self.pool.returned.notify_all();
+        }
+    }
+}
+
+fn is_refusal(e: &ftp::Error) -> bool {
+    e.code() == Some("421")
+}
+
+impl Pool {
+    pub fn new(max_connections: usize, idle_timeout: Duration) -> Pool {
+        Self::with_connector(max_connections, idle_timeout, proxy::from_env())
+    }
+
+    /// Creates a pool whose connections are opened with `connector`
+    pub fn with_connector(max_connections: usize, idle_timeout: Duration, connector: Arc<dyn Connector>) -> Pool {
+        Pool {
+            max_connections: max_connections.max(1),
+            idle_timeout,
+            wait_timeout: Duration::from_secs(30),
+            servers: Mutex::new(HashMap::new()),
+            returned: Condvar::new(),
+            connector,
+            transcript: None,
+            throttle: None,
+            tls: None,
+            account: None,
+            proxy_login: ProxyLogin::None,
+            encoding: Encoding::Utf8
+        }
+    }
+
+    /// Limits every connection opened from now on with `throttle`, shared across all of them
+    pub fn set_throttle(&mut self, throttle: Arc<Throttle>) {
+        self.throttle = Some(throttle);
+    }
+
+    /// Records the transcript of every connection opened from now on to `sink`
+    pub fn set_transcript(&mut self, sink: Arc<dyn Sink>) {
+        self.transcript = Some(sink);
+    }
+
+    /// Secures every connection opened from now on with `tls`
+    pub fn set_tls(&mut self, tls: Tls) {
+        self.tls = Some(tls);
+    }
+
+    /// Sends `account` with ACCT when a server asks for one during login
+    pub fn set_account(&mut self, account: String) {
+        self.account = Some(account);
+    }
+
+    /// Logs in through an FTP proxy with `login`. The pool's host is then the proxy's
+    pub fn set_proxy_login(&mut self, login: ProxyLogin) {
+        self.proxy_login = login;
+    }
+
+    /// Sets the character set of the file names on the servers
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<Key, Server>> {
+        self.servers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn release(&self, key: &Key) {
+        if let Some(server) = self.lock().get_mut(key) {
+            server.open -= 1;
+        }
+        self.returned.notify_all();
+    }
+
+    /// Overrides the maximum number of connections for a single server and user
+    pub fn set_limit(&self, host: &str, user: &str, limit: usize) {
+        let key = Key { host: host.to_string(), user: user.to_string() };
+        let mut servers = self.lock();
+        servers.entry(key)
+            .or_insert_with(|| Server { limit, open: 0, idle: Vec::new() })
+            .limit = limit.max(1);
+    }
+
+    /// Closes every connection that has been idle for longer than the idle timeout
+    pub fn evict_idle(&self) {
+        let mut expired = Vec::new();
+        {
+            let mut servers = self.lock();
+            for server in servers.values_mut() {
+                let (keep, evict): (Vec<_>, Vec<_>) = server.idle.drain(..).partition(|(_, since)| since.elapsed() < self.idle_timeout);
+                server.idle = keep;
+                server.open -= evict.len();
+                expired.extend(evict);
+            }
+        }
+        // QUIT is sent outside of the lock
+        drop(expired);
+    }
+
+    /// Borrows a logged-in connection to `host` as `user`, opening a new one if the limit allows it
+    /// and waiting for one to be returned otherwise.
+    /// Idle connections keep the working directory and transfer type their last borrower left them in.
+    pub fn get(&self, host: &str, user: &str, password: &str, connection_type: ConnectionType) -> ftp::Result<PooledConnection<'_>> {
+        let key = Key { host: host.to_string(), user: user.to_string() };
+        let deadline = Instant::now() + self.wait_timeout;
+        self.evict_idle();
+
+        loop {
+            let mut servers = self.lock();
+            let server = servers.entry(key.clone())
+                .or_insert_with(|| Server { limit: self.max_connections, open: 0, idle: Vec::new() });
+
+            if let Some((mut connection, _)) = server.idle.pop() {
+                drop(servers);
+                if connection.issue_command("NOOP", vec![]).is_ok() {
+                    return Ok(PooledConnection { pool: self, key, connection: Some(connection) });
+                }
+                drop(connection);
+                self.release(&key);
+                continue;
+            }
+
+            if server.open < server.limit {
+                server.open += 1;
+                drop(servers);
+                match self.connect(host, user, password, connection_type) {
+                    Ok(connection) => return Ok(PooledConnection { pool: self, key, connection: Some(connection) }),
+                    Err(e) => {
+                        let mut servers = self.lock();
+                        let server = servers.get_mut(&key).unwrap();
+                        server.open -= 1;
+                        // Stop short of the number of connections the server is willing to accept
+                        if !is_refusal(&e) || server.open == 0 {
+                            return Err(e);
+                        }
+                        server.limit = server.open;
+                        continue;
+                    }
+                }
+            }
+
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                return Err(ftp::Error::PoolTimeout);
+            }
+            drop(self.returned.wait_timeout(servers, timeout).unwrap_or_else(|e| e.into_inner()));
+        }
+    }
+
+    fn connect(&self, host: &str, user: &str, password: &str, connection_type: ConnectionType) -> ftp::Result<Connection> {
+        let mut connection = Connection::with_connector(host, connection_type, self.connector.clone())?;
+        if let Some(sink) = &self.transcript {
+            connection.set_transcript(sink.clone());
+        }
+        if let Some(throttle) = &self.throttle {
+            connection.set_throttle(throttle.clone());
+        }
+        connection.set_encoding(self.encoding);
+        if let Some(tls) = &self.tls {
+            connection.set_tls(tls.clone())?;
+        }
+        connection.login_with_proxy(&self.proxy_login, user, password, self.account.as_deref())?;
+        Ok(connection)
+    }
+}
@@ -0,0 +1,153 @@
+use crate::ftp;
+use crate::remote_fs::{Entry, RemoteFs};
+use std::io::Read;
+
+/// How much of a file is fetched at a time, at first and whenever scrolling gets near the end of what was fetched
+pub const PAGE_SIZE: u64 = 16 << 10;
+
+/// The start of a remote file, as far as it has been fetched
+pub struct Preview {
+    /// The directory the file is in
+    pub dir: String,
+    pub name: String,
+    pub size: Option<u64>,
+    pub data: Vec<u8>,
+    /// Whether `data` runs to the end of the file
+    pub complete: bool,
+    /// Why the file could not be read
+    pub error: Option<String>,
+    /// The first row shown
+    pub scroll: usize,
+    /// The rows the contents take up and the rows that fit in the panel, as last drawn
+    pub rows: usize,
+    pub height: usize
+}
+
+impl Preview {
+    /// A preview of `entry` in `dir`, with nothing fetched yet
+    pub fn new(dir: &str, entry: &Entry) -> Preview {
+        Preview {
+            dir: dir.to_string(),
+            name: entry.name.clone(),
+            size: entry.size,
+            data: Vec::new(),
+            complete: false,
+            error: None,
+            scroll: 0,
+            rows: 0,
+            height: 0
+        }
+    }
+
+    /// Whether this previews `name` in `dir`
+    pub fn shows(&self, dir: &str, name: &str) -> bool {
+        self.dir == dir && self.name == name
+    }
+
+    /// Fetches the next page of the file from the current directory of `fs`
+    pub fn fetch(&mut self, fs: &mut dyn RemoteFs) -> ftp::Result<()> {
+        let offset = self.data.len() as u64;
+        let page = read_range(fs, &self.name, offset, PAGE_SIZE)?;
+        let end = offset + page.len() as u64;
+        self.complete = (page.len() as u64) < PAGE_SIZE || self.size.is_some_and(|size| end >= size);
+        self.data.extend(page);
+        Ok(())
+    }
+
+    /// Whether scrolling has got close enough to the end of what was fetched to fetch more
+    pub fn wants_more(&self) -> bool {
+        !self.complete && self.error.is_none() && self.scroll + 2 * self.height >= self.rows
+    }
+
+    /// Scrolls by `rows`, down if positive, without going past the last page
+    pub fn scroll_by(&mut self, rows: isize) {
+        let last = self.rows.saturating_sub(self.height);
+        self.scroll = self.scroll.saturating_add_signed(rows).min(last);
+    }
+
+    pub fn is_binary(&self) -> bool {
+        is_binary(&self.data)
+    }
+
+    /// The lines to show in a panel `width` columns wide: the text, or a hex dump of binary data
+    pub fn lines(&self, width: usize) -> Vec<String> {
+        if self.is_binary() {
+            return hex_dump(&self.data, width);
+        }
+        String::from_utf8_lossy(&self.data).lines()
+            .map(|line| line.trim_end_matches('\r').replace('\t', "    "))
+            .collect()
+    }
+}
+
+/// Reads up to `len` bytes of `path` on `fs` from `offset` on. A transfer that would go on is aborted
+pub fn read_range(fs: &mut dyn RemoteFs, path: &str, offset: u64, len: u64) -> ftp::Result<Vec<u8>> {
+    // Offsets in ASCII mode do not match the file
+    fs.set_ascii(false)?;
+    let mut input = fs.read_from(path, offset)?;
+    let mut res = Vec::new();
+    (&mut input).take(len).read_to_end(&mut res)?;
+    if (res.len() as u64) < len {
+        input.finish()?;
+    }
+    Ok(res)
+}
+
+/// Whether `data` looks like something other than UTF-8 text. A character cut off at the end still counts as text
+pub fn is_binary(data: &[u8]) -> bool {
+    data.contains(&0) || std::str::from_utf8(data).is_err_and(|e| e.error_len().is_some())
+}
+
+/// `data` as offsets, bytes in hex and printable characters, like `hexdump -C`, with as many bytes per line as fit in
+/// `width` columns
+pub fn hex_dump(data: &[u8], width: usize) -> Vec<String> {
+    // The offset and the separators take 13 columns, each byte 4
+    let per_line = [16, 8, 4].into_iter().find(|&n| 13 + 4 * n <= width).unwrap_or(4);
+    data.chunks(per_line).enumerate().map(|(i, chunk)| {
+        let hex: String = chunk.iter().map(|b| format!("{:02x} ", b)).collect();
+        let text: String = chunk.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }).collect();
+        format!("{:08x}  {:width$} |{}|", i * per_line, hex, text, width = 3 * per_line)
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remote_fs::LocalFs;
+    use std::fs;
+    use std::io;
+
+    #[test]
+    fn fetch_test() -> io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("termftp-preview-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let text: Vec<u8> = "line\r\n".repeat(5000).into_bytes();
+        fs::write(dir.join("text.txt"), &text)?;
+        let mut local = LocalFs::new(dir.clone());
+
+        let entry = local.stat("text.txt").unwrap();
+        let mut preview = Preview::new("/", &entry);
+        preview.fetch(&mut local).unwrap();
+        assert_eq!((preview.data.len() as u64, preview.complete), (PAGE_SIZE, false));
+        assert!(!preview.is_binary());
+        assert_eq!(preview.lines(80)[0], "line");
+        preview.fetch(&mut local).unwrap();
+        assert_eq!((preview.data, preview.complete), (text, true));
+        assert_eq!(read_range(&mut local, "text.txt", 29998, 10).unwrap(), b"\r\n");
+
+        fs::remove_dir_all(&dir)
+    }
+
+    #[test]
+    fn hex_dump_test() {
+        assert!(is_binary(b"\x7fELF\x00") && is_binary(b"\xff\xfe") && !is_binary("caf\u{e9}".as_bytes()));
+        // A character cut off at the end of a page
+        assert!(!is_binary(&"caf\u{e9}".as_bytes()[..4]));
+        let data: Vec<u8> = (0x40..0x54).collect();
+        assert_eq!(hex_dump(&data, 80), vec![
+            "00000000  40 41 42 43 44 45 46 47 48 49 4a 4b 4c 4d 4e 4f  |@ABCDEFGHIJKLMNO|",
+            "00000010  50 51 52 53                                      |PQRS|"
+        ]);
+        assert_eq!(hex_dump(&data[..8], 60), vec!["00000000  40 41 42 43 44 45 46 47  |@ABCDEFG|"]);
+    }
+}
@@ -0,0 +1,684 @@
+use crate::date::format_time;
+use crate::ftp;
+use crate::remote_fs::{self, Entry, EntryKind, LocalFs, RemoteFs};
+use crate::throttle::Throttle;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+
+// How often a running transfer updates its progress
+const UPDATE_INTERVAL: Duration = Duration::from_millis(200);
+// How often the progress of a running transfer is saved
+const SAVE_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Download,
+    Upload
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Queued,
+    Running,
+    Paused,
+    Done,
+    Failed(String)
+}
+
+impl State {
+    /// Whether the transfer is still to be done, and kept in the state file
+    pub fn unfinished(&self) -> bool {
+        matches!(self, State::Queued | State::Running | State::Paused)
+    }
+}
+
+/// A file or directory to copy between the local disk and the server
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Transfer {
+    #[serde(skip)]
+    pub id: u64,
+    /// The server and user, as `Url::server_url` gives them
+    pub server: String,
+    /// The saved site the transfer was queued with, for its settings
+    pub site: Option<String>,
+    pub direction: Direction,
+    pub name: String,
+    pub kind: EntryKind,
+    /// The directories the entry is copied from and to, absolute
+    pub local_dir: PathBuf,
+    pub remote_dir: String,
+    /// In bytes, if known
+    pub size: Option<u64>,
+    pub transferred: u64,
+    /// Bytes per second
+    #[serde(skip)]
+    pub rate: u64,
+    pub state: State,
+    /// Delete the source once it is copied
+    #[serde(default)]
+    pub move_source: bool,
+    /// Bytes per second this transfer is held to besides the limit shared by all, 0 for none
+    #[serde(default)]
+    pub limit: u64
+}
+
+impl Transfer {
+    pub fn new(direction: Direction, entry: &Entry, local_dir: PathBuf, remote_dir: &str, server: &str, site: Option<&str>) -> Transfer {
+        Transfer {
+            id: 0,
+            server: server.to_string(),
+            site: site.map(str::to_string),
+            direction,
+            name: entry.name.clone(),
+            kind: entry.kind,
+            local_dir,
+            remote_dir: remote_dir.to_string(),
+            size: entry.size.filter(|_| !entry.is_dir()),
+            transferred: 0,
+            rate: 0,
+            state: State::Queued,
+            move_source: false,
+            limit: 0
+        }
+    }
+
+    /// The time left at the current rate, if it can be told
+    pub fn eta(&self) -> Option<Duration> {
+        let left = self.size?.saturating_sub(self.transferred);
+        (self.rate > 0).then(|| Duration::from_secs(left / self.rate))
+    }
+
+    /// The part transferred, from 0 to 1
+    pub fn ratio(&self) -> f64 {
+        match self.size {
+            Some(0) => if self.state == State::Done { 1.0 } else { 0.0 },
+            Some(size) => (self.transferred as f64 / size as f64).min(1.0),
+            None => if self.state == State::Done { 1.0 } else { 0.0 }
+        }
+    }
+
+    /// The location on the server, as a URL
+    pub fn remote_url(&self) -> String {
+        format!("{}{}/{}", self.server, self.remote_dir.trim_end_matches('/'), self.name)
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Saved {
+    #[serde(default)]
+    transfers: Vec<Transfer>
+}
+
+#[derive(Default)]
+struct Shared {
+    items: Vec<Transfer>,
+    next_id: u64,
+    // The server the worker is connected to, it leaves transfers for others queued
+    server: String,
+    // The worker is to stop
+    closed: bool,
+    // How many transfers ended, so the panes can be refreshed
+    finished: u64,
+    // The state file and the history file, if the queue is kept
+    path: Option<PathBuf>,
+    history: Option<PathBuf>,
+    last_save: Option<Instant>,
+    // The state file is to be replaced, and how many times it was so far
+    unsaved: bool,
+    version: u64,
+    // Transfers that ended and are to be added to the history file
+    records: Vec<(Transfer, &'static str)>,
+    // Why saving failed, until it is reported
+    error: Option<String>
+}
+
+impl Shared {
+    // Has the unfinished transfers written to the state file once the lock is released, see Queue::write
+    fn save(&mut self) {
+        self.unsaved = true;
+    }
+
+    // Has a transfer that ended added to the history file once the lock is released
+    fn record(&mut self, transfer: &Transfer, outcome: &'static str) {
+        if self.history.is_some() {
+            self.records.push((transfer.clone(), outcome));
+        }
+    }
+}
+
+/// Transfers waiting for and being processed by a worker thread, shared with the UI
+#[derive(Clone, Default)]
+pub struct Queue {
+    // The last of the three is held while writing files, with the version of the state file written last
+    shared: Arc<(Mutex<Shared>, Condvar, Mutex<u64>)>
+}
+
+impl Queue {
+    pub fn new() -> Queue {
+        Queue::default()
+    }
+
+    /// A queue that keeps its unfinished transfers in the state file at `path`, and adds the others to `history`
+    pub fn persistent(path: PathBuf, history: PathBuf) -> Queue {
+        let queue = Queue::new();
+        {
+            let mut shared = queue.lock();
+            shared.path = Some(path);
+            shared.history = Some(history);
+        }
+        queue
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Shared> {
+        self.shared.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Releases `shared`, then writes what it had saved and recorded. The files are written outside of the lock, so
+    // the UI does not wait for the disk
+    fn write(&self, mut shared: MutexGuard<'_, Shared>) {
+        let state = match shared.path.clone() {
+            Some(path) if shared.unsaved => {
+                let transfers: Vec<Transfer> = shared.items.iter().filter(|t| t.state.unfinished()).cloned().collect();
+                shared.version += 1;
+                shared.last_save = Some(Instant::now());
+                Some((path, shared.version, to_text(&transfers)))
+            }
+            _ => None
+        };
+        shared.unsaved = false;
+        let records = mem::take(&mut shared.records);
+        let history = shared.history.clone();
+        drop(shared);
+
+        let mut error = None;
+        {
+            let mut written = self.shared.2.lock().unwrap_or_else(|e| e.into_inner());
+            // Another thread may have written a newer state meanwhile
+            if let Some((path, version, text)) = state.filter(|(_, version, _)| version > &written) {
+                *written = version;
+                if let Err(e) = text.and_then(|text| write_state(&path, &text)) {
+                    error = Some(format!("Could not save the transfer queue: {}", e));
+                }
+            }
+            for (transfer, outcome) in &records {
+                if let Err(e) = history.as_deref().map_or(Ok(()), |history| record(history, transfer, outcome)) {
+                    error = Some(format!("Could not write the transfer history: {}", e));
+                }
+            }
+        }
+        if error.is_some() {
+            self.lock().error = error;
+        }
+    }
+
+    // Applies `f` to the transfer with `id`, saves the queue and wakes the worker
+    fn change<F: FnOnce(&mut Transfer)>(&self, id: u64, f: F) {
+        let mut shared = self.lock();
+        if let Some(transfer) = shared.items.iter_mut().find(|t| t.id == id) {
+            f(transfer);
+        }
+        shared.save();
+        self.shared.1.notify_all();
+        self.write(shared);
+    }
+
+    /// Adds `transfer` at the end, returns its id
+    pub fn push(&self, transfer: Transfer) -> u64 {
+        let id = self.add(transfer);
+        let mut shared = self.lock();
+        shared.save();
+        self.write(shared);
+        id
+    }
+
+    fn add(&self, mut transfer: Transfer) -> u64 {
+        let mut shared = self.lock();
+        shared.next_id += 1;
+        transfer.id = shared.next_id;
+        shared.items.push(transfer);
+        self.shared.1.notify_all();
+        shared.next_id
+    }
+
+    /// Adds transfers read from the state file. Those that were running go on when the worker gets to them
+    pub fn restore(&self, transfers: Vec<Transfer>) {
+        for mut transfer in transfers {
+            if transfer.state == State::Running {
+                transfer.state = State::Queued;
+            }
+            self.add(transfer);
+        }
+        let mut shared = self.lock();
+        shared.save();
+        self.write(shared);
+    }
+
+    /// A copy of the transfers, in the order they are processed
+    pub fn items(&self) -> Vec<Transfer> {
+        self.lock().items.clone()
+    }
+
+    /// How many transfers have ended so far
+    pub fn finished(&self) -> u64 {
+        self.lock().finished
+    }
+
+    /// The server the worker works with, see `reopen`
+    pub fn server(&self) -> String {
+        self.lock().server.clone()
+    }
+
+    /// Why saving the queue or its history failed last, once
+    pub fn take_error(&self) -> Option<String> {
+        self.lock().error.take()
+    }
+
+    /// Stops a running transfer, keeps a queued one from starting
+    pub fn pause(&self, id: u64) {
+        self.change(id, |t| if matches!(t.state, State::Queued | State::Running) { t.state = State::Paused });
+    }
+
+    /// Queues a paused transfer again. It goes on from where it stopped
+    pub fn resume(&self, id: u64) {
+        self.change(id, |t| if t.state == State::Paused { t.state = State::Queued });
+    }
+
+    /// Queues a failed transfer again
+    pub fn retry(&self, id: u64) {
+        self.change(id, |t| if matches!(t.state, State::Failed(_)) { t.state = State::Queued });
+    }
+
+    /// Sets the limit of the transfer, taking effect right away if it is running
+    pub fn set_limit(&self, id: u64, limit: u64) {
+        self.change(id, |t| t.limit = limit);
+    }
+
+    /// Removes the transfer, stopping it if it is running
+    pub fn cancel(&self, id: u64) {
+        let mut shared = self.lock();
+        if let Some(i) = shared.items.iter().position(|t| t.id == id) {
+            let transfer = shared.items.remove(i);
+            if transfer.state.unfinished() {
+                shared.record(&transfer, "cancelled");
+            }
+        }
+        shared.save();
+        self.shared.1.notify_all();
+        self.write(shared);
+    }
+
+    /// Moves the transfer one place towards the front, or towards the back
+    pub fn move_item(&self, id: u64, forward: bool) {
+        let mut shared = self.lock();
+        if let Some(i) = shared.items.iter().position(|t| t.id == id) {
+            let j = if forward { i.checked_sub(1) } else { Some(i + 1).filter(|&j| j < shared.items.len()) };
+            if let Some(j) = j {
+                shared.items.swap(i, j);
+                shared.save();
+            }
+        }
+        self.write(shared);
+    }
+
+    /// Makes the worker stop, leaving a running transfer queued
+    pub fn close(&self) {
+        self.lock().closed = true;
+        self.shared.1.notify_all();
+    }
+
+    /// Lets a new worker run after `close`, for the transfers with `server`
+    pub fn reopen(&self, server: &str) {
+        let mut shared = self.lock();
+        shared.closed = false;
+        shared.server = server.to_string();
+    }
+
+    // Waits for the first queued transfer with the server and marks it running, None once the queue is closed
+    fn next(&self) -> Option<Transfer> {
+        let mut shared = self.lock();
+        loop {
+            if shared.closed {
+                return None;
+            }
+            let server = shared.server.clone();
+            if let Some(transfer) = shared.items.iter_mut().find(|t| t.state == State::Queued && t.server == server) {
+                transfer.state = State::Running;
+                transfer.rate = 0;
+                let transfer = transfer.clone();
+                shared.save();
+                self.write(shared);
+                return Some(transfer);
+            }
+            shared = self.shared.1.wait(shared).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    // Records the progress of a running transfer. Returns its limit, None if it is to stop as it was paused or cancelled
+    fn update(&self, id: u64, transferred: u64, rate: Option<u64>) -> Option<u64> {
+        let mut shared = self.lock();
+        let closed = shared.closed;
+        match shared.items.iter_mut().find(|t| t.id == id) {
+            Some(transfer) if transfer.state == State::Running && !closed => {
+                transfer.transferred = transferred;
+                if let Some(rate) = rate {
+                    transfer.rate = rate;
+                }
+                let limit = transfer.limit;
+                if shared.last_save.is_none_or(|last| last.elapsed() >= SAVE_INTERVAL) {
+                    shared.save();
+                }
+                self.write(shared);
+                Some(limit)
+            }
+            _ => None
+        }
+    }
+
+    // Records how a transfer ended, unless it was stopped on purpose
+    fn complete(&self, id: u64, result: ftp::Result<()>) {
+        let mut shared = self.lock();
+        let closed = shared.closed;
+        if let Some(i) = shared.items.iter().position(|t| t.id == id && t.state == State::Running) {
+            let transfer = &mut shared.items[i];
+            transfer.state = match result {
+                Ok(()) => State::Done,
+                Err(_) if closed => State::Queued,
+                Err(e) => State::Failed(e.to_string())
+            };
+            transfer.rate = 0;
+            let transfer = transfer.clone();
+            match &transfer.state {
+                State::Done => shared.record(&transfer, "done"),
+                State::Failed(_) => shared.record(&transfer, "failed"),
+                _ => {}
+            }
+        }
+        shared.finished += 1;
+        shared.save();
+        self.write(shared);
+    }
+}
+
+// $XDG_DATA_HOME/termftp, or ~/.local/share/termftp
+fn data_dir() -> Option<PathBuf> {
+    let data = match env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => home::home_dir()?.join(".local").join("share")
+    };
+    Some(data.join("termftp"))
+}
+
+/// The state file the unfinished transfers are kept in
+pub fn default_path() -> Option<PathBuf> {
+    data_dir().map(|dir| dir.join("queue.toml"))
+}
+
+/// The file finished, failed and cancelled transfers are added to
+pub fn history_path() -> Option<PathBuf> {
+    data_dir().map(|dir| dir.join("history"))
+}
+
+/// Reads the transfers saved at `path`, none if the file does not exist
+pub fn load(path: &Path) -> io::Result<Vec<Transfer>> {
+    match fs::read_to_string(path) {
+        Ok(text) => {
+            let saved: Saved = toml::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            Ok(saved.transfers)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e)
+    }
+}
+
+/// Replaces the transfers saved at `path`
+pub fn save(path: &Path, transfers: &[Transfer]) -> io::Result<()> {
+    write_state(path, &to_text(transfers)?)
+}
+
+fn to_text(transfers: &[Transfer]) -> io::Result<String> {
+    toml::to_string(&Saved { transfers: transfers.to_vec() }).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+// Replaces the state file with `text`, readable only by the user as it has user names and paths
+fn write_state(path: &Path, text: &str) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // Written aside and renamed, so a crash leaves the previous state rather than half of it
+    let temporary = path.with_extension("toml.tmp");
+    // A temporary file left by a crash may have other permissions, which opening it would keep
+    let _ = fs::remove_file(&temporary);
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(&temporary)?.write_all(text.as_bytes())?;
+    fs::rename(&temporary, path)
+}
+
+/// Adds a line for `transfer` to the history at `path`: the time, `outcome`, the direction, the remote URL,
+/// the local path, the bytes transferred and the error, separated by tabs
+pub fn record(path: &Path, transfer: &Transfer, outcome: &str) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let direction = match transfer.direction {
+        Direction::Download => "download",
+        Direction::Upload => "upload"
+    };
+    let error = match &transfer.state {
+        State::Failed(e) => e.as_str(),
+        _ => ""
+    };
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    // Readable only by the user, like the state file
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    writeln!(file, "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+        format_time(SystemTime::now()),
+        outcome,
+        direction,
+        transfer.remote_url(),
+        transfer.local_dir.join(&transfer.name).display(),
+        transfer.transferred,
+        error.replace(['\t', '\n'], " "))
+}
+
+/// Processes the queue until it is closed, over a connection from `open` that is reopened after a failure.
+/// `ascii` tells which files to transfer as text
+pub fn work<'a, O, A>(queue: &Queue, open: O, ascii: A)
+where
+    O: Fn() -> ftp::Result<Box<dyn RemoteFs + 'a>>,
+    A: Fn(&str) -> bool
+{
+    let mut remote = None;
+    while let Some(transfer) = queue.next() {
+        let result = run(queue, &mut remote, &open, &ascii, &transfer);
+        if result.is_err() {
+            // The connection may be broken, or in the middle of the aborted transfer
+            remote = None;
+        }
+        queue.complete(transfer.id, result);
+    }
+}
+
+fn run<'a, O, A>(queue: &Queue, remote: &mut Option<Box<dyn RemoteFs + 'a>>, open: &O, ascii: &A, transfer: &Transfer) -> ftp::Result<()>
+where
+    O: Fn() -> ftp::Result<Box<dyn RemoteFs + 'a>>,
+    A: Fn(&str) -> bool
+{
+    let remote = match remote {
+        Some(remote) => remote,
+        None => remote.insert(open()?)
+    };
+    remote.cwd(&transfer.remote_dir)?;
+    // The limit of this transfer applies to the server's side, and can change while it runs.
+    // The connection is only reused after a transfer that succeeded, which takes the limit off again
+    let throttle = Arc::new(Throttle::unlimited());
+    let bucket = match transfer.direction {
+        Direction::Download => &throttle.download,
+        Direction::Upload => &throttle.upload
+    };
+    bucket.set_rate(transfer.limit);
+    remote.set_transfer_throttle(Some(throttle.clone()));
+    let mut local = LocalFs::new(transfer.local_dir.clone());
+    let (from, to): (&mut dyn RemoteFs, &mut dyn RemoteFs) = match transfer.direction {
+        Direction::Download => (remote.as_mut(), &mut local),
+        Direction::Upload => (&mut local, remote.as_mut())
+    };
+
+    let mut entry = Entry::new(&transfer.name, transfer.kind);
+    entry.size = transfer.size;
+    // A transfer that got somewhere before goes on from what is at the destination
+    let resume = transfer.transferred > 0;
+    let mut start: Option<(Instant, u64)> = None;
+    let mut last_update = Instant::now();
+    remote_fs::copy_tree(from, &entry, to, ascii, resume, &mut |_, copied| {
+        // The rate is only worked out now and then, but a pause is noticed right away.
+        // It counts from the first progress, after the part that was there already
+        let (since, first) = *start.get_or_insert((Instant::now(), copied));
+        let rate = (last_update.elapsed() >= UPDATE_INTERVAL).then(|| {
+            last_update = Instant::now();
+            ((copied - first) * 1000).checked_div(since.elapsed().as_millis() as u64).unwrap_or(0)
+        });
+        match queue.update(transfer.id, copied, rate) {
+            Some(limit) => {
+                bucket.set_rate(limit);
+                Ok(())
+            }
+            None => Err(io::Error::new(io::ErrorKind::Interrupted, "Transfer stopped"))
+        }
+    })?;
+    if transfer.move_source {
+        remote_fs::delete_tree(from, &entry)?;
+    }
+    remote.set_transfer_throttle(None);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    const SERVER: &str = "ftp://me@example.org:21";
+
+    fn transfer(direction: Direction, name: &str, server: &str) -> Transfer {
+        Transfer::new(direction, &Entry::new(name, EntryKind::File), PathBuf::from("/tmp"), "/", server, None)
+    }
+
+    #[test]
+    fn control_test() {
+        let queue = Queue::new();
+        queue.reopen(SERVER);
+        let a = queue.push(transfer(Direction::Download, "a.txt", SERVER));
+        let b = queue.push(transfer(Direction::Upload, "a.txt", SERVER));
+        // Transfers with other servers wait for a session with them
+        let other = queue.push(transfer(Direction::Upload, "a.txt", "sftp://me@example.org:22"));
+        queue.move_item(b, true);
+        assert_eq!(queue.items().iter().map(|t| t.id).collect::<Vec<_>>(), vec![b, a, other]);
+        queue.move_item(b, true);
+        queue.pause(b);
+
+        // Paused transfers are skipped
+        assert_eq!(queue.next().map(|t| t.id), Some(a));
+        queue.set_limit(a, 1000);
+        assert_eq!(queue.update(a, 10, None), Some(1000));
+        queue.complete(a, Err(ftp::Error::InvalidData));
+        assert!(matches!(queue.items()[1].state, State::Failed(_)));
+        queue.retry(a);
+        queue.resume(b);
+        assert_eq!(queue.next().map(|t| t.id), Some(b));
+        queue.cancel(b);
+        assert_eq!(queue.update(b, 10, None), None);
+        // What was transferred is kept, for resuming
+        assert_eq!(queue.next().map(|t| (t.id, t.transferred)), Some((a, 10)));
+        queue.close();
+        assert_eq!(queue.update(a, 10, None), None);
+        queue.complete(a, Err(ftp::Error::InvalidData));
+        assert_eq!(queue.items()[0].state, State::Queued);
+        assert_eq!((queue.next(), queue.finished()), (None, 2));
+    }
+
+    #[test]
+    fn persistence_test() -> io::Result<()> {
+        let dir = env::temp_dir().join(format!("termftp-state-{}", std::process::id()));
+        let (path, history) = (dir.join("queue.toml"), dir.join("history"));
+        let queue = Queue::persistent(path.clone(), history.clone());
+        queue.reopen(SERVER);
+        let a = queue.push(transfer(Direction::Download, "a.txt", SERVER));
+        let b = queue.push(transfer(Direction::Upload, "b.txt", SERVER));
+        queue.next();
+        queue.update(a, 5, None);
+        queue.complete(b, Ok(()));
+        queue.cancel(b);
+
+        // Only unfinished transfers are kept, the running one goes on from where it was
+        let saved = load(&path)?;
+        assert_eq!(saved.iter().map(|t| (t.name.as_str(), t.transferred)).collect::<Vec<_>>(), vec![("a.txt", 5)]);
+        let restored = Queue::new();
+        restored.restore(saved);
+        assert_eq!(restored.items()[0].state, State::Queued);
+
+        queue.complete(a, Err(ftp::Error::InvalidData));
+        assert!(load(&path)?.is_empty());
+        let lines = fs::read_to_string(&history)?;
+        let outcomes: Vec<&str> = lines.lines().map(|line| line.split('\t').nth(1).unwrap_or("")).collect();
+        assert_eq!(outcomes, vec!["cancelled", "failed"]);
+        assert!(lines.contains("\tdownload\tftp://me@example.org:21/a.txt\t/tmp/a.txt\t"));
+        assert!(queue.take_error().is_none());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            for file in [&path, &history] {
+                assert_eq!(fs::metadata(file)?.permissions().mode() & 0o777, 0o600);
+            }
+        }
+        fs::remove_dir_all(&dir)
+    }
+
+    #[test]
+    fn work_test() -> io::Result<()> {
+        let dir = env::temp_dir().join(format!("termftp-queue-{}", std::process::id()));
+        let server = dir.join("server");
+        fs::create_dir_all(dir.join("up"))?;
+        fs::create_dir_all(&server)?;
+        fs::write(dir.join("up/a.txt"), b"hello")?;
+
+        // The local disk stands in for the server
+        let queue = Queue::new();
+        queue.reopen(SERVER);
+        let entry = Entry::new("up", EntryKind::Directory);
+        let mut upload = Transfer::new(Direction::Upload, &entry, dir.clone(), &server.to_string_lossy(), SERVER, None);
+        upload.move_source = true;
+        queue.push(upload);
+        thread::scope(|scope| {
+            scope.spawn(|| work(&queue, || Ok(Box::new(LocalFs::new(dir.clone())) as Box<dyn RemoteFs>), |_| false));
+            while queue.finished() == 0 {
+                thread::sleep(Duration::from_millis(10));
+            }
+            queue.close();
+        });
+        let transfer = &queue.items()[0];
+        assert_eq!((&transfer.state, transfer.transferred), (&State::Done, 5));
+        assert!(!dir.join("up").exists());
+        assert_eq!(fs::read(server.join("up/a.txt"))?, b"hello");
+        fs::remove_dir_all(&dir)
+    }
+}
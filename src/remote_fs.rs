@@ -0,0 +1,933 @@
+use crate::ftp::{self, Connection, DataStream, TransferMode};
+use crate::listing;
+use crate::throttle::Throttle;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::DerefMut;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink
+}
+
+/// A directory entry. Backends fill in whatever metadata they know.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Entry {
+    pub name: String,
+    pub kind: EntryKind,
+    pub size: Option<u64>,
+    pub modified: Option<SystemTime>,
+    // In "drwxr-xr-x" form
+    pub permissions: Option<String>,
+    pub owner: Option<String>
+}
+
+impl Entry {
+    pub fn new(name: &str, kind: EntryKind) -> Entry {
+        Entry { name: name.to_string(), kind, size: None, modified: None, permissions: None, owner: None }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.kind == EntryKind::Directory
+    }
+}
+
+/// Data being read from a file. `finish` has to be called after the last byte to confirm the transfer succeeded;
+/// dropping the stream early cancels the transfer.
+pub trait ReadStream: Read {
+    fn finish(self: Box<Self>) -> ftp::Result<()>;
+}
+
+/// Data being written to a file. `finish` has to be called after the last byte to complete the file;
+/// dropping the stream early cancels the transfer.
+pub trait WriteStream: Write {
+    fn finish(self: Box<Self>) -> ftp::Result<()>;
+}
+
+/// A file system that can be browsed and transferred to and from, be it a server or the local disk.
+/// Paths are relative to the current directory unless they start with '/'.
+pub trait RemoteFs {
+    /// The entries of the current directory
+    fn list(&mut self) -> ftp::Result<Vec<Entry>>;
+    fn stat(&mut self, path: &str) -> ftp::Result<Entry>;
+    fn read<'a>(&'a mut self, path: &str) -> ftp::Result<Box<dyn ReadStream + 'a>>;
+    fn write<'a>(&'a mut self, path: &str) -> ftp::Result<Box<dyn WriteStream + 'a>>;
+    /// Reads `path` from byte `offset` on, to resume a transfer
+    fn read_from<'a>(&'a mut self, path: &str, offset: u64) -> ftp::Result<Box<dyn ReadStream + 'a>> {
+        match offset {
+            0 => self.read(path),
+            _ => Err(io::Error::new(io::ErrorKind::Unsupported, "Resuming transfers is not supported").into())
+        }
+    }
+    /// Writes `path` from byte `offset` on, keeping what comes before, to resume a transfer
+    fn write_from<'a>(&'a mut self, path: &str, offset: u64) -> ftp::Result<Box<dyn WriteStream + 'a>> {
+        match offset {
+            0 => self.write(path),
+            _ => Err(io::Error::new(io::ErrorKind::Unsupported, "Resuming transfers is not supported").into())
+        }
+    }
+    fn mkdir(&mut self, path: &str) -> ftp::Result<()>;
+    fn rmdir(&mut self, path: &str) -> ftp::Result<()>;
+    fn delete(&mut self, path: &str) -> ftp::Result<()>;
+    fn rename(&mut self, from: &str, to: &str) -> ftp::Result<()>;
+    /// Changes the current directory, ".." goes up one level
+    fn cwd(&mut self, path: &str) -> ftp::Result<()>;
+    /// The absolute path of the current directory
+    fn pwd(&mut self) -> ftp::Result<String>;
+    /// Sets the Unix permission bits of `path`, where the backend knows about them
+    fn chmod(&mut self, path: &str, mode: u32) -> ftp::Result<()> {
+        let _ = (path, mode);
+        Err(io::Error::new(io::ErrorKind::Unsupported, "Changing permissions is not supported").into())
+    }
+    /// Limits the transfers from now on with `throttle`, besides any limit shared by all of them.
+    /// Only FTP's data connections are throttled
+    fn set_transfer_throttle(&mut self, throttle: Option<Arc<Throttle>>) {
+        let _ = throttle;
+    }
+    /// Whether the following transfers convert line endings (FTP's ASCII type) or copy files unchanged.
+    /// Only FTP makes this distinction
+    fn set_ascii(&mut self, ascii: bool) -> ftp::Result<()> {
+        let _ = ascii;
+        Ok(())
+    }
+}
+
+/// Copies `from_path` on `from` to `to_path` on `to`, calling `progress` with the number of bytes copied so far.
+/// Returns the size of the file.
+pub fn copy<P>(from: &mut dyn RemoteFs, from_path: &str, to: &mut dyn RemoteFs, to_path: &str, progress: P) -> ftp::Result<u64>
+where
+    P: FnMut(u64) -> io::Result<()>
+{
+    copy_from(from, from_path, to, to_path, 0, progress)
+}
+
+/// Like `copy`, but goes on from byte `offset` of a partial copy. The bytes copied include the first `offset`
+pub fn copy_from<P>(from: &mut dyn RemoteFs, from_path: &str, to: &mut dyn RemoteFs, to_path: &str, offset: u64, mut progress: P) -> ftp::Result<u64>
+where
+    P: FnMut(u64) -> io::Result<()>
+{
+    let mut input = from.read_from(from_path, offset)?;
+    let mut output = to.write_from(to_path, offset)?;
+    let mut buf = [0u8; 16384];
+    let mut copied = offset;
+    loop {
+        let n = input.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        output.write_all(&buf[..n])?;
+        copied += n as u64;
+        progress(copied)?;
+    }
+    input.finish()?;
+    output.finish()?;
+    Ok(copied)
+}
+
+/// Copies `entry` from the current directory of `from` to the current directory of `to`, a directory with
+/// everything in it. `ascii` tells which files to transfer as text, `progress` gets the name of the file
+/// being copied and the bytes copied of all files so far. With `resume`, files already at the destination
+/// are taken for the start of the copy and completed. Returns the number of files copied
+pub fn copy_tree<A, P>(from: &mut dyn RemoteFs, entry: &Entry, to: &mut dyn RemoteFs, ascii: &A, resume: bool, progress: &mut P) -> ftp::Result<usize>
+where
+    A: Fn(&str) -> bool,
+    P: FnMut(&str, u64) -> io::Result<()>
+{
+    copy_entry(from, entry, to, ascii, resume, progress, &mut 0)
+}
+
+// copy_tree, with the bytes copied before
+fn copy_entry<A, P>(from: &mut dyn RemoteFs, entry: &Entry, to: &mut dyn RemoteFs, ascii: &A, resume: bool, progress: &mut P, total: &mut u64) -> ftp::Result<usize>
+where
+    A: Fn(&str) -> bool,
+    P: FnMut(&str, u64) -> io::Result<()>
+{
+    if !entry.is_dir() {
+        let text = ascii(&entry.name);
+        from.set_ascii(text)?;
+        to.set_ascii(text)?;
+        // Offsets only carry over in binary transfers, where nothing is converted
+        let offset = match (resume && !text, entry.size) {
+            (true, Some(size)) => to.stat(&entry.name).ok().and_then(|e| e.size).filter(|&done| done <= size).unwrap_or(0),
+            _ => 0
+        };
+        let before = *total;
+        *total += match offset {
+            done if done > 0 && Some(done) == entry.size => done,
+            offset => copy_from(from, &entry.name, to, &entry.name, offset, |copied| progress(&entry.name, before + copied))?
+        };
+        return Ok(1);
+    }
+    if let Err(e) = to.mkdir(&entry.name) {
+        // Copies into a directory of the same name
+        to.cwd(&entry.name).map_err(|_| e)?;
+        to.cwd("..")?;
+    }
+    from.cwd(&entry.name)?;
+    to.cwd(&entry.name)?;
+    let res = from.list().and_then(|entries| {
+        entries.iter().try_fold(0, |count, entry| Ok(count + copy_entry(from, entry, to, ascii, resume, progress, total)?))
+    });
+    from.cwd("..")?;
+    to.cwd("..")?;
+    res
+}
+
+/// Deletes `entry` from the current directory of `fs`, a directory with everything in it.
+/// Returns the number of files deleted
+pub fn delete_tree(fs: &mut dyn RemoteFs, entry: &Entry) -> ftp::Result<usize> {
+    if !entry.is_dir() {
+        fs.delete(&entry.name)?;
+        return Ok(1);
+    }
+    // A symlink to a directory can look like one, but goes like a file without touching what it points to
+    if fs.delete(&entry.name).is_ok() {
+        return Ok(1);
+    }
+    fs.cwd(&entry.name)?;
+    let res = fs.list().and_then(|entries| {
+        entries.iter().try_fold(0, |count, entry| Ok(count + delete_tree(fs, entry)?))
+    });
+    fs.cwd("..")?;
+    let count = res?;
+    fs.rmdir(&entry.name)?;
+    Ok(count)
+}
+
+// The last component of a slash separated path
+fn base_name(path: &str) -> &str {
+    path.trim_end_matches('/').rsplit('/').next().unwrap_or(path)
+}
+
+/// An FTP server, through a plain or pooled connection
+pub struct FtpFs<C: DerefMut<Target = Connection>> {
+    connection: C,
+    ascii: bool
+}
+
+impl<C: DerefMut<Target = Connection>> FtpFs<C> {
+    /// Switches the connection to binary mode, so files arrive unchanged
+    pub fn new(mut connection: C) -> ftp::Result<FtpFs<C>> {
+        connection.set_transfer_mode(TransferMode::Binary)?;
+        Ok(FtpFs { connection, ascii: false })
+    }
+
+    pub fn connection(&mut self) -> &mut Connection {
+        &mut self.connection
+    }
+}
+
+// A RETR or STOR in progress
+struct FtpTransfer<'a> {
+    connection: &'a mut Connection,
+    stream: Option<DataStream>
+}
+
+impl FtpTransfer<'_> {
+    fn complete(&mut self) -> ftp::Result<()> {
+        // Closing the data connection ends the transfer, then the server reports how it went.
+        // Flushing sets up the connection if nothing was transferred over it, as for an empty file
+        if let Some(stream) = &mut self.stream {
+            stream.flush()?;
+        }
+        self.stream = None;
+        self.connection.read_server_response()?;
+        Ok(())
+    }
+}
+
+impl Read for FtpTransfer<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.as_mut().map_or(Ok(0), |s| s.read(buf))
+    }
+}
+
+impl Write for FtpTransfer<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.as_mut().map_or(Ok(0), |s| s.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.as_mut().map_or(Ok(()), |s| s.flush())
+    }
+}
+
+impl ReadStream for FtpTransfer<'_> {
+    fn finish(mut self: Box<Self>) -> ftp::Result<()> {
+        self.complete()
+    }
+}
+
+impl WriteStream for FtpTransfer<'_> {
+    fn finish(mut self: Box<Self>) -> ftp::Result<()> {
+        self.complete()
+    }
+}
+
+impl Drop for FtpTransfer<'_> {
+    fn drop(&mut self) {
+        if self.stream.take().is_some() {
+            let _ = self.connection.abort();
+        }
+    }
+}
+
+impl<C: DerefMut<Target = Connection>> RemoteFs for FtpFs<C> {
+    fn list(&mut self) -> ftp::Result<Vec<Entry>> {
+        Ok(listing::parse_listing(&self.connection.get_detailed_listing()?))
+    }
+
+    fn stat(&mut self, path: &str) -> ftp::Result<Entry> {
+        match self.connection.get_remote_size(path) {
+            Ok(size) => {
+                let mut entry = Entry::new(base_name(path), EntryKind::File);
+                entry.size = Some(size);
+                entry.modified = self.connection.modification_time(path).ok();
+                Ok(entry)
+            }
+            Err(e) if e.response().is_some() => {
+                // SIZE is refused for directories, so see whether it can be entered
+                let current = self.connection.current_directory()?;
+                if self.connection.change_directory(path).is_err() {
+                    return Err(e);
+                }
+                self.connection.change_directory(&current)?;
+                Ok(Entry::new(base_name(path), EntryKind::Directory))
+            }
+            Err(e) => Err(e)
+        }
+    }
+
+    fn read<'a>(&'a mut self, path: &str) -> ftp::Result<Box<dyn ReadStream + 'a>> {
+        let stream = self.connection.retrieve(path)?;
+        Ok(Box::new(FtpTransfer { connection: &mut self.connection, stream: Some(stream) }))
+    }
+
+    fn write<'a>(&'a mut self, path: &str) -> ftp::Result<Box<dyn WriteStream + 'a>> {
+        let stream = self.connection.store(path)?;
+        Ok(Box::new(FtpTransfer { connection: &mut self.connection, stream: Some(stream) }))
+    }
+
+    fn read_from<'a>(&'a mut self, path: &str, offset: u64) -> ftp::Result<Box<dyn ReadStream + 'a>> {
+        let stream = self.connection.retrieve_from(path, offset)?;
+        Ok(Box::new(FtpTransfer { connection: &mut self.connection, stream: Some(stream) }))
+    }
+
+    fn write_from<'a>(&'a mut self, path: &str, offset: u64) -> ftp::Result<Box<dyn WriteStream + 'a>> {
+        // REST before STOR makes the server write from the offset on
+        let stream = self.connection.store_from(path, offset)?;
+        Ok(Box::new(FtpTransfer { connection: &mut self.connection, stream: Some(stream) }))
+    }
+
+    fn mkdir(&mut self, path: &str) -> ftp::Result<()> {
+        self.connection.make_directory(path)?;
+        Ok(())
+    }
+
+    fn rmdir(&mut self, path: &str) -> ftp::Result<()> {
+        self.connection.remove_directory(path)?;
+        Ok(())
+    }
+
+    fn delete(&mut self, path: &str) -> ftp::Result<()> {
+        self.connection.delete_file(path)?;
+        Ok(())
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> ftp::Result<()> {
+        self.connection.rename(from, to)?;
+        Ok(())
+    }
+
+    fn cwd(&mut self, path: &str) -> ftp::Result<()> {
+        if path == ".." {
+            self.connection.root_directory()?;
+        }
+        else {
+            self.connection.change_directory(path)?;
+        }
+        Ok(())
+    }
+
+    fn pwd(&mut self) -> ftp::Result<String> {
+        self.connection.current_directory()
+    }
+
+    fn set_transfer_throttle(&mut self, throttle: Option<Arc<Throttle>>) {
+        self.connection.set_transfer_throttle(throttle);
+    }
+
+    fn chmod(&mut self, path: &str, mode: u32) -> ftp::Result<()> {
+        self.connection.chmod(path, mode)?;
+        Ok(())
+    }
+
+    fn set_ascii(&mut self, ascii: bool) -> ftp::Result<()> {
+        if ascii != self.ascii {
+            self.connection.set_transfer_mode(if ascii { TransferMode::ASCII } else { TransferMode::Binary })?;
+            self.ascii = ascii;
+        }
+        Ok(())
+    }
+}
+
+/// The local disk
+#[derive(Clone)]
+pub struct LocalFs {
+    cwd: PathBuf
+}
+
+impl LocalFs {
+    pub fn new(cwd: PathBuf) -> LocalFs {
+        LocalFs { cwd }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.cwd
+    }
+
+    fn resolve(&self, path: &str) -> PathBuf {
+        self.cwd.join(path)
+    }
+}
+
+/// Formats Unix permission bits like `ls -l`, e.g. "drwxr-xr-x"
+pub fn mode_string(kind: EntryKind, mode: u32) -> String {
+    let kind = match kind {
+        EntryKind::File => '-',
+        EntryKind::Directory => 'd',
+        EntryKind::Symlink => 'l'
+    };
+    std::iter::once(kind)
+        .chain("rwxrwxrwx".chars().enumerate().map(|(i, c)| if mode & (0o400 >> i) != 0 { c } else { '-' }))
+        .collect()
+}
+
+/// The permission bits of a `mode_string`, with or without the leading type letter. None if it is not one
+pub fn parse_mode(permissions: &str) -> Option<u32> {
+    let bits: Vec<char> = permissions.chars().collect();
+    let bits = match bits.len() {
+        10 => &bits[1..],
+        9 => &bits[..],
+        _ => return None
+    };
+    bits.iter().zip("rwxrwxrwx".chars()).enumerate().try_fold(0, |mode, (i, (&bit, letter))| {
+        // Setuid, setgid and sticky bits are shown in place of x, lowercase if x is set as well
+        let special = match (i, bit) {
+            (2, 's' | 'S') => 0o4000,
+            (5, 's' | 'S') => 0o2000,
+            (8, 't' | 'T') => 0o1000,
+            _ => 0
+        };
+        match bit {
+            '-' => Some(mode),
+            _ if special != 0 && bit.is_lowercase() => Some(mode | special | 0o400 >> i),
+            _ if special != 0 => Some(mode | special),
+            _ if bit == letter => Some(mode | 0o400 >> i),
+            _ => None
+        }
+    })
+}
+
+#[cfg(unix)]
+fn permissions(kind: EntryKind, metadata: &Metadata) -> Option<String> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(mode_string(kind, metadata.permissions().mode()))
+}
+
+#[cfg(not(unix))]
+fn permissions(_kind: EntryKind, _metadata: &Metadata) -> Option<String> {
+    None
+}
+
+fn local_entry(name: &str, metadata: &Metadata) -> Entry {
+    let kind = if metadata.file_type().is_symlink() {
+        EntryKind::Symlink
+    }
+    else if metadata.is_dir() {
+        EntryKind::Directory
+    }
+    else {
+        EntryKind::File
+    };
+    Entry {
+        name: name.to_string(),
+        kind,
+        size: if metadata.is_dir() { None } else { Some(metadata.len()) },
+        modified: metadata.modified().ok(),
+        permissions: permissions(kind, metadata),
+        owner: None
+    }
+}
+
+struct LocalFile(File);
+
+impl Read for LocalFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for LocalFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl ReadStream for LocalFile {
+    fn finish(self: Box<Self>) -> ftp::Result<()> {
+        Ok(())
+    }
+}
+
+impl WriteStream for LocalFile {
+    fn finish(self: Box<Self>) -> ftp::Result<()> {
+        self.0.sync_all()?;
+        Ok(())
+    }
+}
+
+impl RemoteFs for LocalFs {
+    fn list(&mut self) -> ftp::Result<Vec<Entry>> {
+        let mut res = Vec::new();
+        for entry in fs::read_dir(&self.cwd)? {
+            let entry = entry?;
+            // Report what a symlink points to, unless it is dangling
+            let metadata = match fs::metadata(entry.path()) {
+                Ok(metadata) => metadata,
+                Err(_) => entry.metadata()?
+            };
+            res.push(local_entry(&entry.file_name().to_string_lossy(), &metadata));
+        }
+        res.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(res)
+    }
+
+    fn stat(&mut self, path: &str) -> ftp::Result<Entry> {
+        Ok(local_entry(base_name(path), &fs::metadata(self.resolve(path))?))
+    }
+
+    fn read<'a>(&'a mut self, path: &str) -> ftp::Result<Box<dyn ReadStream + 'a>> {
+        Ok(Box::new(LocalFile(File::open(self.resolve(path))?)))
+    }
+
+    fn write<'a>(&'a mut self, path: &str) -> ftp::Result<Box<dyn WriteStream + 'a>> {
+        Ok(Box::new(LocalFile(File::create(self.resolve(path))?)))
+    }
+
+    fn read_from<'a>(&'a mut self, path: &str, offset: u64) -> ftp::Result<Box<dyn ReadStream + 'a>> {
+        let mut file = File::open(self.resolve(path))?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(Box::new(LocalFile(file)))
+    }
+
+    fn write_from<'a>(&'a mut self, path: &str, offset: u64) -> ftp::Result<Box<dyn WriteStream + 'a>> {
+        let mut file = fs::OpenOptions::new().write(true).create(true).truncate(false).open(self.resolve(path))?;
+        file.set_len(offset)?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(Box::new(LocalFile(file)))
+    }
+
+    fn mkdir(&mut self, path: &str) -> ftp::Result<()> {
+        Ok(fs::create_dir(self.resolve(path))?)
+    }
+
+    fn rmdir(&mut self, path: &str) -> ftp::Result<()> {
+        Ok(fs::remove_dir(self.resolve(path))?)
+    }
+
+    fn delete(&mut self, path: &str) -> ftp::Result<()> {
+        Ok(fs::remove_file(self.resolve(path))?)
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> ftp::Result<()> {
+        Ok(fs::rename(self.resolve(from), self.resolve(to))?)
+    }
+
+    fn cwd(&mut self, path: &str) -> ftp::Result<()> {
+        let cwd = self.resolve(path).canonicalize()?;
+        if !cwd.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::NotADirectory, format!("{} is not a directory", cwd.display())).into());
+        }
+        self.cwd = cwd;
+        Ok(())
+    }
+
+    fn pwd(&mut self) -> ftp::Result<String> {
+        Ok(self.cwd.to_string_lossy().into_owned())
+    }
+
+    #[cfg(unix)]
+    fn chmod(&mut self, path: &str, mode: u32) -> ftp::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        Ok(fs::set_permissions(self.resolve(path), fs::Permissions::from_mode(mode))?)
+    }
+}
+
+enum Node {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, Node>)
+}
+
+/// A file system held in memory, for tests and as a stand-in before connecting
+pub struct MemoryFs {
+    root: Node,
+    cwd: Vec<String>
+}
+
+impl Default for MemoryFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn not_found(path: &str) -> ftp::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{}: no such file or directory", path)).into()
+}
+
+fn already_exists(path: &str) -> ftp::Error {
+    io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", path)).into()
+}
+
+impl MemoryFs {
+    pub fn new() -> MemoryFs {
+        MemoryFs { root: Node::Directory(BTreeMap::new()), cwd: Vec::new() }
+    }
+
+    // The components of `path` from the root, with "." and ".." resolved
+    fn components(&self, path: &str) -> Vec<String> {
+        let mut res = if path.starts_with('/') { Vec::new() } else { self.cwd.clone() };
+        for part in path.split('/') {
+            match part {
+                "" | "." => {}
+                ".." => { res.pop(); }
+                part => res.push(part.to_string())
+            }
+        }
+        res
+    }
+
+    fn node(&self, components: &[String]) -> Option<&Node> {
+        components.iter().try_fold(&self.root, |node, name| match node {
+            Node::Directory(children) => children.get(name),
+            Node::File(_) => None
+        })
+    }
+
+    // The directory holding the last component of `path`, and that component
+    fn parent(&mut self, path: &str) -> ftp::Result<(&mut BTreeMap<String, Node>, String)> {
+        let mut components = self.components(path);
+        let name = components.pop().ok_or_else(|| not_found(path))?;
+        let mut node = &mut self.root;
+        for component in &components {
+            node = match node {
+                Node::Directory(children) => children.get_mut(component).ok_or_else(|| not_found(path))?,
+                Node::File(_) => return Err(not_found(path))
+            };
+        }
+        match node {
+            Node::Directory(children) => Ok((children, name)),
+            Node::File(_) => Err(not_found(path))
+        }
+    }
+
+    fn entry(name: &str, node: &Node) -> Entry {
+        match node {
+            Node::File(data) => Entry { size: Some(data.len() as u64), ..Entry::new(name, EntryKind::File) },
+            Node::Directory(_) => Entry::new(name, EntryKind::Directory)
+        }
+    }
+}
+
+struct MemoryReader(Cursor<Vec<u8>>);
+
+impl Read for MemoryReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl ReadStream for MemoryReader {
+    fn finish(self: Box<Self>) -> ftp::Result<()> {
+        Ok(())
+    }
+}
+
+// The file only appears once it is finished
+struct MemoryWriter<'a> {
+    fs: &'a mut MemoryFs,
+    path: String,
+    data: Vec<u8>
+}
+
+impl Write for MemoryWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl WriteStream for MemoryWriter<'_> {
+    fn finish(self: Box<Self>) -> ftp::Result<()> {
+        let MemoryWriter { fs, path, data } = *self;
+        let (children, name) = fs.parent(&path)?;
+        children.insert(name, Node::File(data));
+        Ok(())
+    }
+}
+
+impl RemoteFs for MemoryFs {
+    fn list(&mut self) -> ftp::Result<Vec<Entry>> {
+        match self.node(&self.cwd) {
+            Some(Node::Directory(children)) => Ok(children.iter().map(|(name, node)| Self::entry(name, node)).collect()),
+            _ => Err(not_found("."))
+        }
+    }
+
+    fn stat(&mut self, path: &str) -> ftp::Result<Entry> {
+        let components = self.components(path);
+        let node = self.node(&components).ok_or_else(|| not_found(path))?;
+        Ok(Self::entry(components.last().map_or("/", |n| n.as_str()), node))
+    }
+
+    fn read<'a>(&'a mut self, path: &str) -> ftp::Result<Box<dyn ReadStream + 'a>> {
+        match self.node(&self.components(path)) {
+            Some(Node::File(data)) => Ok(Box::new(MemoryReader(Cursor::new(data.clone())))),
+            _ => Err(not_found(path))
+        }
+    }
+
+    fn write<'a>(&'a mut self, path: &str) -> ftp::Result<Box<dyn WriteStream + 'a>> {
+        let (children, name) = self.parent(path)?;
+        if let Some(Node::Directory(_)) = children.get(&name) {
+            return Err(already_exists(path));
+        }
+        Ok(Box::new(MemoryWriter { fs: self, path: path.to_string(), data: Vec::new() }))
+    }
+
+    fn mkdir(&mut self, path: &str) -> ftp::Result<()> {
+        let (children, name) = self.parent(path)?;
+        if children.contains_key(&name) {
+            return Err(already_exists(path));
+        }
+        children.insert(name, Node::Directory(BTreeMap::new()));
+        Ok(())
+    }
+
+    fn rmdir(&mut self, path: &str) -> ftp::Result<()> {
+        let (children, name) = self.parent(path)?;
+        match children.get(&name) {
+            Some(Node::Directory(entries)) if entries.is_empty() => {
+                children.remove(&name);
+                Ok(())
+            }
+            Some(Node::Directory(_)) => Err(io::Error::new(io::ErrorKind::DirectoryNotEmpty, format!("{} is not empty", path)).into()),
+            _ => Err(not_found(path))
+        }
+    }
+
+    fn delete(&mut self, path: &str) -> ftp::Result<()> {
+        let (children, name) = self.parent(path)?;
+        match children.get(&name) {
+            Some(Node::File(_)) => {
+                children.remove(&name);
+                Ok(())
+            }
+            _ => Err(not_found(path))
+        }
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> ftp::Result<()> {
+        let (children, name) = self.parent(from)?;
+        let node = children.remove(&name).ok_or_else(|| not_found(from))?;
+        let (children, name) = self.parent(to)?;
+        children.insert(name, node);
+        Ok(())
+    }
+
+    fn cwd(&mut self, path: &str) -> ftp::Result<()> {
+        let components = self.components(path);
+        match self.node(&components) {
+            Some(Node::Directory(_)) => {
+                self.cwd = components;
+                Ok(())
+            }
+            _ => Err(not_found(path))
+        }
+    }
+
+    fn pwd(&mut self) -> ftp::Result<String> {
+        Ok(format!("/{}", self.cwd.join("/")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put(fs: &mut dyn RemoteFs, path: &str, data: &[u8]) {
+        let mut output = fs.write(path).unwrap();
+        output.write_all(data).unwrap();
+        output.finish().unwrap();
+    }
+
+    fn get(fs: &mut dyn RemoteFs, path: &str) -> Vec<u8> {
+        let mut input = fs.read(path).unwrap();
+        let mut res = Vec::new();
+        input.read_to_end(&mut res).unwrap();
+        input.finish().unwrap();
+        res
+    }
+
+    fn names(fs: &mut dyn RemoteFs) -> Vec<String> {
+        fs.list().unwrap().into_iter().map(|e| e.name).collect()
+    }
+
+    // Runs the same operations against any backend
+    fn exercise(fs: &mut dyn RemoteFs) {
+        fs.mkdir("dir").unwrap();
+        put(fs, "dir/a.txt", b"hello");
+        assert!(fs.mkdir("dir").is_err());
+        assert!(fs.rmdir("dir").is_err());
+
+        fs.cwd("dir").unwrap();
+        assert!(fs.pwd().unwrap().ends_with("/dir"));
+        assert_eq!(names(fs), vec!["a.txt"]);
+        let entry = fs.stat("a.txt").unwrap();
+        assert_eq!((entry.kind, entry.size), (EntryKind::File, Some(5)));
+        assert_eq!(get(fs, "a.txt"), b"hello");
+
+        fs.rename("a.txt", "b.txt").unwrap();
+        assert_eq!(names(fs), vec!["b.txt"]);
+        assert!(fs.read("a.txt").is_err());
+        fs.delete("b.txt").unwrap();
+        assert!(names(fs).is_empty());
+
+        fs.cwd("..").unwrap();
+        assert!(fs.stat("dir").unwrap().is_dir());
+        fs.rmdir("dir").unwrap();
+        assert!(fs.cwd("dir").is_err());
+    }
+
+    #[test]
+    fn mode_test() {
+        assert_eq!(mode_string(EntryKind::Directory, 0o750), "drwxr-x---");
+        assert_eq!(parse_mode("drwxr-x---"), Some(0o750));
+        assert_eq!(parse_mode("rw-r--r--"), Some(0o644));
+        assert_eq!(parse_mode("-rwsr-xr-t"), Some(0o5755));
+        assert_eq!(parse_mode("drwxr-S--T"), Some(0o3740));
+        assert_eq!(parse_mode("-rwtr--r--"), None);
+        assert_eq!(parse_mode("-rw-r--r--+"), None);
+        assert_eq!(parse_mode("drwqr-x---"), None);
+    }
+
+    #[test]
+    fn memory_test() {
+        let mut fs = MemoryFs::new();
+        exercise(&mut fs);
+        assert_eq!(fs.pwd().unwrap(), "/");
+    }
+
+    #[test]
+    fn unfinished_write_test() {
+        let mut fs = MemoryFs::new();
+        {
+            let mut output = fs.write("partial").unwrap();
+            output.write_all(b"data").unwrap();
+        }
+        assert!(fs.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn local_test() {
+        let dir = std::env::temp_dir().join(format!("termftp-remote-fs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut local = LocalFs::new(dir.clone());
+        exercise(&mut local);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn copy_test() {
+        let mut source = MemoryFs::new();
+        let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        put(&mut source, "big.bin", &data);
+
+        let mut destination = MemoryFs::new();
+        destination.mkdir("in").unwrap();
+        let mut reported = 0;
+        let copied = copy(&mut source, "big.bin", &mut destination, "/in/big.bin", |n| {
+            reported = n;
+            Ok(())
+        }).unwrap();
+        assert_eq!((copied, reported), (data.len() as u64, data.len() as u64));
+        assert_eq!(get(&mut destination, "in/big.bin"), data);
+    }
+
+    #[test]
+    fn tree_test() {
+        let mut source = MemoryFs::new();
+        source.mkdir("dir").unwrap();
+        source.mkdir("dir/empty").unwrap();
+        put(&mut source, "dir/a.txt", b"a");
+        put(&mut source, "dir/b.txt", b"b");
+        let dir = source.stat("dir").unwrap();
+
+        let mut destination = MemoryFs::new();
+        destination.mkdir("dir").unwrap();
+        let (mut files, mut total) = (Vec::new(), 0);
+        let copied = copy_tree(&mut source, &dir, &mut destination, &|_| false, false, &mut |name, copied| {
+            files.push(name.to_string());
+            total = copied;
+            Ok(())
+        }).unwrap();
+        assert_eq!((copied, total), (2, 2));
+        files.dedup();
+        assert_eq!(files, vec!["a.txt", "b.txt"]);
+        assert_eq!(get(&mut destination, "dir/b.txt"), b"b");
+        assert!(destination.stat("dir/empty").unwrap().is_dir());
+        assert_eq!(destination.pwd().unwrap(), source.pwd().unwrap());
+
+        assert_eq!(delete_tree(&mut source, &dir).unwrap(), 2);
+        assert!(source.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn resume_test() -> io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("termftp-resume-{}", std::process::id()));
+        fs::create_dir_all(dir.join("from/tree"))?;
+        fs::create_dir_all(dir.join("to/tree"))?;
+        let data: Vec<u8> = (0..100).collect();
+        fs::write(dir.join("from/tree/partial.bin"), &data)?;
+        fs::write(dir.join("from/tree/whole.bin"), &data)?;
+        fs::write(dir.join("to/tree/partial.bin"), &data[..40])?;
+        fs::write(dir.join("to/tree/whole.bin"), &data)?;
+
+        let (mut source, mut destination) = (LocalFs::new(dir.join("from")), LocalFs::new(dir.join("to")));
+        let tree = source.stat("tree").unwrap();
+        let mut reported = Vec::new();
+        copy_tree(&mut source, &tree, &mut destination, &|_| false, true, &mut |name, copied| {
+            reported.push((name.to_string(), copied));
+            Ok(())
+        }).unwrap();
+        // Only the rest of the partial file is transferred
+        assert_eq!(reported, vec![("partial.bin".to_string(), 100)]);
+        assert_eq!(fs::read(dir.join("to/tree/partial.bin"))?, data);
+        fs::remove_dir_all(&dir)
+    }
+}
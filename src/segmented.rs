@@ -0,0 +1,139 @@
+use crate::ftp::{self, Connection, TransferMode};
+use std::fs::{File, OpenOptions};
+use std::ops::DerefMut;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How many times a single segment is retried after a transient error before the whole download fails
+const MAX_RETRIES: usize = 3;
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+pub struct Progress {
+    pub received: u64,
+    pub total: u64,
+    pub connections: usize,
+    pub elapsed: Duration
+}
+
+impl Progress {
+    pub fn percent(&self) -> u64 {
+        (self.received * 100).checked_div(self.total).unwrap_or(100)
+    }
+
+    // Bytes per second since the download started
+    pub fn rate(&self) -> u64 {
+        (self.received * 1000).checked_div(self.elapsed.as_millis() as u64).unwrap_or(0)
+    }
+}
+
+#[cfg(unix)]
+fn write_at(file: &File, data: &[u8], offset: u64) -> std::io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.write_all_at(data, offset)
+}
+
+#[cfg(windows)]
+fn write_at(file: &File, mut data: &[u8], mut offset: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !data.is_empty() {
+        let n = file.seek_write(data, offset)?;
+        data = &data[n..];
+        offset += n as u64;
+    }
+    Ok(())
+}
+
+// Fetches [*offset, end) over a fresh connection, advancing `offset` as data is written
+fn receive_segment<C, T>(connect: &C, filename: &str, file: &File, offset: &mut u64, end: u64, size: u64, received: &AtomicU64) -> ftp::Result<()>
+where
+    C: Fn() -> ftp::Result<T>,
+    T: DerefMut<Target = Connection>
+{
+    let mut ftp = connect()?;
+    ftp.set_transfer_mode(TransferMode::Binary)?;
+
+    // The last segment runs to the end of the file, the others abort at their range end
+    let length = if end == size { None } else { Some(end - *offset) };
+    ftp.receive_range(filename, *offset, length, |data| {
+        write_at(file, data, *offset)?;
+        *offset += data.len() as u64;
+        received.fetch_add(data.len() as u64, Ordering::Relaxed);
+        Ok(())
+    })?;
+
+    if *offset < end {
+        // The server closed the data connection early
+        Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into())
+    }
+    else {
+        Ok(())
+    }
+}
+
+fn fetch_segment<C, T>(connect: &C, filename: &str, file: &File, begin: u64, end: u64, size: u64, received: &AtomicU64) -> ftp::Result<()>
+where
+    C: Fn() -> ftp::Result<T>,
+    T: DerefMut<Target = Connection>
+{
+    let mut offset = begin;
+    let mut attempt = 0;
+    loop {
+        // A retry resumes from wherever the failed attempt stopped
+        match receive_segment(connect, filename, file, &mut offset, end, size, received) {
+            Ok(()) => return Ok(()),
+            Err(e) if e.is_transient() && attempt < MAX_RETRIES => attempt += 1,
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+/// Downloads `filename` of `size` bytes into `destination` over `segments` parallel connections. The connections can be
+/// in any directory, so `filename` is best an absolute path.
+/// Every segment obtains its own logged-in connection through `connect` and fetches a byte range with REST + RETR,
+/// writing it into a preallocated file. `progress` is called periodically from the calling thread.
+pub fn download<C, T, P>(connect: C, filename: &str, size: u64, destination: &Path, segments: usize, mut progress: P) -> ftp::Result<()>
+where
+    C: Fn() -> ftp::Result<T> + Sync,
+    T: DerefMut<Target = Connection>,
+    P: FnMut(&Progress)
+{
+    let file = OpenOptions::new().write(true).create(true).truncate(true).open(destination)?;
+    file.set_len(size)?;
+
+    let segments = segments.clamp(1, size.max(1) as usize) as u64;
+    let segment_len = size.div_ceil(segments);
+    let ranges: Vec<(u64, u64)> = (0..segments)
+        .map(|i| (i * segment_len, ((i + 1) * segment_len).min(size)))
+        .filter(|(begin, end)| begin < end)
+        .collect();
+
+    let received = AtomicU64::new(0);
+    let start = Instant::now();
+    let report = |received: &AtomicU64| Progress {
+        received: received.load(Ordering::Relaxed),
+        total: size,
+        connections: ranges.len(),
+        elapsed: start.elapsed()
+    };
+
+    thread::scope(|s| {
+        let (connect, file, received) = (&connect, &file, &received);
+        let handles: Vec<_> = ranges.iter()
+            .map(|&(begin, end)| s.spawn(move || fetch_segment(connect, filename, file, begin, end, size, received)))
+            .collect();
+
+        while !handles.iter().all(|h| h.is_finished()) {
+            progress(&report(received));
+            thread::sleep(PROGRESS_INTERVAL);
+        }
+
+        handles.into_iter()
+            .map(|h| h.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+            .collect::<ftp::Result<Vec<()>>>()
+    })?;
+
+    progress(&report(&received));
+    Ok(())
+}
@@ -0,0 +1,320 @@
+use crate::ftp::{self, Context, Error};
+use crate::proxy::{self, Connector};
+use crate::remote_fs::{self, Entry, EntryKind, ReadStream, RemoteFs, WriteStream};
+use ssh2::{CheckResult, FileStat, HashType, KnownHostFileKind, OpenFlags, OpenType, Session, Sftp};
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+use zeroize::Zeroizing;
+
+pub const DEFAULT_PORT: u16 = 22;
+// For every blocking call, so a silent server cannot hang the session
+const TIMEOUT_MS: u32 = 30_000;
+
+/// A way of logging in, tried in the order given until one is accepted
+pub enum Auth {
+    Password(Zeroizing<String>),
+    Key { private_key: PathBuf, passphrase: Option<String> },
+    /// The first identity offered by a running ssh-agent
+    Agent
+}
+
+/// What to do about a host key
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum HostKeyPolicy {
+    /// Only accept keys listed in known_hosts
+    Strict,
+    /// Also accept an unknown key with this fingerprint, and add it to known_hosts
+    AcceptFingerprint(String)
+}
+
+/// The user's ~/.ssh/known_hosts
+pub fn default_known_hosts() -> Option<PathBuf> {
+    home::home_dir().map(|home| home.join(".ssh").join("known_hosts"))
+}
+
+/// Agent authentication and the usual key files in ~/.ssh, then the password if there is one
+pub fn default_auths(password: &str) -> Vec<Auth> {
+    let mut res = vec![Auth::Agent];
+    if let Some(home) = home::home_dir() {
+        for name in ["id_ed25519", "id_ecdsa", "id_rsa"] {
+            let private_key = home.join(".ssh").join(name);
+            if private_key.exists() {
+                res.push(Auth::Key { private_key, passphrase: None });
+            }
+        }
+    }
+    if !password.is_empty() {
+        res.push(Auth::Password(Zeroizing::new(password.to_string())));
+    }
+    res
+}
+
+fn context(command: &str, path: Option<&str>) -> Context {
+    Context { command: command.to_string(), path: path.map(str::to_string) }
+}
+
+fn ssh_error(command: &str, path: Option<&str>) -> impl FnOnce(ssh2::Error) -> Error {
+    let context = context(command, path);
+    move |source| Error::Ssh { context, source }
+}
+
+// How known_hosts names a host: just the name on the default port, "[host]:port" otherwise
+fn known_host_name(host: &str, port: u16) -> String {
+    if port == DEFAULT_PORT { host.to_string() } else { format!("[{}]:{}", host, port) }
+}
+
+// The key a server presented
+struct HostKey<'a> {
+    key: &'a [u8],
+    format: ssh2::KnownHostKeyFormat,
+    fingerprint: String
+}
+
+/// Checks the host key against the known_hosts file, adding it if the policy accepts its fingerprint
+fn check_host_key(session: &Session, known_hosts: &Path, host: &str, port: u16, host_key: &HostKey, policy: &HostKeyPolicy) -> ftp::Result<()> {
+    let HostKey { key, format, fingerprint } = host_key;
+    let mut hosts = session.known_hosts().map_err(ssh_error("Check host key", None))?;
+    if known_hosts.exists() {
+        hosts.read_file(known_hosts, KnownHostFileKind::OpenSSH).map_err(ssh_error("Check host key", None))?;
+    }
+    match hosts.check_port(host, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(Error::HostKeyMismatch { host: host.to_string(), fingerprint: fingerprint.to_string() }),
+        CheckResult::NotFound if *policy == HostKeyPolicy::AcceptFingerprint(fingerprint.to_string()) => {
+            hosts.add(&known_host_name(host, port), key, "added by termftp", *format).map_err(ssh_error("Add host key", None))?;
+            if let Some(dir) = known_hosts.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            hosts.write_file(known_hosts, KnownHostFileKind::OpenSSH).map_err(ssh_error("Add host key", None))?;
+            Ok(())
+        }
+        CheckResult::NotFound | CheckResult::Failure => Err(Error::UnknownHostKey { host: host.to_string(), fingerprint: fingerprint.to_string() })
+    }
+}
+
+/// A server reached over SFTP
+pub struct SftpFs {
+    // Keeps the SSH connection alive for `sftp`
+    _session: Session,
+    sftp: Sftp,
+    cwd: String
+}
+
+impl SftpFs {
+    /// Connects to `host` with `connector`, verifies its key against `known_hosts` and logs in with the first of `auths` that works.
+    /// Starts in the user's home directory.
+    pub fn connect(connector: &dyn Connector, host: &str, port: u16, user: &str, auths: &[Auth], known_hosts: &Path, policy: &HostKeyPolicy) -> ftp::Result<SftpFs> {
+        let stream = connector.connect(&format!("{}:{}", host, port))?;
+        let mut session = Session::new().map_err(ssh_error("Connect", None))?;
+        session.set_tcp_stream(stream);
+        session.set_timeout(TIMEOUT_MS);
+        session.handshake().map_err(ssh_error("Connect", None))?;
+
+        let (key, key_type) = session.host_key().ok_or(Error::InvalidData)?;
+        let fingerprint = format!("SHA256:{}", proxy::base64(session.host_key_hash(HashType::Sha256).ok_or(Error::InvalidData)?).trim_end_matches('='));
+        check_host_key(&session, known_hosts, host, port, &HostKey { key, format: key_type.into(), fingerprint }, policy)?;
+
+        for auth in auths {
+            // A refused method is no error as long as another one is accepted
+            let _ = match auth {
+                Auth::Password(password) => session.userauth_password(user, password.as_str()),
+                Auth::Key { private_key, passphrase } => session.userauth_pubkey_file(user, None, private_key, passphrase.as_deref()),
+                Auth::Agent => session.userauth_agent(user)
+            };
+            if session.authenticated() {
+                break;
+            }
+        }
+        if !session.authenticated() {
+            return Err(Error::AuthenticationFailed);
+        }
+
+        let sftp = session.sftp().map_err(ssh_error("Start SFTP", None))?;
+        let cwd = sftp.realpath(Path::new(".")).map_err(ssh_error("Start SFTP", None))?.to_string_lossy().into_owned();
+        Ok(SftpFs { _session: session, sftp, cwd })
+    }
+
+    fn resolve(&self, path: &str) -> PathBuf {
+        if path.starts_with('/') {
+            PathBuf::from(path)
+        }
+        else {
+            Path::new(&self.cwd).join(path)
+        }
+    }
+}
+
+fn sftp_entry(name: &str, stat: &FileStat) -> Entry {
+    let file_type = stat.file_type();
+    let kind = if file_type.is_symlink() {
+        EntryKind::Symlink
+    }
+    else if file_type.is_dir() {
+        EntryKind::Directory
+    }
+    else {
+        EntryKind::File
+    };
+    Entry {
+        name: name.to_string(),
+        kind,
+        size: if kind == EntryKind::Directory { None } else { stat.size },
+        modified: stat.mtime.map(|t| UNIX_EPOCH + Duration::from_secs(t)),
+        permissions: stat.perm.map(|mode| remote_fs::mode_string(kind, mode)),
+        owner: stat.uid.map(|uid| uid.to_string())
+    }
+}
+
+struct SftpFile(ssh2::File);
+
+impl Read for SftpFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for SftpFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl ReadStream for SftpFile {
+    fn finish(self: Box<Self>) -> ftp::Result<()> {
+        Ok(())
+    }
+}
+
+impl WriteStream for SftpFile {
+    fn finish(mut self: Box<Self>) -> ftp::Result<()> {
+        // The server may only report a failed write when the handle is closed
+        self.0.close().map_err(ssh_error("Close", None))
+    }
+}
+
+impl RemoteFs for SftpFs {
+    fn list(&mut self) -> ftp::Result<Vec<Entry>> {
+        let mut res: Vec<Entry> = self.sftp.readdir(Path::new(&self.cwd)).map_err(ssh_error("List", Some(&self.cwd)))?.iter()
+            .map(|(path, stat)| sftp_entry(&path.file_name().unwrap_or_default().to_string_lossy(), stat))
+            .collect();
+        res.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(res)
+    }
+
+    fn stat(&mut self, path: &str) -> ftp::Result<Entry> {
+        let stat = self.sftp.stat(&self.resolve(path)).map_err(ssh_error("Stat", Some(path)))?;
+        let name = path.trim_end_matches('/').rsplit('/').next().unwrap_or(path);
+        Ok(sftp_entry(name, &stat))
+    }
+
+    fn read<'a>(&'a mut self, path: &str) -> ftp::Result<Box<dyn ReadStream + 'a>> {
+        Ok(Box::new(SftpFile(self.sftp.open(self.resolve(path)).map_err(ssh_error("Get", Some(path)))?)))
+    }
+
+    fn write<'a>(&'a mut self, path: &str) -> ftp::Result<Box<dyn WriteStream + 'a>> {
+        Ok(Box::new(SftpFile(self.sftp.create(&self.resolve(path)).map_err(ssh_error("Put", Some(path)))?)))
+    }
+
+    fn read_from<'a>(&'a mut self, path: &str, offset: u64) -> ftp::Result<Box<dyn ReadStream + 'a>> {
+        let mut file = self.sftp.open(self.resolve(path)).map_err(ssh_error("Get", Some(path)))?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(Box::new(SftpFile(file)))
+    }
+
+    fn write_from<'a>(&'a mut self, path: &str, offset: u64) -> ftp::Result<Box<dyn WriteStream + 'a>> {
+        // Opened without truncating, then written from the offset on
+        let mut file = self.sftp.open_mode(self.resolve(path), OpenFlags::WRITE | OpenFlags::CREATE, 0o644, OpenType::File)
+            .map_err(ssh_error("Put", Some(path)))?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(Box::new(SftpFile(file)))
+    }
+
+    fn mkdir(&mut self, path: &str) -> ftp::Result<()> {
+        self.sftp.mkdir(&self.resolve(path), 0o755).map_err(ssh_error("Mkdir", Some(path)))
+    }
+
+    fn rmdir(&mut self, path: &str) -> ftp::Result<()> {
+        self.sftp.rmdir(&self.resolve(path)).map_err(ssh_error("Rmdir", Some(path)))
+    }
+
+    fn delete(&mut self, path: &str) -> ftp::Result<()> {
+        self.sftp.unlink(&self.resolve(path)).map_err(ssh_error("Remove", Some(path)))
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> ftp::Result<()> {
+        self.sftp.rename(&self.resolve(from), &self.resolve(to), None).map_err(ssh_error("Rename", Some(from)))
+    }
+
+    fn cwd(&mut self, path: &str) -> ftp::Result<()> {
+        let cwd = self.sftp.realpath(&self.resolve(path)).map_err(ssh_error("Change directory", Some(path)))?;
+        if !self.sftp.stat(&cwd).map_err(ssh_error("Change directory", Some(path)))?.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::NotADirectory, format!("{} is not a directory", path)).into());
+        }
+        self.cwd = cwd.to_string_lossy().into_owned();
+        Ok(())
+    }
+
+    fn pwd(&mut self) -> ftp::Result<String> {
+        Ok(self.cwd.clone())
+    }
+
+    fn chmod(&mut self, path: &str, mode: u32) -> ftp::Result<()> {
+        let stat = FileStat { size: None, uid: None, gid: None, perm: Some(mode), atime: None, mtime: None };
+        self.sftp.setstat(&self.resolve(path), stat).map_err(ssh_error("Chmod", Some(path)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_hosts_test() {
+        let file = std::env::temp_dir().join(format!("termftp-known-hosts-{}", std::process::id()));
+        let _ = fs::remove_file(&file);
+        let session = Session::new().unwrap();
+        let key = b"\x00\x00\x00\x0bssh-ed25519\x00\x00\x00\x20abcdefghijklmnopqrstuvwxyz012345";
+        let check = |key: &[u8], policy: &HostKeyPolicy| {
+            let host_key = HostKey { key, format: ssh2::KnownHostKeyFormat::Ed25519, fingerprint: "SHA256:abc".to_string() };
+            check_host_key(&session, &file, "example.org", 2222, &host_key, policy)
+        };
+
+        assert!(matches!(check(key, &HostKeyPolicy::Strict), Err(Error::UnknownHostKey { .. })));
+        // Only the fingerprint the user saw is accepted
+        assert!(matches!(check(key, &HostKeyPolicy::AcceptFingerprint("SHA256:other".to_string())), Err(Error::UnknownHostKey { .. })));
+        check(key, &HostKeyPolicy::AcceptFingerprint("SHA256:abc".to_string())).unwrap();
+        assert!(fs::read_to_string(&file).unwrap().starts_with("[example.org]:2222 ssh-ed25519 "));
+
+        check(key, &HostKeyPolicy::Strict).unwrap();
+        let mut changed = key.to_vec();
+        changed[20] ^= 1;
+        assert!(matches!(check(&changed, &HostKeyPolicy::Strict), Err(Error::HostKeyMismatch { .. })));
+        fs::remove_file(&file).unwrap();
+    }
+
+    // Needs an OpenSSH sshd on localhost that knows the current user's key, and its host key in known_hosts
+    #[test]
+    #[ignore]
+    fn local_sshd_test() -> ftp::Result<()> {
+        let user = std::env::var("USER").unwrap_or_else(|_| "root".to_string());
+        let mut sftp = SftpFs::connect(&proxy::Direct, "localhost", DEFAULT_PORT, &user, &default_auths(""), &default_known_hosts().unwrap(), &HostKeyPolicy::Strict)?;
+        let _ = sftp.delete("termftp_test_file");
+        {
+            let mut output = sftp.write("termftp_test_file")?;
+            output.write_all(b"This is a test file")?;
+            output.finish()?;
+        }
+        assert_eq!(sftp.stat("termftp_test_file")?.size, Some(19));
+        sftp.chmod("termftp_test_file", 0o600)?;
+        assert!(sftp.list()?.iter().any(|e| e.name == "termftp_test_file" && e.permissions.as_deref() == Some("-rw-------")));
+        sftp.rename("termftp_test_file", "termftp_test_file2")?;
+        sftp.delete("termftp_test_file2")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,476 @@
+use crate::ftp::{Encoding, ProxyLogin};
+use crate::proxy::{self, Connector};
+use crate::tls::TlsMode;
+use crate::url::{Protocol, Url};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferType {
+    /// Text, with line endings converted by the server
+    Ascii,
+    Binary
+}
+
+/// Transfers files whose name matches `pattern` ("*" and "?" wildcards) as `type`
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct TransferRule {
+    pub pattern: String,
+    #[serde(rename = "type")]
+    pub transfer_type: TransferType
+}
+
+/// The login schemes of FTP proxies, see `ProxyLogin`
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProxyScheme {
+    #[default]
+    UserAtHost,
+    UserAtHostAfterLogon,
+    Site,
+    Open,
+    Transparent,
+    UserAtProxyUserAtHost
+}
+
+impl ProxyScheme {
+    pub const ALL: [ProxyScheme; 6] = [
+        ProxyScheme::UserAtHost, ProxyScheme::UserAtHostAfterLogon, ProxyScheme::Site,
+        ProxyScheme::Open, ProxyScheme::Transparent, ProxyScheme::UserAtProxyUserAtHost
+    ];
+
+    /// The name in the site file and the site manager
+    pub fn name(self) -> &'static str {
+        match self {
+            ProxyScheme::UserAtHost => "user-at-host",
+            ProxyScheme::UserAtHostAfterLogon => "user-at-host-after-logon",
+            ProxyScheme::Site => "site",
+            ProxyScheme::Open => "open",
+            ProxyScheme::Transparent => "transparent",
+            ProxyScheme::UserAtProxyUserAtHost => "user-at-proxy-user-at-host"
+        }
+    }
+
+    /// Whether the proxy wants a user and password of its own
+    pub fn logs_in(self) -> bool {
+        self != ProxyScheme::UserAtHost
+    }
+}
+
+/// An FTP proxy that the control connection goes to, logging in to the server behind it
+#[derive(Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FtpProxy {
+    pub scheme: ProxyScheme,
+    /// "host:port" of the proxy
+    pub address: String,
+    pub user: Option<String>,
+    /// Only kept when the file allows plaintext passwords
+    pub password: Option<String>
+}
+
+impl FtpProxy {
+    /// Parses "scheme [user@]host[:port]", the port defaulting to 21
+    pub fn parse(text: &str) -> Result<FtpProxy, String> {
+        let (scheme, rest) = text.split_once(char::is_whitespace).ok_or_else(|| format!("\"{}\" is not scheme [user@]host[:port]", text))?;
+        let scheme = ProxyScheme::ALL.into_iter()
+            .find(|s| s.name().eq_ignore_ascii_case(scheme))
+            .ok_or_else(|| format!("Unknown proxy login \"{}\"", scheme))?;
+        let (user, host) = match rest.trim().rsplit_once('@') {
+            Some((user, host)) => (optional(user), host),
+            None => (None, rest.trim())
+        };
+        let address = if host.contains(':') { host.to_string() } else { format!("{}:21", host) };
+        Ok(FtpProxy { scheme, address, user, password: None })
+    }
+
+    pub fn text(&self) -> String {
+        match &self.user {
+            Some(user) => format!("{} {}@{}", self.scheme.name(), user, self.address),
+            None => format!("{} {}", self.scheme.name(), self.address)
+        }
+    }
+
+    /// How to log in to the server at `host` through the proxy
+    pub fn login(&self, host: &str) -> ProxyLogin {
+        let host = host.to_string();
+        let (proxy_user, proxy_password) = (self.user.clone().unwrap_or_default(), self.password.clone().unwrap_or_default());
+        match self.scheme {
+            ProxyScheme::UserAtHost => ProxyLogin::UserAtHost { host },
+            ProxyScheme::UserAtHostAfterLogon => ProxyLogin::UserAtHostAfterLogon { host, proxy_user, proxy_password },
+            ProxyScheme::Site => ProxyLogin::Site { host, proxy_user, proxy_password },
+            ProxyScheme::Open => ProxyLogin::Open { host, proxy_user, proxy_password },
+            ProxyScheme::Transparent => ProxyLogin::Transparent { proxy_user, proxy_password },
+            ProxyScheme::UserAtProxyUserAtHost => ProxyLogin::UserAtProxyUserAtHost { host, proxy_user, proxy_password }
+        }
+    }
+}
+
+/// A saved connection profile
+#[derive(Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Site {
+    pub name: String,
+    pub host: String,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    /// Only kept when the file allows plaintext passwords
+    pub password: Option<String>,
+    /// The entry of the credential vault holding the password
+    pub vault_entry: Option<String>,
+    pub protocol: Protocol,
+    pub tls: Option<TlsMode>,
+    pub active: bool,
+    pub encoding: Encoding,
+    pub local_dir: Option<PathBuf>,
+    pub remote_dir: Option<String>,
+    pub transfer_types: Vec<TransferRule>,
+    /// A SOCKS5 or HTTP proxy to connect through, like "socks5://host:1080". ALL_PROXY is used without one
+    pub proxy: Option<String>,
+    pub ftp_proxy: Option<FtpProxy>
+}
+
+/// The fields of the site manager's form, the password last as it is only asked for when it can be saved
+pub const FIELDS: [&str; 14] = [
+    "Name", "Host", "Port", "User", "Protocol (ftp/sftp)", "TLS (none/explicit/implicit)", "Mode (passive/active)",
+    "Encoding (utf-8/latin1)", "Local directory", "Remote directory", "Transfer types (*.txt=ascii, ...)",
+    "Proxy (socks5://host:port or http://host:port)",
+    "FTP proxy (site, open, transparent, user-at-host, ... then [user@]host[:port])", "Password (saved in plaintext)"
+];
+
+fn optional(text: &str) -> Option<String> {
+    if text.is_empty() { None } else { Some(text.to_string()) }
+}
+
+impl Site {
+    /// The text of field `index` of `FIELDS`
+    pub fn field(&self, index: usize) -> String {
+        match index {
+            0 => self.name.clone(),
+            1 => self.host.clone(),
+            2 => self.port.map(|port| port.to_string()).unwrap_or_default(),
+            3 => self.user.clone().unwrap_or_default(),
+            4 => if self.protocol == Protocol::Sftp { "sftp" } else { "ftp" }.to_string(),
+            5 => match self.tls {
+                None => "none",
+                Some(TlsMode::Explicit) => "explicit",
+                Some(TlsMode::Implicit) => "implicit"
+            }.to_string(),
+            6 => if self.active { "active" } else { "passive" }.to_string(),
+            7 => if self.encoding == Encoding::Latin1 { "latin1" } else { "utf-8" }.to_string(),
+            8 => self.local_dir.as_ref().map(|dir| dir.display().to_string()).unwrap_or_default(),
+            9 => self.remote_dir.clone().unwrap_or_default(),
+            10 => rules_text(&self.transfer_types),
+            11 => self.proxy.clone().unwrap_or_default(),
+            12 => self.ftp_proxy.as_ref().map(FtpProxy::text).unwrap_or_default(),
+            _ => self.password.clone().unwrap_or_default()
+        }
+    }
+
+    /// Sets field `index` of `FIELDS` from its text
+    pub fn set_field(&mut self, index: usize, text: &str) -> Result<(), String> {
+        let text = text.trim();
+        match index {
+            0 | 1 if text.is_empty() => return Err(format!("{} is required", FIELDS[index])),
+            0 => self.name = text.to_string(),
+            1 => self.host = text.to_string(),
+            2 => self.port = match text {
+                "" => None,
+                port => Some(port.parse().map_err(|_| format!("Invalid port \"{}\"", port))?)
+            },
+            3 => self.user = optional(text),
+            4 => self.protocol = match text.to_ascii_lowercase().as_str() {
+                "" | "ftp" => Protocol::Ftp,
+                "sftp" => Protocol::Sftp,
+                other => return Err(format!("Unknown protocol \"{}\"", other))
+            },
+            5 => self.tls = match text.to_ascii_lowercase().as_str() {
+                "" | "none" => None,
+                "explicit" => Some(TlsMode::Explicit),
+                "implicit" => Some(TlsMode::Implicit),
+                other => return Err(format!("Unknown TLS mode \"{}\"", other))
+            },
+            6 => self.active = match text.to_ascii_lowercase().as_str() {
+                "" | "passive" => false,
+                "active" => true,
+                other => return Err(format!("Unknown mode \"{}\"", other))
+            },
+            7 => self.encoding = match text.to_ascii_lowercase().as_str() {
+                "" | "utf-8" | "utf8" => Encoding::Utf8,
+                "latin1" | "iso-8859-1" => Encoding::Latin1,
+                other => return Err(format!("Unknown encoding \"{}\"", other))
+            },
+            8 => self.local_dir = optional(text).map(PathBuf::from),
+            9 => self.remote_dir = optional(text),
+            10 => self.transfer_types = parse_rules(text)?,
+            11 => self.proxy = match text {
+                "" => None,
+                url if proxy::from_url(url).is_some() => Some(url.to_string()),
+                url => return Err(format!("Unknown proxy \"{}\"", url))
+            },
+            12 => {
+                let proxy = optional(text).map(|text| FtpProxy::parse(&text)).transpose()?;
+                // The password is not part of the text, it stays unless the proxy changes
+                let password = self.ftp_proxy.take().filter(|old| proxy.as_ref().is_some_and(|new| new.address == old.address && new.user == old.user))
+                    .and_then(|old| old.password);
+                self.ftp_proxy = proxy.map(|proxy| FtpProxy { password, ..proxy });
+            }
+            _ => self.password = optional(text)
+        }
+        Ok(())
+    }
+
+    /// The server and remote directory to connect to
+    pub fn url(&self) -> Url {
+        Url {
+            protocol: self.protocol,
+            tls: self.tls,
+            host: self.host.clone(),
+            port: self.port,
+            user: self.user.clone(),
+            password: self.password.clone(),
+            path: self.remote_dir.clone(),
+            type_code: None
+        }
+    }
+
+    /// Opens the connections to the server, through the site's proxy or the one ALL_PROXY names
+    pub fn connector(&self) -> Arc<dyn Connector> {
+        self.proxy.as_deref().and_then(proxy::from_url).unwrap_or_else(proxy::from_env)
+    }
+
+    /// The type of the first rule matching `filename`, binary if none does
+    pub fn transfer_type(&self, filename: &str) -> TransferType {
+        self.transfer_types.iter()
+            .find(|rule| glob_match(&rule.pattern, filename))
+            .map_or(TransferType::Binary, |rule| rule.transfer_type)
+    }
+}
+
+/// The site manager's file
+#[derive(Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Sites {
+    /// Passwords are dropped on loading and saving unless this is set
+    pub allow_plaintext_passwords: bool,
+    #[serde(rename = "site")]
+    pub sites: Vec<Site>
+}
+
+impl Sites {
+    pub fn find(&self, name: &str) -> Option<&Site> {
+        self.sites.iter().find(|site| site.name == name)
+    }
+
+    fn strip_passwords(&mut self) {
+        if !self.allow_plaintext_passwords {
+            for site in &mut self.sites {
+                site.password = None;
+                if let Some(proxy) = &mut site.ftp_proxy {
+                    proxy.password = None;
+                }
+            }
+        }
+    }
+}
+
+/// Matches `name` against a pattern with "*" (any run of characters) and "?" (one character), ignoring ASCII case
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().map(|c| c.to_ascii_lowercase()).collect();
+    let name: Vec<char> = name.chars().map(|c| c.to_ascii_lowercase()).collect();
+    // Backtracks to the last "*" on a mismatch, letting it absorb one more character
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false
+            }
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Parses rules written as "*.txt=ascii, *.zip=binary"
+pub fn parse_rules(text: &str) -> Result<Vec<TransferRule>, String> {
+    text.split(',').map(str::trim).filter(|rule| !rule.is_empty()).map(|rule| {
+        let (pattern, transfer_type) = rule.rsplit_once('=').ok_or_else(|| format!("\"{}\" is not pattern=type", rule))?;
+        let transfer_type = match transfer_type.trim().to_ascii_lowercase().as_str() {
+            "ascii" | "a" => TransferType::Ascii,
+            "binary" | "i" => TransferType::Binary,
+            other => return Err(format!("Unknown transfer type \"{}\"", other))
+        };
+        Ok(TransferRule { pattern: pattern.trim().to_string(), transfer_type })
+    }).collect()
+}
+
+pub fn rules_text(rules: &[TransferRule]) -> String {
+    rules.iter()
+        .map(|rule| format!("{}={}", rule.pattern, if rule.transfer_type == TransferType::Ascii { "ascii" } else { "binary" }))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// $XDG_CONFIG_HOME/termftp/sites.toml, by default in ~/.config
+pub fn default_path() -> Option<PathBuf> {
+    let config = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => home::home_dir()?.join(".config")
+    };
+    Some(config.join("termftp").join("sites.toml"))
+}
+
+/// Reads the sites saved at `path`, none if the file does not exist yet
+pub fn load(path: &Path) -> io::Result<Sites> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Sites::default()),
+        Err(e) => return Err(e)
+    };
+    let mut sites: Sites = toml::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    sites.strip_passwords();
+    Ok(sites)
+}
+
+/// Writes `sites` to `path` through a temporary file, readable only by the user
+pub fn save(path: &Path, sites: &Sites) -> io::Result<()> {
+    let mut sites = sites.clone();
+    sites.strip_passwords();
+    let text = toml::to_string(&sites).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let temporary = path.with_extension("toml.tmp");
+    // Created readable only by the user, as it may hold passwords. A leftover file would keep its permissions
+    let _ = fs::remove_file(&temporary);
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(&temporary)?.write_all(text.as_bytes())?;
+    fs::rename(&temporary, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Site {
+        Site {
+            name: "Work".to_string(),
+            host: "ftp.example.org".to_string(),
+            port: Some(2121),
+            user: Some("alice".to_string()),
+            password: Some("secret".to_string()),
+            tls: Some(TlsMode::Explicit),
+            active: true,
+            encoding: Encoding::Latin1,
+            local_dir: Some(PathBuf::from("/tmp")),
+            remote_dir: Some("pub".to_string()),
+            transfer_types: parse_rules("*.txt=ascii, *=binary").unwrap(),
+            proxy: Some("socks5://proxy.example.org:1080".to_string()),
+            ftp_proxy: Some(FtpProxy::parse("site fwuser@gateway.example.org").unwrap()),
+            ..Site::default()
+        }
+    }
+
+    #[test]
+    fn save_and_load_test() -> io::Result<()> {
+        let path = env::temp_dir().join(format!("termftp_sites_{}", std::process::id())).join("sites.toml");
+        let mut sites = Sites { allow_plaintext_passwords: false, sites: vec![sample(), Site { name: "Other".to_string(), ..Site::default() }] };
+        save(&path, &sites)?;
+        let text = fs::read_to_string(&path)?;
+        assert!(text.contains("[[site]]") && text.contains("encoding = \"latin1\"") && text.contains("tls = \"explicit\""));
+        // Passwords are not written unless allowed
+        assert!(!text.contains("secret"));
+        let loaded = load(&path)?;
+        assert_eq!(loaded.find("Work").unwrap().password, None);
+        assert_eq!(loaded.find("Work").unwrap().transfer_types, sample().transfer_types);
+        assert_eq!(loaded.find("Other").unwrap().protocol, Protocol::Ftp);
+
+        sites.allow_plaintext_passwords = true;
+        save(&path, &sites)?;
+        assert_eq!(load(&path)?, sites);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+        }
+        fs::remove_dir_all(path.parent().unwrap())
+    }
+
+    #[test]
+    fn load_test() {
+        let sites: Sites = toml::from_str("[[site]]\nname = \"Mirror\"\nhost = \"mirror.example.org\"\nprotocol = \"sftp\"\n").unwrap();
+        let url = sites.find("Mirror").unwrap().url();
+        assert_eq!((url.protocol, url.port_or_default(), url.user), (Protocol::Sftp, 22, None));
+        assert!(toml::from_str::<Sites>("[[site]]\nname = \"x\"\ntls = \"sometimes\"\n").is_err());
+        assert!(load(Path::new("/nonexistent/sites.toml")).unwrap().sites.is_empty());
+    }
+
+    #[test]
+    fn fields_test() {
+        let site = sample();
+        let mut copy = Site::default();
+        for i in 0..FIELDS.len() {
+            copy.set_field(i, &site.field(i)).unwrap();
+        }
+        assert_eq!(copy, site);
+        assert!(copy.set_field(0, " ").is_err());
+        assert!(copy.set_field(2, "ftp").is_err());
+        assert!(copy.set_field(5, "always").is_err());
+        assert!(copy.set_field(11, "ftp://proxy.example.org").is_err());
+        assert!(copy.set_field(12, "sideways gateway").is_err());
+        copy.set_field(12, "").unwrap();
+        assert_eq!(copy.ftp_proxy, None);
+        copy.set_field(2, "").unwrap();
+        assert_eq!(copy.port, None);
+    }
+
+    #[test]
+    fn ftp_proxy_test() {
+        let mut proxy = FtpProxy::parse("open fwuser@gateway.example.org").unwrap();
+        assert_eq!((proxy.address.as_str(), proxy.text()), ("gateway.example.org:21", "open fwuser@gateway.example.org:21".to_string()));
+        proxy.password = Some("fwpass".to_string());
+        assert!(matches!(proxy.login("ftp.example.org:21"), ProxyLogin::Open { host, proxy_user, proxy_password }
+            if host == "ftp.example.org:21" && proxy_user == "fwuser" && proxy_password == "fwpass"));
+        let proxy = FtpProxy::parse("USER-AT-HOST gateway:2121").unwrap();
+        assert!(!proxy.scheme.logs_in() && proxy.user.is_none());
+        assert!(matches!(proxy.login("ftp.example.org"), ProxyLogin::UserAtHost { host } if host == "ftp.example.org"));
+        assert!(FtpProxy::parse("site").is_err());
+    }
+
+    #[test]
+    fn transfer_type_test() {
+        let site = sample();
+        assert_eq!(site.transfer_type("README.TXT"), TransferType::Ascii);
+        assert_eq!(site.transfer_type("image.png"), TransferType::Binary);
+        assert!(glob_match("a*b?c", "axxbyc"));
+        assert!(glob_match("*.tar.*", "x.tar.gz"));
+        assert!(!glob_match("*.txt", "txt"));
+        assert!(parse_rules("*.txt").is_err());
+        assert!(parse_rules("*.txt=text").is_err());
+        assert_eq!(rules_text(&site.transfer_types), "*.txt=ascii, *=binary");
+    }
+}
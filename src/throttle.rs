@@ -0,0 +1,96 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Limits throughput to `rate` bytes per second, allowing bursts of up to one second's worth.
+/// A rate of 0 means unlimited. The rate can be changed at any time, also while data is flowing.
+pub struct TokenBucket {
+    rate: AtomicU64,
+    // Available tokens (negative when in debt) and when they were last refilled
+    state: Mutex<(f64, Instant)>
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> TokenBucket {
+        TokenBucket { rate: AtomicU64::new(rate), state: Mutex::new((0.0, Instant::now())) }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    pub fn set_rate(&self, rate: u64) {
+        self.rate.store(rate, Ordering::Relaxed);
+    }
+
+    /// Blocks until `amount` bytes may pass
+    pub fn take(&self, amount: usize) {
+        let rate = self.rate() as f64;
+        if rate == 0.0 {
+            return;
+        }
+
+        let wait = {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            let now = Instant::now();
+            state.0 = (state.0 + now.duration_since(state.1).as_secs_f64() * rate).min(rate);
+            state.1 = now;
+            state.0 -= amount as f64;
+            if state.0 < 0.0 { Duration::from_secs_f64(-state.0 / rate) } else { Duration::ZERO }
+        };
+        thread::sleep(wait);
+    }
+}
+
+/// Separate limits for both directions
+pub struct Throttle {
+    pub upload: TokenBucket,
+    pub download: TokenBucket
+}
+
+impl Throttle {
+    pub fn new(upload_rate: u64, download_rate: u64) -> Throttle {
+        Throttle { upload: TokenBucket::new(upload_rate), download: TokenBucket::new(download_rate) }
+    }
+
+    pub fn unlimited() -> Throttle {
+        Self::new(0, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unlimited_test() {
+        let bucket = TokenBucket::new(0);
+        let start = Instant::now();
+        bucket.take(100_000_000);
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn rate_test() {
+        // 200 KB at 200 KB/s, starting from an empty bucket
+        let bucket = TokenBucket::new(200_000);
+        let start = Instant::now();
+        for _ in 0..10 {
+            bucket.take(20_000);
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(900), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1500), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn set_rate_test() {
+        let bucket = TokenBucket::new(10_000);
+        bucket.set_rate(0);
+        let start = Instant::now();
+        bucket.take(1_000_000);
+        assert!(start.elapsed() < Duration::from_millis(100));
+        assert_eq!(bucket.rate(), 0);
+    }
+}
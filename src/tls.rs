@@ -0,0 +1,131 @@
+use crate::certs::{Certificate, KnownCertificates, Trust};
+use crate::ftp;
+use openssl::ssl::{SslConnector, SslMethod, SslOptions, SslSession, SslStream, SslVerifyMode};
+use openssl::x509::X509VerifyResult;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, RwLock};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsMode {
+    /// Upgrade the plain control connection with AUTH TLS, usually on port 21
+    Explicit,
+    /// TLS from the first byte, usually on port 990
+    Implicit
+}
+
+pub const IMPLICIT_PORT: u16 = 990;
+
+fn tls_error(domain: &str, e: impl std::fmt::Display) -> io::Error {
+    io::Error::other(format!("TLS handshake with {} failed: {}", domain, e))
+}
+
+/// How to secure the connections to a server: the mode, the name its certificate has to carry,
+/// and the certificates pinned for servers
+#[derive(Clone)]
+pub struct Tls {
+    pub mode: TlsMode,
+    connector: SslConnector,
+    domain: String,
+    server: String,
+    known: Arc<RwLock<KnownCertificates>>
+}
+
+impl Tls {
+    /// `server` is the "host:port" the certificate is pinned for. A server is trusted if its certificate
+    /// is the pinned one, or if none is pinned and it verifies against the system's trusted certificates.
+    pub fn new(mode: TlsMode, domain: &str, server: &str, known: Arc<RwLock<KnownCertificates>>) -> io::Result<Tls> {
+        let mut builder = SslConnector::builder(SslMethod::tls()).map_err(|e| tls_error(domain, e))?;
+        // Failed verification is no reason to abort the handshake here, `check` decides whether the certificate is pinned instead
+        builder.set_verify_callback(SslVerifyMode::PEER, |_, _| true);
+        // Many servers close data connections without a close_notify, which is no sign of truncation in FTP
+        // because the reply on the control connection confirms the transfer
+        // (SSL_OP_IGNORE_UNEXPECTED_EOF of OpenSSL 3, which the bindings do not name)
+        builder.set_options(SslOptions::from_bits_retain(0x80));
+        Ok(Tls { mode, connector: builder.build(), domain: domain.to_string(), server: server.to_string(), known })
+    }
+
+    /// Performs the handshake over `stream`. Data connections pass the control connection's session,
+    /// as many servers only accept data connections that resume it.
+    pub fn connect(&self, stream: TcpStream, session: Option<&SslSession>) -> ftp::Result<SslStream<TcpStream>> {
+        let mut ssl = self.connector.configure().and_then(|c| c.into_ssl(&self.domain)).map_err(|e| tls_error(&self.domain, e))?;
+        if let Some(session) = session {
+            // SAFETY: the session was negotiated through the same connector, so it belongs to the same context
+            unsafe { ssl.set_session(session).map_err(|e| tls_error(&self.domain, e))?; }
+        }
+        let stream = ssl.connect(stream).map_err(|e| tls_error(&self.domain, e))?;
+        self.check(&stream)?;
+        Ok(stream)
+    }
+
+    fn check(&self, stream: &SslStream<TcpStream>) -> ftp::Result<()> {
+        let ssl = stream.ssl();
+        let peer = ssl.peer_certificate().ok_or_else(|| tls_error(&self.domain, "no certificate"))?;
+        let verified = ssl.verify_result() == X509VerifyResult::OK;
+        let problem = if verified { None } else { Some(ssl.verify_result().error_string().to_string()) };
+        let certificate = Certificate::new(&peer, problem)?;
+        let trust = self.known.read().map_err(|_| ftp::Error::InvalidData)?.check(&self.server, &certificate.fingerprint);
+        match trust {
+            Trust::Pinned => Ok(()),
+            Trust::Unknown if verified => Ok(()),
+            Trust::Unknown => Err(ftp::Error::UnknownCertificate { server: self.server.clone(), certificate: Box::new(certificate) }),
+            Trust::Changed { pinned } => Err(ftp::Error::CertificateChanged { server: self.server.clone(), pinned, fingerprint: certificate.fingerprint })
+        }
+    }
+}
+
+/// A socket that may have been secured with TLS
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Box<SslStream<TcpStream>>)
+}
+
+impl Stream {
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(stream) => stream,
+            Stream::Tls(stream) => stream.get_ref()
+        }
+    }
+
+    pub fn session(&self) -> Option<SslSession> {
+        match self {
+            Stream::Plain(_) => None,
+            Stream::Tls(stream) => stream.ssl().session().map(|s| s.to_owned())
+        }
+    }
+
+    /// Sends close_notify on a TLS stream, which some servers wait for before confirming an upload
+    pub fn shutdown(&mut self) {
+        if let Stream::Tls(stream) = self {
+            let _ = stream.shutdown();
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf)
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush()
+        }
+    }
+}
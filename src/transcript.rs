@@ -0,0 +1,131 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use crate::date::format_time;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Direction {
+    Sent,
+    Received,
+    // Data connection opened or closed
+    Data
+}
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub time: SystemTime,
+    pub direction: Direction,
+    pub line: String
+}
+
+impl std::fmt::Display for Entry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let marker = match self.direction {
+            Direction::Sent => '>',
+            Direction::Received => '<',
+            Direction::Data => '*'
+        };
+        write!(f, "[{}] {} {}", format_time(self.time), marker, self.line)
+    }
+}
+
+/// Receives every line that goes over the control connection, and data connection events
+pub trait Sink: Send + Sync {
+    fn record(&self, entry: &Entry);
+}
+
+/// Appends the transcript to a file
+pub struct FileSink {
+    file: Mutex<File>
+}
+
+impl FileSink {
+    pub fn create(path: &Path) -> io::Result<FileSink> {
+        Ok(FileSink { file: Mutex::new(OpenOptions::new().create(true).append(true).open(path)?) })
+    }
+}
+
+impl Sink for FileSink {
+    fn record(&self, entry: &Entry) {
+        if let Ok(mut file) = self.file.lock() {
+            // A failing transcript must not break the session
+            let _ = writeln!(file, "{}", entry);
+        }
+    }
+}
+
+/// Keeps the most recent entries in memory, e.g. for showing them in the UI
+pub struct Memory {
+    entries: Mutex<VecDeque<Entry>>,
+    capacity: usize
+}
+
+impl Memory {
+    /// Keeps the last `capacity` entries, which must be at least one
+    pub fn new(capacity: usize) -> Memory {
+        assert!(capacity > 0, "a transcript keeps at least one entry");
+        Memory { entries: Mutex::new(VecDeque::new()), capacity }
+    }
+
+    pub fn entries(&self) -> Vec<Entry> {
+        self.entries.lock().map(|e| e.iter().cloned().collect()).unwrap_or_default()
+    }
+}
+
+impl Sink for Memory {
+    fn record(&self, entry: &Entry) {
+        if let Ok(mut entries) = self.entries.lock() {
+            if entries.len() == self.capacity {
+                entries.pop_front();
+            }
+            entries.push_back(entry.clone());
+        }
+    }
+}
+
+/// Passes every entry on to several sinks
+pub struct Fanout(pub Vec<Arc<dyn Sink>>);
+
+impl Sink for Fanout {
+    fn record(&self, entry: &Entry) {
+        for sink in &self.0 {
+            sink.record(entry);
+        }
+    }
+}
+
+/// Hides the arguments of commands carrying credentials
+pub fn redact(command: &str, line: &str) -> String {
+    if command.eq_ignore_ascii_case("PASS") || command.eq_ignore_ascii_case("ACCT") {
+        format!("{} ****", command)
+    }
+    else {
+        line.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn redact_test() {
+        assert_eq!(redact("PASS", "PASS hunter2"), "PASS ****");
+        assert_eq!(redact("acct", "acct billing"), "acct ****");
+        assert_eq!(redact("USER", "USER bob"), "USER bob");
+    }
+
+    #[test]
+    fn memory_capacity_test() {
+        let memory = Memory::new(2);
+        for line in ["one", "two", "three"] {
+            memory.record(&Entry { time: UNIX_EPOCH, direction: Direction::Sent, line: line.to_string() });
+        }
+        let lines: Vec<String> = memory.entries().into_iter().map(|e| e.line).collect();
+        assert_eq!(lines, vec!["two", "three"]);
+    }
+}
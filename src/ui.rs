@@ -1,50 +1,364 @@
 use tui::{
     backend::Backend,
-    text::Span,
-    widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
+    text::{Span, Spans},
+    widgets::{Block, Borders, Cell, Clear, Gauge, List, ListItem, Paragraph, Row, Table, Wrap},
     layout::{Layout, Constraint, Direction, Rect},
     Frame,
     style::{Style, Color, Modifier},
 };
-use crate::app::App;
+use crate::app::{App, Column, Focus, Pane};
+use crate::date;
+use crate::form::{self, LoginForm};
+use crate::remote_fs::{Entry, EntryKind};
+use crate::queue::{self, State, Transfer};
+use std::time::{Duration, SystemTime};
+
+pub fn human_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{} {}", bytes, units[0]) } else { format!("{:.1} {}", size, units[unit]) }
+}
 
 pub fn update_status<B: Backend>(f: &mut Frame<B>, area: Rect, status: String) {
-    let text = Paragraph::new(Span::raw(status));
+    // Wrapped, so long prompts like a certificate to trust stay readable
+    let text = Paragraph::new(Span::raw(status)).wrap(Wrap { trim: false });
     f.render_widget(text, area);
 } 
 
-pub fn draw_list<B: Backend>(f: &mut Frame<B>, app: &mut App, area: Rect, name: &str) {
-    let remote_items: Vec<ListItem> = app.remote_items().into_iter().map(|i| ListItem::new(i)).collect();
+// The name with a marker for its kind, like `ls -F`
+pub fn entry_text(entry: &Entry) -> String {
+    match entry.kind {
+        EntryKind::File => entry.name.clone(),
+        EntryKind::Directory => format!("{}/", entry.name),
+        EntryKind::Symlink => format!("{}@", entry.name)
+    }
+}
+
+/// An entry as `entry_text` shows it, or how many there are
+pub fn entries_text(entries: &[Entry]) -> String {
+    match entries {
+        [entry] => entry_text(entry),
+        _ => format!("{} entries", entries.len())
+    }
+}
+
+// The name column keeps at least this width, the others are left out when they would make it narrower
+const MIN_NAME_WIDTH: u16 = 12;
+
+// "YYYY-MM-DD HH:MM" in UTC
+fn modified_text(time: SystemTime) -> String {
+    let mut text = date::format_time(time);
+    text.truncate(16);
+    text
+}
+
+// The text of `entry` in `column`. Sizes are right-aligned to `width`, directories have none
+fn cell_text(entry: &Entry, column: Column, width: u16) -> String {
+    match column {
+        Column::Name => entry_text(entry),
+        Column::Size => match entry.size.filter(|_| !entry.is_dir()) {
+            Some(size) => format!("{:>width$}", human_size(size), width = width as usize),
+            None => String::new()
+        },
+        Column::Modified => entry.modified.map(modified_text).unwrap_or_default(),
+        Column::Permissions => entry.permissions.clone().unwrap_or_default(),
+        Column::Owner => entry.owner.clone().unwrap_or_default()
+    }
+}
+
+// The columns that fit in `width` besides the name, with their widths. Columns the listing has no values for are left out
+fn columns(entries: &[Entry], width: u16) -> Vec<(Column, u16)> {
+    let mut res = Vec::new();
+    let mut left = width.saturating_sub(MIN_NAME_WIDTH);
+    for column in Column::ALL.into_iter().skip(1) {
+        let widest = entries.iter().map(|e| cell_text(e, column, 0).chars().count()).max().unwrap_or(0) as u16;
+        if widest == 0 {
+            continue;
+        }
+        let column_width = widest.max(column.title().len() as u16 + 2).min(16);
+        if column_width + 1 > left {
+            break;
+        }
+        left -= column_width + 1;
+        res.push((column, column_width));
+    }
+    res
+}
+
+// The path, and with a filter how many of the entries it lets through
+fn pane_title(label: &str, pane: &Pane) -> String {
+    match &pane.filter {
+        Some(filter) => format!("{}: {} [{}: {}/{}]", label, pane.path, filter, pane.file_count(), pane.total_count()),
+        None => format!("{}: {}", label, pane.path)
+    }
+}
 
-    let block = List::new(remote_items)
-        .block(Block::default().title(name).borders(Borders::ALL))
+pub fn draw_list<B: Backend>(f: &mut Frame<B>, pane: &mut Pane, area: Rect, title: &str, focused: bool) {
+    let border = if focused { Style::default().fg(Color::Yellow) } else { Style::default() };
+    // Inside the borders and the highlight symbol
+    let width = area.width.saturating_sub(4);
+    let others = columns(&pane.list.items, width);
+    let name_width = width.saturating_sub(others.iter().map(|(_, w)| w + 1).sum());
+    let all: Vec<(Column, u16)> = std::iter::once((Column::Name, name_width)).chain(others).collect();
+
+    let header = all.iter().map(|&(column, _)| {
+        let arrow = match (column == pane.sort, pane.reverse) {
+            (false, _) => "",
+            (true, false) => " ▲",
+            (true, true) => " ▼"
+        };
+        Cell::from(format!("{}{}", column.title(), arrow))
+    });
+    // Marked entries are highlighted, the selected one is in italics
+    let marked = Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD);
+    let rows = pane.list.items.iter().map(|e| {
+        let row = Row::new(all.iter().map(|&(column, width)| Cell::from(cell_text(e, column, width))));
+        if pane.is_marked(e) { row.style(marked) } else { row }
+    });
+    let widths: Vec<Constraint> = all.iter().map(|&(_, width)| Constraint::Length(width)).collect();
+    let table = Table::new(rows)
+        .header(Row::new(header).style(Style::default().add_modifier(Modifier::BOLD)))
+        .block(Block::default().title(title).borders(Borders::ALL).border_style(border))
         .style(Style::default().fg(Color::White))
+        .widths(&widths)
+        .column_spacing(1)
         .highlight_style(Style::default().add_modifier(Modifier::ITALIC))
-        .highlight_symbol(">>");
-    
-    f.render_stateful_widget(block, area, &mut app.remote_list.state);
+        .highlight_symbol(if focused { ">>" } else { "  " });
 
-    /*let local_items: Vec<ListItem> = app.local_items().into_iter().map(|i| ListItem::new(i)).collect();
+    pane.table.select(pane.list.state.selected());
+    f.render_stateful_widget(table, area, &mut pane.table);
+}
 
-    let block = List::new(local_items)
-        .block(Block::default().title(name).borders(Borders::ALL))
+pub fn draw_preview<B: Backend>(f: &mut Frame<B>, app: &mut App, area: Rect) {
+    // Inside the borders
+    let (width, height) = (area.width.saturating_sub(2) as usize, area.height.saturating_sub(2) as usize);
+    let (title, lines, scroll) = match &mut app.preview {
+        Some(preview) => {
+            let lines = match &preview.error {
+                Some(e) => vec![e.clone()],
+                None => preview.lines(width)
+            };
+            // Wrapped lines take up more than one row
+            preview.rows = lines.iter().map(|line| line.chars().count().div_ceil(width.max(1)).max(1)).sum();
+            preview.height = height;
+            preview.scroll_by(0);
+            let fetched = match preview.size {
+                Some(size) => format!("{} of {}", human_size(preview.data.len() as u64), human_size(size)),
+                None => human_size(preview.data.len() as u64)
+            };
+            (format!("Preview: {} ({}, PgUp/PgDn: scroll)", preview.name, fetched), lines, preview.scroll)
+        }
+        None => ("Preview".to_string(), Vec::new(), 0)
+    };
+    let text: Vec<Spans> = lines.into_iter().map(Spans::from).collect();
+    let paragraph = Paragraph::new(text)
+        .block(Block::default().title(title).borders(Borders::ALL))
         .style(Style::default().fg(Color::White))
-        .highlight_style(Style::default().add_modifier(Modifier::ITALIC))
-        .highlight_symbol(">>");
+        .wrap(Wrap { trim: false })
+        .scroll((scroll as u16, 0));
+    f.render_widget(paragraph, area);
+}
 
-    f.render_stateful_widget(block, area, &mut app.local_list.state);*/
-} 
+pub fn draw_transcript<B: Backend>(f: &mut Frame<B>, app: &App, area: Rect) {
+    // Only the most recent lines that fit inside the borders
+    let entries = app.transcript.entries();
+    let visible = entries.len().saturating_sub(area.height.saturating_sub(2) as usize);
+    let items: Vec<ListItem> = entries[visible..].iter().map(|e| ListItem::new(e.to_string())).collect();
+
+    let block = List::new(items)
+        .block(Block::default().title("Transcript").borders(Borders::ALL))
+        .style(Style::default().fg(Color::Gray));
+    f.render_widget(block, area);
+}
+
+// Rows the transfer panel shows at most, the rest scroll
+const TRANSFER_ROWS: usize = 6;
+
+/// The height of the transfer panel, none without transfers
+pub fn transfers_height(app: &App) -> u16 {
+    match app.transfers.items.len() {
+        0 => 0,
+        len => len.min(TRANSFER_ROWS) as u16 + 2
+    }
+}
+
+fn duration_text(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 { format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60) } else { format!("{}:{:02}", secs / 60, secs % 60) }
+}
+
+// The name, state, speed and time left of a transfer
+fn transfer_text(transfer: &Transfer) -> String {
+    let arrow = match transfer.direction {
+        queue::Direction::Download => "<-",
+        queue::Direction::Upload => "->"
+    };
+    let state = match &transfer.state {
+        State::Queued => "queued".to_string(),
+        State::Running => {
+            let eta = transfer.eta().map(|eta| format!(", {} left", duration_text(eta))).unwrap_or_default();
+            format!("{}/s{}", human_size(transfer.rate), eta)
+        }
+        State::Paused => "paused".to_string(),
+        State::Done => "done".to_string(),
+        State::Failed(e) => format!("failed: {}", e)
+    };
+    let limit = match transfer.limit {
+        0 => String::new(),
+        limit => format!(" (limit {}/s)", human_size(limit))
+    };
+    format!("{} {} {}{}", arrow, entry_text(&Entry::new(&transfer.name, transfer.kind)), state, limit)
+}
+
+pub fn draw_transfers<B: Backend>(f: &mut Frame<B>, app: &App, area: Rect) {
+    let focused = app.focus == Focus::Queue;
+    let border = if focused { Style::default().fg(Color::Yellow) } else { Style::default() };
+    let title = "Transfers (p: pause, r: resume or retry, c: cancel, u/d: move up/down, +/-: limit)";
+    let block = Block::default().title(title).borders(Borders::ALL).border_style(border);
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+
+    // Scrolled so the selected transfer is visible
+    let selected = app.transfers.state.selected();
+    let rows = inner.height as usize;
+    let first = selected.map_or(0, |i| (i + 1).saturating_sub(rows));
+    for (row, (i, transfer)) in app.transfers.items.iter().enumerate().skip(first).take(rows).enumerate() {
+        let line = Rect::new(inner.x, inner.y + row as u16, inner.width, 1);
+        let columns = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(60), Constraint::Percentage(40)].as_ref())
+            .split(line);
+        let marker = if focused && selected == Some(i) { ">>" } else { "  " };
+        let style = match transfer.state {
+            State::Failed(_) => Style::default().fg(Color::Red),
+            State::Done | State::Paused => Style::default().fg(Color::DarkGray),
+            _ => Style::default().fg(Color::White)
+        };
+        f.render_widget(Paragraph::new(Span::styled(format!("{}{}", marker, transfer_text(transfer)), style)), columns[0]);
+        let label = match transfer.size {
+            Some(size) => format!("{} of {}", human_size(transfer.transferred), human_size(size)),
+            None => human_size(transfer.transferred)
+        };
+        let gauge = Gauge::default()
+            .gauge_style(Style::default().fg(Color::Green).bg(Color::Black))
+            .ratio(transfer.ratio())
+            .label(label);
+        f.render_widget(gauge, columns[1]);
+    }
+}
+
+// A rectangle of the given percentages of `area`, centered in it
+fn centered(area: Rect, width_percent: u16, height_percent: u16) -> Rect {
+    let width = area.width * width_percent / 100;
+    let height = area.height * height_percent / 100;
+    Rect::new(area.x + (area.width - width) / 2, area.y + (area.height - height) / 2, width, height)
+}
+
+pub fn draw_sites<B: Backend>(f: &mut Frame<B>, app: &mut App, area: Rect) {
+    if let Some(dialog) = &mut app.site_dialog {
+        let items: Vec<ListItem> = dialog.items.iter().map(|name| ListItem::new(name.as_str())).collect();
+        let block = List::new(items)
+            .block(Block::default().title("Sites: Enter connect, n new, e edit, d delete, Esc quick connect").borders(Borders::ALL))
+            .style(Style::default().fg(Color::White))
+            .highlight_style(Style::default().add_modifier(Modifier::BOLD))
+            .highlight_symbol(">>");
+        let area = centered(area, 60, 50);
+        f.render_widget(Clear, area);
+        f.render_stateful_widget(block, area, &mut dialog.state);
+    }
+}
+
+pub fn draw_question<B: Backend>(f: &mut Frame<B>, app: &App, area: Rect) {
+    if let Some(question) = &app.question {
+        let text = Paragraph::new(format!("{}\n\ny: yes, n: no", question))
+            .block(Block::default().title("Confirm").borders(Borders::ALL))
+            .wrap(Wrap { trim: false });
+        let area = centered(area, 50, 25);
+        f.render_widget(Clear, area);
+        f.render_widget(text, area);
+    }
+}
+
+// The value of a form field as shown, with the column of the cursor for text fields
+fn field_text(form: &LoginForm, index: usize) -> (String, Option<usize>) {
+    let choice = |on: bool, label: &str| format!("({}) {}", if on { '*' } else { ' ' }, label);
+    match index {
+        form::PASSWORD => ("*".repeat(form.password.text.chars().count()), Some(form.password.cursor)),
+        form::SECURITY => (format!("< {} >", form.security.label()), None),
+        form::MODE => (format!("{}  {}", choice(!form.active, "Passive"), choice(form.active, "Active")), None),
+        form::ANONYMOUS => (format!("[{}]", if form.anonymous { 'x' } else { ' ' }), None),
+        form::PORT if form.port.text.is_empty() && form.focus != form::PORT => ("default".to_string(), None),
+        _ => match form.text_field(index) {
+            Some(field) => (field.text.to_string(), Some(field.cursor)),
+            None => (String::new(), None)
+        }
+    }
+}
+
+pub fn draw_login<B: Backend>(f: &mut Frame<B>, app: &App, area: Rect) {
+    let form = match &app.login {
+        Some(form) => form,
+        None => return
+    };
+    let label_width = form::FIELDS.iter().map(|label| label.len()).max().unwrap_or(0) + 2;
+    let mut lines = Vec::new();
+    if let Some(message) = &form.message {
+        lines.push(Spans::from(Span::styled(message.clone(), Style::default().fg(Color::Red))));
+        lines.push(Spans::default());
+    }
+    let mut cursor = None;
+    for (index, label) in form::FIELDS.iter().enumerate() {
+        let (text, column) = field_text(form, index);
+        let style = if !form.enabled(index) {
+            Style::default().fg(Color::DarkGray)
+        } else if index == form.focus {
+            Style::default().fg(Color::Yellow)
+        } else {
+            Style::default()
+        };
+        if index == form.focus {
+            cursor = column.map(|column| (lines.len(), label_width + column));
+        }
+        lines.push(Spans::from(vec![Span::styled(format!("{:width$}", format!("{}:", label), width = label_width), style), Span::styled(text, style)]));
+        if let Some((_, error)) = form.error.as_ref().filter(|(field, _)| *field == index) {
+            lines.push(Spans::from(Span::styled(format!("{:width$}{}", "", error, width = label_width), Style::default().fg(Color::Red))));
+        }
+    }
+    lines.push(Spans::default());
+    lines.push(Spans::from("Tab/Shift+Tab next/previous field, Left/Right or Space change a choice, Enter connect, Esc quit"));
+
+    let area = centered(area, 70, 60);
+    let text = Paragraph::new(lines)
+        .block(Block::default().title("Connect").borders(Borders::ALL))
+        .wrap(Wrap { trim: false });
+    f.render_widget(Clear, area);
+    f.render_widget(text, area);
+    if let Some((line, column)) = cursor {
+        f.set_cursor(area.x + 1 + column as u16, area.y + 1 + line as u16);
+    }
+}
 
 pub fn draw_layout<B: Backend>(f: &mut Frame<B>, app: &mut App, status_text: String) {
+    // The panes get what the other panels leave
+    let mut constraints = if app.show_transcript {
+        vec![Constraint::Min(3), Constraint::Percentage(30)]
+    } else {
+        vec![Constraint::Min(3)]
+    };
+    let transfers = transfers_height(app);
+    if transfers > 0 {
+        constraints.push(Constraint::Length(transfers));
+    }
+    constraints.push(Constraint::Length(2));
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .margin(1)
-        .constraints(
-            [
-                Constraint::Percentage(95),
-                Constraint::Percentage(5)
-            ].as_ref()
-        )
+        .constraints(constraints)
         .split(f.size());
     let h_chunks = Layout::default()
         .direction(Direction::Horizontal)
@@ -55,12 +369,23 @@ pub fn draw_layout<B: Backend>(f: &mut Frame<B>, app: &mut App, status_text: Str
             ].as_ref()
         )
         .split(chunks[0]);
-    draw_list(f, app, h_chunks[0], "Remote");
-    
-    let block = Block::default()
-        .title("Local")
-        .borders(Borders::ALL);
-    f.render_widget(block, h_chunks[1]);
-    update_status(f, chunks[1], status_text);
+    let title = pane_title("Remote", &app.remote);
+    draw_list(f, &mut app.remote, h_chunks[0], &title, app.focus == Focus::Remote);
+    if app.show_preview {
+        draw_preview(f, app, h_chunks[1]);
+    } else {
+        let title = pane_title("Local", &app.local);
+        draw_list(f, &mut app.local, h_chunks[1], &title, app.focus == Focus::Local);
+    }
+    if app.show_transcript {
+        draw_transcript(f, app, chunks[1]);
+    }
+    if transfers > 0 {
+        draw_transfers(f, app, chunks[chunks.len() - 2]);
+    }
+    draw_sites(f, app, chunks[0]);
+    draw_question(f, app, chunks[0]);
+    draw_login(f, app, chunks[0]);
+    update_status(f, chunks[chunks.len() - 1], status_text);
         
 }
\ No newline at end of file
@@ -0,0 +1,255 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
+
+// File layout: MAGIC, the Argon2id costs (3 little-endian u32), salt, nonce, tag, then the AES-256-GCM ciphertext.
+// Everything before the nonce is authenticated along with the ciphertext.
+const MAGIC: &[u8; 8] = b"TFVAULT1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+const HEADER_LEN: usize = MAGIC.len() + 12 + SALT_LEN;
+// The highest costs a vault may ask for. The header is only authenticated after the key is derived, so a damaged or
+// crafted file could otherwise make opening it take all memory or hours
+const MAX_KDF: Kdf = Kdf { memory: 1 << 20, iterations: 64, lanes: 64 };
+
+/// Argon2id costs: memory in KiB, iterations and lanes
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Kdf {
+    pub memory: u32,
+    pub iterations: u32,
+    pub lanes: u32
+}
+
+impl Default for Kdf {
+    fn default() -> Self {
+        Kdf { memory: Params::DEFAULT_M_COST, iterations: Params::DEFAULT_T_COST, lanes: Params::DEFAULT_P_COST }
+    }
+}
+
+impl Kdf {
+    /// Whether the costs are within MAX_KDF
+    fn is_sane(&self) -> bool {
+        self.memory <= MAX_KDF.memory && self.iterations <= MAX_KDF.iterations && self.lanes <= MAX_KDF.lanes
+    }
+
+    fn derive(&self, passphrase: &str, salt: &[u8]) -> io::Result<Zeroizing<[u8; KEY_LEN]>> {
+        let params = Params::new(self.memory, self.iterations, self.lanes, Some(KEY_LEN)).map_err(invalid)?;
+        let mut key = Zeroizing::new([0; KEY_LEN]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), salt, key.as_mut())
+            .map_err(invalid)?;
+        Ok(key)
+    }
+}
+
+fn invalid(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Secrets encrypted with a key derived from a master password, kept in memory that is wiped when dropped
+pub struct Vault {
+    path: PathBuf,
+    kdf: Kdf,
+    salt: [u8; SALT_LEN],
+    key: Zeroizing<[u8; KEY_LEN]>,
+    secrets: BTreeMap<String, Zeroizing<String>>
+}
+
+impl Vault {
+    /// Creates an empty vault at `path`, which must not exist yet
+    pub fn create(path: &Path, passphrase: &str) -> io::Result<Vault> {
+        Self::create_with(path, passphrase, Kdf::default())
+    }
+
+    pub fn create_with(path: &Path, passphrase: &str, kdf: Kdf) -> io::Result<Vault> {
+        if path.exists() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} exists already", path.display())));
+        }
+        if !kdf.is_sane() {
+            return Err(invalid("The key derivation costs are too high"));
+        }
+        let mut salt = [0; SALT_LEN];
+        rand_bytes(&mut salt)?;
+        let vault = Vault { path: path.to_path_buf(), kdf, salt, key: kdf.derive(passphrase, &salt)?, secrets: BTreeMap::new() };
+        vault.save()?;
+        Ok(vault)
+    }
+
+    /// Decrypts the vault at `path`. A wrong passphrase fails with `InvalidData`
+    pub fn open(path: &Path, passphrase: &str) -> io::Result<Vault> {
+        let data = fs::read(path)?;
+        if data.len() < HEADER_LEN + NONCE_LEN + TAG_LEN || &data[..MAGIC.len()] != MAGIC {
+            return Err(invalid(format!("{} is not a credential vault", path.display())));
+        }
+        let cost = |i: usize| u32::from_le_bytes(data[MAGIC.len() + 4 * i..MAGIC.len() + 4 * i + 4].try_into().unwrap());
+        let kdf = Kdf { memory: cost(0), iterations: cost(1), lanes: cost(2) };
+        if !kdf.is_sane() {
+            return Err(invalid(format!("{} asks for unreasonable key derivation costs", path.display())));
+        }
+        let (header, rest) = data.split_at(HEADER_LEN);
+        let (nonce, rest) = rest.split_at(NONCE_LEN);
+        let (tag, ciphertext) = rest.split_at(TAG_LEN);
+        let mut salt = [0; SALT_LEN];
+        salt.copy_from_slice(&header[HEADER_LEN - SALT_LEN..]);
+
+        let key = kdf.derive(passphrase, &salt)?;
+        let plaintext = Zeroizing::new(decrypt_aead(Cipher::aes_256_gcm(), key.as_ref(), Some(nonce), header, ciphertext, tag)
+            .map_err(|_| invalid("Wrong master password, or the vault is damaged"))?);
+        Ok(Vault { path: path.to_path_buf(), kdf, salt, key, secrets: decode(&plaintext)? })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.secrets.get(name).map(|secret| secret.as_str())
+    }
+
+    pub fn insert(&mut self, name: &str, secret: Zeroizing<String>) {
+        self.secrets.insert(name.to_string(), secret);
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.secrets.remove(name).is_some()
+    }
+
+    /// Encrypts the secrets with a fresh nonce and replaces the file, readable only by the user
+    pub fn save(&self) -> io::Result<()> {
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        for cost in [self.kdf.memory, self.kdf.iterations, self.kdf.lanes] {
+            header.extend_from_slice(&cost.to_le_bytes());
+        }
+        header.extend_from_slice(&self.salt);
+        let mut nonce = [0; NONCE_LEN];
+        rand_bytes(&mut nonce)?;
+        let mut tag = [0; TAG_LEN];
+        let ciphertext = encrypt_aead(Cipher::aes_256_gcm(), self.key.as_ref(), Some(&nonce), &header, &encode(&self.secrets), &mut tag)?;
+
+        let mut data = header;
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&tag);
+        data.extend_from_slice(&ciphertext);
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let temporary = self.path.with_extension("tmp");
+        // Created readable only by the user rather than restricted afterwards. A leftover file would keep its permissions
+        let _ = fs::remove_file(&temporary);
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options.open(&temporary)?.write_all(&data)?;
+        fs::rename(&temporary, &self.path)
+    }
+}
+
+// The plaintext: each name and secret as a little-endian u32 length and UTF-8 bytes
+fn encode(secrets: &BTreeMap<String, Zeroizing<String>>) -> Zeroizing<Vec<u8>> {
+    let len = secrets.iter().map(|(name, secret)| 8 + name.len() + secret.len()).sum();
+    // Sized up front, so the buffer is never reallocated and no unwiped copy is left behind
+    let mut res = Zeroizing::new(Vec::with_capacity(len));
+    for (name, secret) in secrets {
+        for field in [name.as_bytes(), secret.as_bytes()] {
+            res.extend_from_slice(&(field.len() as u32).to_le_bytes());
+            res.extend_from_slice(field);
+        }
+    }
+    res
+}
+
+fn decode(mut data: &[u8]) -> io::Result<BTreeMap<String, Zeroizing<String>>> {
+    let mut field = || -> io::Result<Zeroizing<String>> {
+        let len = data.get(..4).ok_or_else(|| invalid("Truncated vault"))?;
+        let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+        let bytes = data.get(4..4 + len).ok_or_else(|| invalid("Truncated vault"))?;
+        let mut text = Zeroizing::new(String::with_capacity(len));
+        text.push_str(std::str::from_utf8(bytes).map_err(invalid)?);
+        data = &data[4 + len..];
+        Ok(text)
+    };
+    let mut res = BTreeMap::new();
+    loop {
+        let name = match field() {
+            Ok(name) => name,
+            Err(_) if data.is_empty() => return Ok(res),
+            Err(e) => return Err(e)
+        };
+        res.insert(name.to_string(), field()?);
+    }
+}
+
+/// The vault next to the site manager's file
+pub fn default_path() -> Option<PathBuf> {
+    crate::sites::default_path().map(|sites| sites.with_file_name("vault"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    // Fast costs, the default ones take seconds in debug builds
+    const TEST_KDF: Kdf = Kdf { memory: 64, iterations: 1, lanes: 1 };
+
+    fn test_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("termftp_vault_{}_{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn round_trip_test() -> io::Result<()> {
+        let path = test_path("round_trip");
+        let mut vault = Vault::create_with(&path, "master", TEST_KDF)?;
+        vault.insert("Work", Zeroizing::new("se€ret".to_string()));
+        vault.insert("", Zeroizing::new(String::new()));
+        vault.save()?;
+        assert!(!fs::read(&path)?.windows(4).any(|w| w == b"Work"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+        }
+
+        let mut vault = Vault::open(&path, "master")?;
+        assert_eq!((vault.get("Work"), vault.get("")), (Some("se€ret"), Some("")));
+        assert!(vault.remove("Work"));
+        vault.save()?;
+        assert_eq!(Vault::open(&path, "master")?.get("Work"), None);
+        assert_eq!(Vault::create_with(&path, "other", TEST_KDF).err().map(|e| e.kind()), Some(io::ErrorKind::AlreadyExists));
+        fs::remove_file(&path)
+    }
+
+    #[test]
+    fn wrong_passphrase_test() -> io::Result<()> {
+        let path = test_path("wrong_passphrase");
+        let mut vault = Vault::create_with(&path, "master", TEST_KDF)?;
+        vault.insert("Work", Zeroizing::new("secret".to_string()));
+        vault.save()?;
+        assert_eq!(Vault::open(&path, "Master").err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+
+        // Tampering with the header or the ciphertext is detected
+        let data = fs::read(&path)?;
+        for i in [MAGIC.len(), data.len() - 1] {
+            let mut damaged = data.clone();
+            damaged[i] ^= 1;
+            fs::write(&path, damaged)?;
+            assert!(Vault::open(&path, "master").is_err());
+        }
+        // Costs that would take too long are rejected before deriving the key
+        for i in 0..3 {
+            let mut damaged = data.clone();
+            damaged[MAGIC.len() + 4 * i..MAGIC.len() + 4 * i + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+            fs::write(&path, damaged)?;
+            assert_eq!(Vault::open(&path, "master").err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+        }
+        fs::remove_file(&path)
+    }
+}